@@ -0,0 +1,195 @@
+//! Logging requests to a file, with `--access-log`.
+//!
+//! Records are written in the Combined Log Format used by Apache and nginx, so
+//! that the usual tools can read them. The log can be rotated by size or by
+//! day, keeping a fixed number of old logs next to it, as "access.log.1",
+//! "access.log.2" and so on.
+
+use super::conn::ConnInfo;
+use super::{Config, Error, Result};
+use chrono::{DateTime, Local, NaiveDate};
+use http::{HeaderMap, Request, Response};
+use hyper::{header, Body};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+/// The access log file, shared by all connections.
+#[derive(Clone)]
+pub struct AccessLog {
+    file: Arc<Mutex<LogFile>>,
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    day: NaiveDate,
+    max_size: Option<u64>,
+    daily: bool,
+    keep: u32,
+}
+
+impl AccessLog {
+    /// Open the access log for appending, if `--access-log` is set.
+    pub fn open(config: &Config) -> Result<Option<AccessLog>> {
+        let path = match &config.access_log {
+            Some(path) => path.clone(),
+            None => return Ok(None),
+        };
+
+        let file = open_append(&path).map_err(Error::AccessLogOpen)?;
+        let meta = file.metadata().map_err(Error::AccessLogOpen)?;
+        let modified = meta.modified().map(DateTime::<Local>::from);
+        let day = modified.unwrap_or_else(|_| Local::now()).date_naive();
+
+        let file = LogFile {
+            path,
+            file,
+            size: meta.len(),
+            day,
+            max_size: config.access_log_max_size,
+            daily: config.access_log_daily,
+            keep: config.access_log_keep,
+        };
+
+        Ok(Some(AccessLog {
+            file: Arc::new(Mutex::new(file)),
+        }))
+    }
+
+    /// Write a record to the log, rotating it first if it's time to.
+    ///
+    /// Failures are only logged, since a full disk shouldn't stop the server.
+    pub fn write(&self, record: &Record) {
+        let line = record.to_string();
+        let mut file = self.file.lock().expect("access log lock");
+
+        if file.needs_rotation(line.len() as u64) {
+            if let Err(e) = file.rotate() {
+                warn!("failed to rotate access log: {}", e);
+            }
+        }
+
+        match file.file.write_all(line.as_bytes()) {
+            Ok(()) => file.size += line.len() as u64,
+            Err(e) => warn!("failed to write access log: {}", e),
+        }
+    }
+}
+
+impl LogFile {
+    fn needs_rotation(&self, len: u64) -> bool {
+        let too_big = self
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + len > max);
+        let new_day = self.daily && Local::now().date_naive() != self.day;
+        too_big || new_day
+    }
+
+    /// Shift the old logs along, dropping the oldest, and start a new log.
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..self.keep).rev() {
+            rename_if_exists(&self.numbered_path(n), &self.numbered_path(n + 1))?;
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, self.numbered_path(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        self.day = Local::now().date_naive();
+
+        Ok(())
+    }
+
+    fn numbered_path(&self, n: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+/// A record of one request and its response, in the Combined Log Format.
+pub struct Record {
+    time: DateTime<Local>,
+    remote_addr: String,
+    request_line: String,
+    referer: String,
+    user_agent: String,
+    status: u16,
+    size: String,
+}
+
+impl Record {
+    /// Start a record with the request, before it is handled.
+    pub fn new(conn: ConnInfo, req: &Request<Body>) -> Record {
+        Record {
+            time: Local::now(),
+            remote_addr: conn
+                .remote_addr
+                .map_or_else(|| "-".to_string(), |addr| addr.ip().to_string()),
+            request_line: format!("{} {} {:?}", req.method(), req.uri(), req.version()),
+            referer: header_or_dash(req.headers(), header::REFERER),
+            user_agent: header_or_dash(req.headers(), header::USER_AGENT),
+            status: 0,
+            size: "-".to_string(),
+        }
+    }
+
+    /// Complete the record with the response.
+    ///
+    /// The size of streamed responses isn't known, and is logged as "-".
+    pub fn finish(&mut self, resp: &Response<Body>) {
+        self.status = resp.status().as_u16();
+        self.size = resp
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("-")
+            .to_string();
+    }
+}
+
+impl std::fmt::Display for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\"",
+            self.remote_addr,
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            escape(&self.request_line),
+            self.status,
+            self.size,
+            escape(&self.referer),
+            escape(&self.user_agent),
+        )
+    }
+}
+
+fn header_or_dash(headers: &HeaderMap, name: header::HeaderName) -> String {
+    headers
+        .get(name)
+        .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Escape quotes and backslashes, so that clients can't forge log fields.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,65 @@
+//! Checking the `Host` of requests, with `--allowed-hosts`, to protect the
+//! server from DNS rebinding.
+//!
+//! A web page on another site can make a browser send requests to a server on
+//! the local machine or network by pointing its own domain name at the
+//! server's address, so requests for names the server doesn't know are
+//! refused. IP addresses, "localhost" and its subdomains, the host name of
+//! `--addr`, `--vhost` names and the `--mdns` name are always allowed.
+
+use super::Config;
+use hyper::{Body, Request};
+use std::net::IpAddr;
+use tracing::warn;
+
+/// Whether a request's host is allowed.
+///
+/// Requests without a host, from HTTP/1.0 clients, are allowed, since a
+/// browser always sends one.
+pub fn is_allowed(config: &Config, req: &Request<Body>) -> bool {
+    let host = match super::vhost::request_host(req) {
+        Some(host) => host,
+        None => return true,
+    };
+
+    if is_default_allowed(config, &host) || is_listed(config, &host) {
+        return true;
+    }
+
+    warn!(
+        "refusing request for host '{}'; allow it with --allowed-hosts {}",
+        host, host
+    );
+    false
+}
+
+fn is_default_allowed(config: &Config, host: &str) -> bool {
+    let ip = host.trim_start_matches('[').trim_end_matches(']');
+    if ip.parse::<IpAddr>().is_ok() {
+        return true;
+    }
+
+    host == "localhost"
+        || host.ends_with(".localhost")
+        || config.addr.host().eq_ignore_ascii_case(host)
+        || config.vhost.iter().any(|vhost| vhost.host() == host)
+        || config
+            .mdns
+            .as_ref()
+            .is_some_and(|name| super::mdns::host_name(name) == host)
+}
+
+/// Whether a host is in `--allowed-hosts`, where "*" allows every host, and a
+/// name starting with "." allows its subdomains too.
+fn is_listed(config: &Config, host: &str) -> bool {
+    config.allowed_hosts.iter().any(|allowed| {
+        let allowed = allowed.trim().trim_end_matches('.').to_ascii_lowercase();
+        if allowed == "*" {
+            return true;
+        }
+        match allowed.strip_prefix('.') {
+            Some(domain) => host == domain || host.ends_with(&allowed),
+            None => host == allowed,
+        }
+    })
+}
@@ -0,0 +1,369 @@
+//! Downloading directories as tarballs, with `?tar.gz` and `-x`.
+//!
+//! The tarball is written and gzipped as it is sent, so it can be as big as
+//! the directory without being held in memory or on disk. It keeps each
+//! file's permissions and modification time, and symlinks are stored as
+//! symlinks instead of being followed, so build trees survive being moved
+//! between machines. Hidden files are left out.
+//!
+//! The tree is read on tokio's blocking threads, one directory at a time.
+//!
+//! Entries are in the POSIX ustar format, with GNU extensions for long names
+//! and big files.
+
+use super::{Config, Error, Result};
+use http::{header, Request, Response, StatusCode};
+use hyper::body::Sender;
+use hyper::Body;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio_executor::blocking;
+use tracing::{debug, warn};
+
+/// The size of tar headers and the unit that file data is padded to.
+const BLOCK_LEN: usize = 512;
+
+/// How much of a file is read at a time.
+const CHUNK_LEN: usize = 64 * 1024;
+
+/// The kinds of tar entries.
+const REGULAR: u8 = b'0';
+const SYMLINK: u8 = b'2';
+const DIRECTORY: u8 = b'5';
+const GNU_LONG_NAME: u8 = b'L';
+const GNU_LONG_LINK: u8 = b'K';
+
+/// Whether the query string asks for a tarball, with `?tar.gz`.
+pub fn wants_tar_gz(req: &Request<Body>) -> bool {
+    super::ext::has_query_param(req, "tar.gz")
+}
+
+/// Respond with a gzipped tarball of a directory, named after it.
+pub fn tar_gz_response(config: &Config, dir: &Path) -> Result<Response<Body>> {
+    let name = archive_name(config, dir);
+    debug!("sending {} as {}.tar.gz", dir.display(), name);
+
+    let (sender, body) = Body::channel();
+    tokio::spawn(write_tar(
+        config.clone(),
+        dir.to_owned(),
+        name.clone(),
+        sender,
+    ));
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/gzip")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.tar.gz\"", name.replace('"', "")),
+        )
+        .body(super::compress::gzip_body(body))
+        .map_err(Error::from)
+}
+
+/// The name of the directory at the top of the tarball.
+fn archive_name(config: &Config, dir: &Path) -> String {
+    let dir = if dir == config.root_dir {
+        fs::canonicalize(dir).unwrap_or_else(|_| dir.to_owned())
+    } else {
+        dir.to_owned()
+    };
+    dir.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "root".to_string())
+}
+
+/// Write the tarball of a directory to the response body, logging any
+/// error, since the response has already started.
+async fn write_tar(config: Config, dir: PathBuf, name: String, sender: Sender) {
+    let mut tar = TarWriter { sender };
+
+    match tar.write_tree(Arc::new(config), dir, name).await {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {
+            debug!("client went away during tarball");
+        }
+        Err(e) => {
+            warn!("error writing tarball: {}", e);
+            tar.sender.abort();
+        }
+    }
+}
+
+/// Writes tar entries to a response body.
+struct TarWriter {
+    sender: Sender,
+}
+
+impl TarWriter {
+    /// Write a directory and everything in it, then the end of the archive.
+    async fn write_tree(
+        &mut self,
+        config: Arc<Config>,
+        dir: PathBuf,
+        name: String,
+    ) -> io::Result<()> {
+        // Walk the tree depth first, in name order.
+        let mut stack = vec![(dir, name)];
+
+        while let Some((path, name)) = stack.pop() {
+            let entry = {
+                let (config, path, name) = (config.clone(), path.clone(), name.clone());
+                blocking::run(move || read_entry(&config, &path, &name)).await?
+            };
+
+            match entry {
+                Entry::Symlink(meta, target) => {
+                    self.write_header(&name, &meta, SYMLINK, 0, &target).await?;
+                }
+                Entry::Dir(meta, children) => {
+                    self.write_header(&format!("{}/", name), &meta, DIRECTORY, 0, "")
+                        .await?;
+                    stack.extend(children.into_iter().rev());
+                }
+                Entry::File(meta) => {
+                    self.write_header(&name, &meta, REGULAR, meta.len(), "")
+                        .await?;
+                    self.write_file(&path, meta.len()).await?;
+                }
+                Entry::Other => {}
+            }
+        }
+
+        // The end of the archive is two empty blocks.
+        self.send(vec![0; BLOCK_LEN * 2]).await
+    }
+
+    /// Write the header of an entry, preceded by GNU long name entries if its
+    /// name or link target don't fit in the header.
+    async fn write_header(
+        &mut self,
+        name: &str,
+        meta: &Metadata,
+        kind: u8,
+        size: u64,
+        link: &str,
+    ) -> io::Result<()> {
+        let mut buf = Vec::new();
+        if name.len() > 100 {
+            long_name_entry(&mut buf, GNU_LONG_NAME, name);
+        }
+        if link.len() > 100 {
+            long_name_entry(&mut buf, GNU_LONG_LINK, link);
+        }
+
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let header = Header {
+            name,
+            mode: mode(meta),
+            size,
+            mtime,
+            kind,
+            link,
+        };
+        buf.extend_from_slice(&header.to_block());
+
+        self.send(buf).await
+    }
+
+    /// Write exactly `len` bytes of a file, padded to a whole block.
+    ///
+    /// If the file changes size while it is read, it is cut short or padded
+    /// with zeros, since its size is already in the header.
+    async fn write_file(&mut self, path: &Path, len: u64) -> io::Result<()> {
+        let mut file = File::open(path).await?;
+        let mut remaining = len;
+        let mut shrank = false;
+
+        while remaining > 0 {
+            let mut chunk = vec![0; CHUNK_LEN.min(remaining as usize)];
+            let n = if shrank {
+                0
+            } else {
+                file.read(&mut chunk).await?
+            };
+            if n == 0 && !shrank {
+                warn!("{} changed while writing tarball", path.display());
+                shrank = true;
+            }
+            if n > 0 {
+                chunk.truncate(n);
+            }
+            remaining -= chunk.len() as u64;
+            self.send(chunk).await?;
+        }
+
+        let padding = padding(len);
+        if padding > 0 {
+            self.send(vec![0; padding]).await?;
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, data: Vec<u8>) -> io::Result<()> {
+        self.sender
+            .send_data(data.into())
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))
+    }
+}
+
+/// A file in the tree, as read from the file system.
+enum Entry {
+    /// A symlink and its target.
+    Symlink(Metadata, String),
+    /// A directory and its children that aren't hidden, in name order, with
+    /// their names in the tarball.
+    Dir(Metadata, Vec<(PathBuf, String)>),
+    File(Metadata),
+    /// Sockets, devices and the like, which are left out.
+    Other,
+}
+
+/// Read what is at a path in the tree, which blocks.
+fn read_entry(config: &Config, path: &Path, name: &str) -> io::Result<Entry> {
+    let meta = fs::symlink_metadata(path)?;
+
+    if meta.file_type().is_symlink() {
+        let target = fs::read_link(path)?;
+        Ok(Entry::Symlink(meta, target.to_string_lossy().into_owned()))
+    } else if meta.is_dir() {
+        let mut children = Vec::new();
+        for dent in fs::read_dir(path)? {
+            let child = dent?.path();
+            if super::hide::is_hidden(config, &child) {
+                continue;
+            }
+            if let Some(file_name) = child.file_name() {
+                let child_name = format!("{}/{}", name, file_name.to_string_lossy());
+                children.push((child, child_name));
+            }
+        }
+        children.sort();
+        Ok(Entry::Dir(meta, children))
+    } else if meta.is_file() {
+        Ok(Entry::File(meta))
+    } else {
+        Ok(Entry::Other)
+    }
+}
+
+/// The fields of a ustar header that are used.
+struct Header<'a> {
+    name: &'a str,
+    mode: u32,
+    size: u64,
+    mtime: u64,
+    kind: u8,
+    link: &'a str,
+}
+
+impl<'a> Header<'a> {
+    /// Lay out the header block. Names longer than their fields are cut
+    /// short, having been written in full by a long name entry before it.
+    fn to_block(&self) -> [u8; BLOCK_LEN] {
+        let mut block = [0; BLOCK_LEN];
+
+        put_str(&mut block[0..100], self.name);
+        put_octal(&mut block[100..108], u64::from(self.mode));
+        put_octal(&mut block[108..116], 0); // uid
+        put_octal(&mut block[116..124], 0); // gid
+        put_number(&mut block[124..136], self.size);
+        put_number(&mut block[136..148], self.mtime);
+        block[156] = self.kind;
+        put_str(&mut block[157..257], self.link);
+        block[257..263].copy_from_slice(b"ustar\0");
+        block[263..265].copy_from_slice(b"00");
+
+        // The checksum is computed with its own field as spaces.
+        block[148..156].copy_from_slice(b"        ");
+        let checksum: u32 = block.iter().map(|&b| u32::from(b)).sum();
+        put_octal(&mut block[148..155], u64::from(checksum));
+        block[155] = b' ';
+
+        block
+    }
+}
+
+/// Write a GNU entry holding a name that is too long for a header.
+fn long_name_entry(buf: &mut Vec<u8>, kind: u8, name: &str) {
+    let data_len = name.len() + 1;
+    let header = Header {
+        name: "././@LongLink",
+        mode: 0o644,
+        size: data_len as u64,
+        mtime: 0,
+        kind,
+        link: "",
+    };
+    buf.extend_from_slice(&header.to_block());
+    buf.extend_from_slice(name.as_bytes());
+    buf.push(0);
+    buf.resize(buf.len() + padding(data_len as u64), 0);
+}
+
+/// The zeros needed after data of a length to fill its last block.
+fn padding(len: u64) -> usize {
+    let rem = (len % BLOCK_LEN as u64) as usize;
+    if rem == 0 {
+        0
+    } else {
+        BLOCK_LEN - rem
+    }
+}
+
+/// Copy as much of a string as fits into a field.
+fn put_str(field: &mut [u8], s: &str) {
+    let len = s.len().min(field.len());
+    field[..len].copy_from_slice(&s.as_bytes()[..len]);
+}
+
+/// Write a number as zero-padded octal digits and a terminating NUL.
+fn put_octal(field: &mut [u8], n: u64) {
+    let len = field.len();
+    let digits = format!("{:0width$o}", n, width = len - 1);
+    field[..len - 1].copy_from_slice(digits.as_bytes());
+    field[len - 1] = 0;
+}
+
+/// Write a number as octal, or in GNU's base-256 form if it is too big, as
+/// for files of 8 GiB or more.
+fn put_number(field: &mut [u8], n: u64) {
+    let max_octal = (1u64 << (3 * (field.len() - 1))) - 1;
+    if n <= max_octal {
+        put_octal(field, n);
+    } else {
+        field.iter_mut().for_each(|b| *b = 0);
+        let len = field.len();
+        field[len - 8..].copy_from_slice(&n.to_be_bytes());
+        field[0] |= 0x80;
+    }
+}
+
+/// The permission bits of a file.
+#[cfg(unix)]
+fn mode(meta: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+/// The permission bits of a file, made up from whether it is read-only.
+#[cfg(not(unix))]
+fn mode(meta: &Metadata) -> u32 {
+    match (meta.is_dir(), meta.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
@@ -0,0 +1,117 @@
+//! Serving the site under a URL prefix, with `--base-path`, as it will be on
+//! e.g. a GitHub Pages project site at "/PROJECT/".
+//!
+//! The prefix is taken off request URLs before anything else looks at them,
+//! and put back on the URLs the server makes, in directory listings and
+//! redirects. Other requests get 404 Not Found, except for "/" and the base
+//! path without its trailing slash, which are redirected to the site, and
+//! the server's own endpoints, such as "/_events", which stay at the root.
+
+use super::{Config, Result};
+use http::{header, HeaderValue, StatusCode, Uri};
+use hyper::{Body, Request, Response};
+
+/// Parse `--base-path`, which starts with "/" and is kept without a trailing
+/// one.
+pub fn parse(s: &str) -> std::result::Result<String, String> {
+    if !s.starts_with('/') {
+        return Err(format!("expected a path starting with '/', found '{}'", s));
+    }
+    Ok(s.trim_end_matches('/').to_string())
+}
+
+/// A URL path of the site, with the base path put in front.
+pub fn url(config: &Config, path: &str) -> String {
+    match &config.base_path {
+        Some(base) => format!("{}{}", base, path),
+        None => path.to_string(),
+    }
+}
+
+/// A URL path with the base path taken off, or `None` if it's outside it.
+pub fn strip_path<'a>(config: &Config, path: &'a str) -> Option<&'a str> {
+    let base = match &config.base_path {
+        Some(base) => base,
+        None => return Some(path),
+    };
+
+    match path.strip_prefix(base.as_str()) {
+        Some(rest) if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+/// Take the base path off a request's URL, returning whether the request is
+/// for the site.
+pub fn strip(config: &Config, req: &mut Request<Body>) -> bool {
+    if config.base_path.is_none() || is_endpoint(req.uri().path()) {
+        return true;
+    }
+
+    let path = match strip_path(config, req.uri().path()) {
+        Some(path) => path,
+        None => return false,
+    };
+
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+
+    let mut parts = req.uri().clone().into_parts();
+    match path_and_query.parse() {
+        Ok(path_and_query) => parts.path_and_query = Some(path_and_query),
+        Err(_) => return false,
+    }
+    match Uri::from_parts(parts) {
+        Ok(uri) => {
+            *req.uri_mut() = uri;
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Answer a request from outside the base path.
+pub fn outside(config: &Config, req: &Request<Body>) -> Result<Response<Body>> {
+    let path = req.uri().path();
+    if path == "/" || config.base_path.as_ref().is_some_and(|base| base == path) {
+        // The Location is given the base path with the other redirects.
+        return Response::builder()
+            .status(StatusCode::FOUND)
+            .header(header::LOCATION, "/")
+            .body(Body::empty())
+            .map_err(Into::into);
+    }
+
+    super::make_error_response_from_code(config, StatusCode::NOT_FOUND)
+}
+
+/// Put the base path on a redirect to a path of the site.
+pub fn add_to_location(config: &Config, resp: &mut Response<Body>) {
+    if config.base_path.is_none() {
+        return;
+    }
+
+    let location = match resp.headers().get(header::LOCATION) {
+        Some(location) => location.to_str().unwrap_or(""),
+        None => return,
+    };
+    if !location.starts_with('/') || location.starts_with("//") {
+        return;
+    }
+
+    if let Ok(location) = HeaderValue::from_str(&url(config, location)) {
+        resp.headers_mut().insert(header::LOCATION, location);
+    }
+}
+
+/// Whether a path is one of the server's own endpoints, which aren't under
+/// the base path.
+fn is_endpoint(path: &str) -> bool {
+    path == super::watch::EVENTS_PATH
+        || path == super::search::SEARCH_PATH
+        || path == super::tree::TREE_PATH
+        || path == super::tree::STAT_PATH
+        || path.starts_with(super::file_manager::FILES_PATH)
+}
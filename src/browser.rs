@@ -0,0 +1,91 @@
+//! The URLs of the served site, and opening them in the system browser, with
+//! `--open`.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::process::Command;
+use std::thread;
+use tracing::{info, warn};
+
+/// The URL of a path on an address.
+pub fn url(scheme: &str, addr: SocketAddr, path: &str) -> String {
+    let slash = if path.starts_with('/') { "" } else { "/" };
+    format!("{}://{}{}{}", scheme, addr, slash, path)
+}
+
+/// The URL of a path on a listener, using the loopback address if it listens
+/// on all addresses, since "0.0.0.0" can't be browsed to everywhere.
+pub fn local_url(scheme: &str, addr: SocketAddr, path: &str) -> String {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    url(scheme, SocketAddr::new(ip, addr.port()), path)
+}
+
+/// The addresses that other machines on the network can reach a listener
+/// on: its own address, or those of the network interfaces if it listens on
+/// all addresses. Loopback and link-local addresses are left out.
+pub fn lan_addrs(addr: SocketAddr) -> Vec<SocketAddr> {
+    if !addr.ip().is_unspecified() {
+        if addr.ip().is_loopback() {
+            return Vec::new();
+        }
+        return vec![addr];
+    }
+
+    let ifaces = match if_addrs::get_if_addrs() {
+        Ok(ifaces) => ifaces,
+        Err(e) => {
+            warn!("unable to list network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let mut addrs: Vec<SocketAddr> = ifaces
+        .iter()
+        .filter(|iface| !iface.is_loopback() && !iface.is_link_local())
+        .map(|iface| iface.ip())
+        // An IPv4 listener can't be reached on IPv6 addresses.
+        .filter(|ip| addr.is_ipv6() || ip.is_ipv4())
+        .map(|ip| SocketAddr::new(ip, addr.port()))
+        .collect();
+    addrs.sort();
+    addrs.dedup();
+    addrs
+}
+
+/// Open a URL in the system browser, without waiting for it.
+pub fn open(url: &str) {
+    info!("opening {}", url);
+
+    let mut cmd = browser_command(url);
+    thread::spawn(move || match cmd.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("unable to open the browser: {}", status),
+        Err(e) => warn!("unable to open the browser: {}", e),
+    });
+}
+
+#[cfg(target_os = "macos")]
+fn browser_command(url: &str) -> Command {
+    let mut cmd = Command::new("open");
+    cmd.arg(url);
+    cmd
+}
+
+#[cfg(windows)]
+fn browser_command(url: &str) -> Command {
+    // The empty argument is the window title, so that a quoted URL isn't
+    // taken for one.
+    let mut cmd = Command::new("cmd");
+    cmd.args(&["/C", "start", ""]).arg(url);
+    cmd
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+fn browser_command(url: &str) -> Command {
+    let mut cmd = Command::new("xdg-open");
+    cmd.arg(url);
+    cmd
+}
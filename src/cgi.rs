@@ -0,0 +1,346 @@
+//! Running CGI scripts, with `--cgi-dir DIR`.
+//!
+//! Files in the CGI directory are run for requests to them, as described by
+//! RFC 3875. The request is described to the script by environment variables,
+//! its body is streamed to the script's standard input, and the script writes
+//! response headers, a blank line, and the response body to its standard
+//! output.
+//!
+//! https://tools.ietf.org/html/rfc3875
+
+use super::{Config, ConnInfo, Error, Result};
+use bytes::BytesMut;
+use futures::StreamExt;
+use http::header::{self, HeaderName, HeaderValue};
+use http::uri::Authority;
+use http::{Request, Response, StatusCode};
+use hyper::Body;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::process::Stdio;
+use tokio::codec::{BytesCodec, FramedRead};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::process::{ChildStdin, Command};
+use tracing::{debug, warn};
+
+/// The most bytes of headers a script may write.
+pub const MAX_HEADERS_LEN: usize = 64 * 1024;
+
+/// The CGI directory, if `--cgi-dir` is given.
+fn cgi_root(config: &Config) -> Option<PathBuf> {
+    let dir = config.cgi_dir.as_ref()?;
+    let dir = dir.strip_prefix("/").unwrap_or(dir);
+    Some(config.root_dir.join(dir))
+}
+
+/// Whether a request is for the CGI directory.
+pub fn is_cgi_request(config: &Config, req: &Request<Body>) -> bool {
+    let root = match cgi_root(config) {
+        Some(root) => root,
+        None => return false,
+    };
+
+    super::local_path_for_request(req.uri(), &config.root_dir)
+        .map(|path| path.starts_with(&root) && path != root)
+        .unwrap_or(false)
+}
+
+/// A script, and the rest of the request path after it.
+pub struct Script {
+    pub path: PathBuf,
+    /// The URL path of the script.
+    name: String,
+    /// The rest of the URL path, which may be empty.
+    path_info: String,
+}
+
+/// Find the script for a request path, like "/cgi-bin/script.cgi/extra".
+fn find_script(config: &Config, req: &Request<Body>) -> Result<Script> {
+    let not_found = || Error::Io(io::Error::new(io::ErrorKind::NotFound, "no CGI script"));
+
+    let root = cgi_root(config).ok_or_else(not_found)?;
+    let path = super::local_path_for_request(req.uri(), &config.root_dir)?;
+
+    // Don't let "/cgi-bin/../" run programs outside the CGI directory.
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Err(not_found());
+    }
+
+    let script = path
+        .ancestors()
+        .take_while(|p| p.starts_with(&root) && *p != root)
+        .find(|p| p.is_file())
+        .ok_or_else(not_found)?;
+
+    if super::hide::is_hidden(config, script) {
+        return Err(not_found());
+    }
+
+    Ok(Script::new(config, script, &path))
+}
+
+impl Script {
+    /// Describe the script at a path, run for a request path at or under it.
+    pub fn new(config: &Config, script: &Path, request_path: &Path) -> Script {
+        let url_path = |p: &Path| {
+            let segments: Vec<_> = p
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect();
+            segments
+                .iter()
+                .fold(String::new(), |url, segment| url + "/" + segment)
+        };
+        let name = url_path(script.strip_prefix(&config.root_dir).unwrap_or(script));
+        let path_info = url_path(request_path.strip_prefix(script).unwrap_or(Path::new("")));
+
+        Script {
+            path: script.to_owned(),
+            name,
+            path_info,
+        }
+    }
+}
+
+/// Run the CGI script for a request, and stream its output as the response.
+pub async fn serve(config: &Config, conn: ConnInfo, req: Request<Body>) -> Result<Response<Body>> {
+    let script = find_script(config, &req)?;
+    debug!("running CGI script {}", script.path.display());
+
+    let mut cmd = Command::new(&script.path);
+    cmd.env_clear()
+        .envs(meta_variables(config, conn, &req, &script))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+    if let Some(dir) = script.path.parent() {
+        cmd.current_dir(dir);
+    }
+    let mut child = cmd.spawn().map_err(Error::CgiSpawn)?;
+
+    let stdin = child.stdin().take().expect("piped stdin");
+    let stdout = child.stdout().take().expect("piped stdout");
+
+    tokio::spawn(write_body(req.into_body(), stdin));
+
+    let script_path = script.path.clone();
+    tokio::spawn(async move {
+        match child.await {
+            Ok(status) if !status.success() => {
+                warn!(
+                    "CGI script {} exited with {}",
+                    script_path.display(),
+                    status
+                )
+            }
+            Ok(_) => {}
+            Err(e) => warn!("CGI script {} failed: {}", script_path.display(), e),
+        }
+    });
+
+    let mut stdout = BufReader::new(stdout);
+    let mut resp = read_headers(&mut stdout).await?;
+
+    let stream = FramedRead::new(stdout, BytesCodec::new());
+    let stream = stream.map(|b| b.map(BytesMut::freeze));
+    *resp.body_mut() = Body::wrap_stream(stream);
+
+    Ok(resp)
+}
+
+/// Stream the request body to the script's standard input, and close it at
+/// the end.
+async fn write_body(mut body: Body, mut stdin: ChildStdin) {
+    while let Some(chunk) = body.next().await {
+        let written = match chunk {
+            Ok(chunk) => stdin.write_all(&chunk).await,
+            Err(e) => {
+                debug!("error reading request body for CGI: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = written {
+            // The script may not read its input.
+            debug!("error writing request body to CGI: {}", e);
+            return;
+        }
+    }
+}
+
+/// The environment variables describing the request to the script.
+///
+/// https://tools.ietf.org/html/rfc3875#section-4.1
+pub fn meta_variables(
+    config: &Config,
+    conn: ConnInfo,
+    req: &Request<Body>,
+    script: &Script,
+) -> Vec<(String, String)> {
+    let mut vars = Vec::new();
+    let mut var = |name: &str, value: String| vars.push((name.to_string(), value));
+
+    var("GATEWAY_INTERFACE", "CGI/1.1".to_string());
+    var(
+        "SERVER_SOFTWARE",
+        format!("basic-http-server/{}", env!("CARGO_PKG_VERSION")),
+    );
+    var("SERVER_PROTOCOL", format!("{:?}", req.version()));
+
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok())
+        .map(|host| host.host().to_string());
+    let addr = conn.local_addr;
+    var(
+        "SERVER_NAME",
+        host.or_else(|| addr.map(|a| a.ip().to_string()))
+            .unwrap_or_default(),
+    );
+    var(
+        "SERVER_PORT",
+        addr.map(|a| a.port().to_string()).unwrap_or_default(),
+    );
+    if conn.tls {
+        var("HTTPS", "on".to_string());
+    }
+
+    var("REQUEST_METHOD", req.method().to_string());
+    var("REQUEST_URI", req.uri().to_string());
+    var("QUERY_STRING", req.uri().query().unwrap_or("").to_string());
+    var("SCRIPT_NAME", script.name.clone());
+    // FastCGI servers run elsewhere, so they need absolute paths.
+    let absolute = |p: &Path| fs::canonicalize(p).unwrap_or_else(|_| p.to_owned());
+    var(
+        "SCRIPT_FILENAME",
+        absolute(&script.path).display().to_string(),
+    );
+    var(
+        "DOCUMENT_ROOT",
+        absolute(&config.root_dir).display().to_string(),
+    );
+    if !script.path_info.is_empty() {
+        var("PATH_INFO", script.path_info.clone());
+        let translated = config
+            .root_dir
+            .join(script.path_info.trim_start_matches('/'));
+        var("PATH_TRANSLATED", translated.display().to_string());
+    }
+
+    if let Some(remote_addr) = conn.remote_addr {
+        var("REMOTE_ADDR", remote_addr.ip().to_string());
+        var("REMOTE_PORT", remote_addr.port().to_string());
+    }
+
+    for (name, value) in req.headers() {
+        let value = match value.to_str() {
+            Ok(value) => value.to_string(),
+            Err(_) => continue,
+        };
+        if name == header::CONTENT_LENGTH {
+            var("CONTENT_LENGTH", value);
+        } else if name == header::CONTENT_TYPE {
+            var("CONTENT_TYPE", value);
+        } else if name == "proxy" {
+            // HTTP_PROXY would be taken by many scripts' HTTP libraries as
+            // the proxy for their own requests, letting clients redirect them
+            // ("httpoxy", CVE-2016-5385).
+            continue;
+        } else if name != header::AUTHORIZATION {
+            // Credentials aren't passed on, as RFC 3875 suggests.
+            let name = name.as_str().to_ascii_uppercase().replace('-', "_");
+            var(&format!("HTTP_{}", name), value);
+        }
+    }
+
+    // Scripts need to find their interpreters.
+    if let Ok(path) = std::env::var("PATH") {
+        var("PATH", path);
+    }
+
+    vars
+}
+
+/// Read the headers the script writes before its body, and make a response
+/// from them.
+///
+/// https://tools.ietf.org/html/rfc3875#section-6
+async fn read_headers<R>(stdout: &mut R) -> Result<Response<Body>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut headers = String::new();
+
+    loop {
+        // A line is read no further than the limit, so a script writing one
+        // long line can't fill memory.
+        let remaining = MAX_HEADERS_LEN - headers.len();
+        let mut line = String::new();
+        let len = (&mut *stdout)
+            .take(remaining as u64 + 1)
+            .read_line(&mut line)
+            .await
+            .map_err(Error::CgiOutput)?;
+
+        if len == 0 || len > remaining {
+            return Err(missing_end_of_headers());
+        }
+
+        if line.trim_end_matches(['\r', '\n']).is_empty() {
+            break;
+        }
+        headers.push_str(&line);
+    }
+
+    response_from_headers(&headers)
+}
+
+/// The error for script output without a blank line after the headers.
+pub fn missing_end_of_headers() -> Error {
+    let msg = "missing end of headers";
+    Error::CgiOutput(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+/// Make a response from the headers written by a script, without the blank
+/// line after them.
+///
+/// The status is given by the "Status" header, or is 302 Found if there is a
+/// "Location" header, or else 200 OK.
+pub fn response_from_headers(headers: &str) -> Result<Response<Body>> {
+    let mut resp = Response::new(Body::empty());
+    let mut status = None;
+
+    for line in headers.lines() {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim();
+
+        if name.eq_ignore_ascii_case("status") {
+            // Like "404 Not Found".
+            let code = value.split_whitespace().next().unwrap_or("");
+            status = Some(code.parse::<StatusCode>().map_err(|_| {
+                let msg = format!("invalid status '{}'", value);
+                Error::CgiOutput(io::Error::new(io::ErrorKind::InvalidData, msg))
+            })?);
+            continue;
+        }
+
+        match (name.parse::<HeaderName>(), HeaderValue::from_str(value)) {
+            (Ok(name), Ok(value)) => {
+                resp.headers_mut().append(name, value);
+            }
+            _ => warn!("ignoring invalid CGI header: {}", line),
+        }
+    }
+
+    let redirect = resp.headers().contains_key(header::LOCATION);
+    *resp.status_mut() = match status {
+        Some(status) => status,
+        None if redirect => StatusCode::FOUND,
+        None => StatusCode::OK,
+    };
+
+    Ok(resp)
+}
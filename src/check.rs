@@ -0,0 +1,179 @@
+//! Checking the configuration without serving, with the `check` subcommand,
+//! so that a deployment can be tested in CI before it goes live.
+//!
+//! Each file and directory the options name is looked at, TLS certificates
+//! are loaded, and templates are rendered, and the results are printed one
+//! per line.
+
+use super::{Config, Error, HtmlCfg, Page, Result};
+use std::error::Error as StdError;
+use std::fs;
+use std::path::Path;
+
+/// Check the configuration, printing a report, and fail if anything is wrong.
+pub fn run(config: &Config) -> Result<()> {
+    let mut report = Report { failed: false };
+
+    report.line("root directory", check_root(config));
+    for vhost in &config.vhost {
+        let what = format!("--vhost {}", vhost.host());
+        report.line(&what, check_dir(vhost.root_dir()));
+    }
+    for root in &config.fallback_roots {
+        report.line("--fallback-root", check_dir(root));
+    }
+    if !config.rewrite.is_empty() {
+        report.line("--rewrite", check_rewrites(config));
+    }
+    if config.redirects {
+        report.line("--redirects", check_redirects(config));
+    }
+    if !config.plugin.is_empty() {
+        report.line("--plugin", check_plugins(config));
+    }
+    if uses_tls(config) {
+        report.line("TLS", check_tls(config));
+    }
+    if let Some(dir) = &config.template_dir {
+        report.line("--template-dir", check_templates(config, dir));
+    }
+    if let Some(dir) = &config.cgi_dir {
+        report.line("--cgi-dir", check_dir(&config.root_dir.join(dir)));
+    }
+    if let Some(path) = &config.access_log {
+        report.line("--access-log", check_new_file(path));
+    }
+    if let Some(dir) = &config.proxy_cache {
+        report.line("--proxy-cache", check_new_dir(dir));
+    }
+
+    if report.failed {
+        Err(Error::ConfigCheck)
+    } else {
+        println!("configuration ok");
+        Ok(())
+    }
+}
+
+struct Report {
+    failed: bool,
+}
+
+impl Report {
+    fn line(&mut self, what: &str, result: std::result::Result<String, String>) {
+        match result {
+            Ok(detail) => println!("ok     {}: {}", what, detail),
+            Err(problem) => {
+                println!("error  {}: {}", what, problem);
+                self.failed = true;
+            }
+        }
+    }
+}
+
+fn check_root(config: &Config) -> std::result::Result<String, String> {
+    let root = fs::canonicalize(&config.root_dir)
+        .map_err(|e| format!("{}: {}", config.root_dir.display(), e))?;
+    check_dir(&root)
+}
+
+fn uses_tls(config: &Config) -> bool {
+    config.tls_cert.is_some()
+        || config.tls_key.is_some()
+        || config.tls_auto
+        || !config.tls_sni.is_empty()
+        || config.tls_addr.is_some()
+        || config.https_redirect
+}
+
+/// Load the certificates as the server would, and check the options that
+/// depend on them.
+fn check_tls(config: &Config) -> std::result::Result<String, String> {
+    let tls_config = super::tls::server_config(config).map_err(|e| error_chain(&e))?;
+
+    if config.tls_addr.is_some() && tls_config.is_none() {
+        return Err(Error::TlsAddrWithoutTls.to_string());
+    }
+    if config.https_redirect && config.tls_addr.is_none() {
+        return Err(Error::HttpsRedirectWithoutTlsAddr.to_string());
+    }
+
+    let certs = config.tls_sni.len() + usize::from(config.tls_cert.is_some());
+    Ok(format!("{} certificate(s) loaded", certs))
+}
+
+fn check_rewrites(config: &Config) -> std::result::Result<String, String> {
+    super::rewrite::Rewrites::new(config).map_err(|e| error_chain(&e))?;
+    Ok(format!("{} rule(s)", config.rewrite.len() / 2))
+}
+
+fn check_redirects(config: &Config) -> std::result::Result<String, String> {
+    let count = super::redirects::rule_count(config).map_err(|e| error_chain(&e))?;
+    Ok(format!("{} rule(s)", count))
+}
+
+fn check_plugins(config: &Config) -> std::result::Result<String, String> {
+    super::plugin::Plugins::load(config).map_err(|e| error_chain(&e))?;
+    Ok(format!("{} plugin(s)", config.plugin.len()))
+}
+
+/// Render each page with the templates that are there.
+fn check_templates(config: &Config, dir: &Path) -> std::result::Result<String, String> {
+    check_dir(dir)?;
+
+    for page in &[Page::Error, Page::Listing, Page::Markdown] {
+        let cfg = HtmlCfg {
+            title: "Check".to_string(),
+            body: String::new(),
+        };
+        super::render_html(config, *page, cfg)
+            .map_err(|e| format!("{}: {}", page.template_file_name(), error_chain(&e)))?;
+    }
+
+    Ok(dir.display().to_string())
+}
+
+fn check_dir(dir: &Path) -> std::result::Result<String, String> {
+    match fs::metadata(dir) {
+        Ok(meta) if meta.is_dir() => Ok(dir.display().to_string()),
+        Ok(_) => Err(format!("{} is not a directory", dir.display())),
+        Err(e) => Err(format!("{}: {}", dir.display(), e)),
+    }
+}
+
+/// Check that a file is there, or that it can be created in its directory.
+fn check_new_file(path: &Path) -> std::result::Result<String, String> {
+    match fs::metadata(path) {
+        Ok(meta) if meta.is_file() => Ok(path.display().to_string()),
+        Ok(_) => Err(format!("{} is not a file", path.display())),
+        Err(_) => check_parent(path),
+    }
+}
+
+/// Check that a directory is there, or that it can be created.
+fn check_new_dir(dir: &Path) -> std::result::Result<String, String> {
+    match fs::metadata(dir) {
+        Ok(_) => check_dir(dir),
+        Err(_) => check_parent(dir),
+    }
+}
+
+fn check_parent(path: &Path) -> std::result::Result<String, String> {
+    let parent = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    check_dir(parent)?;
+    Ok(format!("{} (will be created)", path.display()))
+}
+
+/// An error and its causes, on one line.
+fn error_chain(mut e: &dyn StdError) -> String {
+    let mut s = e.to_string();
+    while let Some(source) = e.source() {
+        s.push_str(": ");
+        s.push_str(&source.to_string());
+        e = source;
+    }
+    s
+}
@@ -0,0 +1,337 @@
+//! Response compression.
+//!
+//! Response bodies are compressed as they are streamed to the client, using
+//! whichever content encoding the client prefers out of those it advertises in
+//! its `Accept-Encoding` header.
+
+use super::Config;
+use brotli::CompressorWriter;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use futures::task::{Context, Poll};
+use futures::Stream;
+use http::header::{HeaderMap, HeaderValue};
+use http::StatusCode;
+use hyper::{header, Body, Response};
+use std::error::Error as StdError;
+use std::io::{self, Write};
+use std::pin::Pin;
+use tracing::{debug, trace};
+use zstd::stream::write::Encoder as ZstdEncoder;
+
+/// The content encodings supported by the server.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+/// The supported encodings, in order of preference when the client accepts
+/// several of them equally.
+static ENCODINGS: &[Encoding] = &[Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+impl Encoding {
+    /// The name of the encoding as used in `Accept-Encoding` and
+    /// `Content-Encoding` headers.
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Create a fresh encoder for a single response body.
+    fn encoder(self) -> Box<dyn Encoder> {
+        match self {
+            // Quality 11 is far too slow for on-the-fly compression; 5 is a
+            // common choice for dynamic content.
+            Encoding::Brotli => Box::new(CompressorWriter::new(Vec::new(), 4096, 5, 22)),
+            Encoding::Zstd => Box::new(
+                ZstdEncoder::new(Vec::new(), zstd::DEFAULT_COMPRESSION_LEVEL)
+                    .expect("zstd encoder"),
+            ),
+            Encoding::Gzip => Box::new(GzEncoder::new(Vec::new(), flate2::Compression::default())),
+        }
+    }
+}
+
+/// Pick the best encoding out of those the client accepts, if any.
+///
+/// Encodings are ranked by their "q" value, with ties broken by the order of
+/// `ENCODINGS`. A q-value of 0 means "not acceptable".
+///
+/// https://tools.ietf.org/html/rfc7231#section-5.3.4
+pub fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let accept = headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())?;
+
+    let accepted: Vec<(&str, f32)> = accept.split(',').filter_map(parse_coding).collect();
+
+    let qvalue = |name: &str| {
+        let exact = accepted.iter().find(|(n, _)| n.eq_ignore_ascii_case(name));
+        let wildcard = accepted.iter().find(|(n, _)| *n == "*");
+        exact.or(wildcard).map(|(_, q)| *q).unwrap_or(0.0)
+    };
+
+    let mut best = None;
+    let mut best_q = 0.0;
+    for &encoding in ENCODINGS {
+        let q = qvalue(encoding.name());
+        if q > best_q {
+            best = Some(encoding);
+            best_q = q;
+        }
+    }
+
+    best
+}
+
+/// Parse one element of an `Accept-Encoding` list, e.g. "gzip;q=0.8".
+fn parse_coding(s: &str) -> Option<(&str, f32)> {
+    let mut parts = s.split(';').map(str::trim);
+    let name = parts.next().filter(|n| !n.is_empty())?;
+    let mut q = 1.0;
+    for param in parts {
+        if param.starts_with("q=") || param.starts_with("Q=") {
+            q = param[2..].parse().ok()?;
+        }
+    }
+    Some((name, q))
+}
+
+/// Media types that are already compressed, and never worth compressing again.
+#[rustfmt::skip]
+static INCOMPRESSIBLE_TYPES: &[&str] = &[
+    "application/gzip",
+    "application/pdf",
+    "application/vnd.rar",
+    "application/wasm",
+    "application/x-7z-compressed",
+    "application/x-bzip2",
+    "application/x-gzip",
+    "application/x-rar-compressed",
+    "application/x-xz",
+    "application/zip",
+    "application/zstd",
+    "audio/*",
+    "font/woff",
+    "font/woff2",
+    "image/*",
+    "video/*",
+];
+
+/// Media types that match `INCOMPRESSIBLE_TYPES` but compress well anyway.
+static COMPRESSIBLE_EXCEPTIONS: &[&str] = &["image/svg+xml", "image/x-icon", "image/bmp"];
+
+/// Decide whether a response is worth compressing, according to the
+/// configured minimum size and MIME type lists.
+pub fn should_compress(config: &Config, resp: &Response<Body>) -> bool {
+    // Responses without a known length, e.g. rendered markdown, are assumed
+    // to be large enough.
+    let len = resp
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let Some(len) = len {
+        if len < config.compress_min_size {
+            trace!("not compressing {} byte response", len);
+            return false;
+        }
+    }
+
+    let essence = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_ascii_lowercase());
+    let essence = match essence {
+        Some(essence) => essence,
+        None => return false,
+    };
+
+    // Compressors buffer their output, which would hold back events.
+    if essence == "text/event-stream" {
+        return false;
+    }
+
+    let matches = |pattern: &str| mime_matches(pattern, &essence);
+
+    if !config.compress_types.is_empty() {
+        if !config.compress_types.iter().any(|p| matches(p)) {
+            trace!("not compressing {}, not in --compress-type", essence);
+            return false;
+        }
+    } else if INCOMPRESSIBLE_TYPES.iter().any(|p| matches(p))
+        && !COMPRESSIBLE_EXCEPTIONS.contains(&essence.as_str())
+    {
+        trace!("not compressing already-compressed {}", essence);
+        return false;
+    }
+
+    if config.no_compress_types.iter().any(|p| matches(p)) {
+        trace!("not compressing {}, in --no-compress-type", essence);
+        return false;
+    }
+
+    true
+}
+
+/// Match a MIME type like "text/html", without parameters, against a pattern
+/// like "text/html" or "text/*".
+fn mime_matches(pattern: &str, essence: &str) -> bool {
+    let mut pattern = pattern.trim().splitn(2, '/');
+    let mut essence = essence.splitn(2, '/');
+
+    let part_matches = |pattern: Option<&str>, part: Option<&str>| match (pattern, part) {
+        (Some("*"), _) | (None, _) => true,
+        (Some(pattern), Some(part)) => pattern.eq_ignore_ascii_case(part),
+        (Some(_), None) => false,
+    };
+
+    part_matches(pattern.next(), essence.next()) && part_matches(pattern.next(), essence.next())
+}
+
+/// Compress a response body with the given encoding.
+///
+/// Responses without a body, or that are already encoded, are returned
+/// unchanged.
+pub fn compress_response(encoding: Encoding, resp: Response<Body>) -> Response<Body> {
+    let status = resp.status();
+    if status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED {
+        return resp;
+    }
+
+    if resp.headers().contains_key(header::CONTENT_ENCODING) {
+        return resp;
+    }
+
+    debug!("compressing response with {}", encoding.name());
+
+    let (mut parts, body) = resp.into_parts();
+
+    let stream = EncodeStream {
+        body,
+        encoder: Some(encoding.encoder()),
+    };
+
+    // The length of the compressed body isn't known until it is done, so the
+    // response is sent chunked.
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.name()),
+    );
+    parts.headers.append(
+        header::VARY,
+        HeaderValue::from_static(header::ACCEPT_ENCODING.as_str()),
+    );
+
+    // The ETag identifies the uncompressed file, not these exact bytes, so it
+    // may only be used as a weak validator.
+    if let Some(etag) = parts.headers.get(header::ETAG) {
+        if !etag.as_bytes().starts_with(b"W/") {
+            let mut weak = b"W/".to_vec();
+            weak.extend_from_slice(etag.as_bytes());
+            let weak = HeaderValue::from_bytes(&weak).expect("weak etag is valid header");
+            parts.headers.insert(header::ETAG, weak);
+        }
+    }
+
+    Response::from_parts(parts, Body::wrap_stream(stream))
+}
+
+/// Gzip a body, for responses that are gzip files themselves, like tarballs.
+pub fn gzip_body(body: Body) -> Body {
+    let stream = EncodeStream {
+        body,
+        encoder: Some(Encoding::Gzip.encoder()),
+    };
+    Body::wrap_stream(stream)
+}
+
+/// A streaming compressor that writes its output to an in-memory buffer.
+trait Encoder: Send + Sync {
+    /// Compress a chunk of input, returning whatever output is ready.
+    fn encode(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Finish the compressed stream, returning the remaining output.
+    fn finish(self: Box<Self>) -> io::Result<Vec<u8>>;
+}
+
+impl Encoder for CompressorWriter<Vec<u8>> {
+    fn encode(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        self.write_all(chunk)?;
+        Ok(std::mem::take(self.get_mut()))
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<Vec<u8>> {
+        Ok(self.into_inner())
+    }
+}
+
+impl Encoder for GzEncoder<Vec<u8>> {
+    fn encode(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        self.write_all(chunk)?;
+        Ok(std::mem::take(self.get_mut()))
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<Vec<u8>> {
+        (*self).finish()
+    }
+}
+
+impl Encoder for ZstdEncoder<'static, Vec<u8>> {
+    fn encode(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        self.write_all(chunk)?;
+        Ok(std::mem::take(self.get_mut()))
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<Vec<u8>> {
+        (*self).finish()
+    }
+}
+
+/// A stream that compresses a `Body` chunk by chunk.
+struct EncodeStream {
+    body: Body,
+    // This is taken when the body ends and the encoder is finished.
+    encoder: Option<Box<dyn Encoder>>,
+}
+
+impl Stream for EncodeStream {
+    type Item = Result<Bytes, Box<dyn StdError + Send + Sync>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            let encoder = match this.encoder.as_mut() {
+                Some(encoder) => encoder,
+                None => return Poll::Ready(None),
+            };
+
+            let chunk = match Pin::new(&mut this.body).poll_next(cx) {
+                Poll::Ready(chunk) => chunk,
+                Poll::Pending => return Poll::Pending,
+            };
+
+            let out = match chunk {
+                Some(Ok(chunk)) => encoder.encode(&chunk),
+                Some(Err(e)) => return Poll::Ready(Some(Err(Box::new(e)))),
+                None => this.encoder.take().expect("encoder").finish(),
+            };
+
+            match out {
+                // The encoder may buffer its input; wait for more.
+                Ok(ref out) if out.is_empty() => continue,
+                Ok(out) => return Poll::Ready(Some(Ok(Bytes::from(out)))),
+                Err(e) => return Poll::Ready(Some(Err(Box::new(e)))),
+            }
+        }
+    }
+}
@@ -0,0 +1,648 @@
+//! Accepting incoming connections, over either plain TCP or TLS.
+//!
+//! Hyper's own `AddrIncoming` only knows about TCP, so this module provides
+//! the stream of connections that is handed to `Server::builder`.
+//!
+//! Connections are also closed when clients are too slow, since hyper has no
+//! timeouts of its own: a client gets `--header-timeout` to send a request's
+//! headers, and `--keep-alive-timeout` of silence once a response has been
+//! sent. While a request is being handled, it is up to `--request-timeout`.
+//! Once a connection is upgraded, as for a proxied WebSocket, it is a tunnel
+//! with no more requests, and is left open until either end closes it.
+
+use super::proxy_protocol;
+use super::Config;
+use futures::future::{self, Future};
+use futures::stream::{self, Stream, StreamExt};
+use futures::task::{Context, Poll};
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::{delay, Delay, Timeout};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+
+/// The number of TLS handshakes that may be in progress at once.
+const MAX_HANDSHAKES: usize = 64;
+
+/// What plain HTTP clients are sent when there are `--max-connections`
+/// connections open already.
+const BUSY_RESPONSE: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+/// The stream of accepted connections from all listeners.
+pub type Incoming = Pin<Box<dyn Stream<Item = io::Result<Conn>> + Send>>;
+
+/// The bound listeners.
+pub struct Listening {
+    /// The connections from all of the listeners.
+    pub incoming: Incoming,
+    /// The addresses of the `--addr` listeners, with the ports they got.
+    pub addrs: Vec<SocketAddr>,
+}
+
+/// An address to listen on, from `--addr`: an IP address and port, or a host
+/// name and port, e.g. "localhost:4000".
+///
+/// Host names are resolved when the options are parsed, and the server
+/// listens on all of their addresses, so that "localhost" means both IPv4 and
+/// IPv6.
+#[derive(Clone, Debug)]
+pub struct ListenAddr {
+    name: String,
+    addrs: Vec<SocketAddr>,
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ListenAddr, String> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(ListenAddr {
+                name: s.to_string(),
+                addrs: vec![addr],
+            });
+        }
+
+        if !s.contains(':') {
+            return Err(format!("expected HOST:PORT, found '{}'", s));
+        }
+        let mut addrs: Vec<SocketAddr> = s
+            .to_socket_addrs()
+            .map_err(|e| format!("unable to resolve '{}': {}", s, e))?
+            .collect();
+        addrs.sort();
+        addrs.dedup();
+        if addrs.is_empty() {
+            return Err(format!("'{}' has no addresses", s));
+        }
+
+        Ok(ListenAddr {
+            name: s.to_string(),
+            addrs,
+        })
+    }
+}
+
+impl ListenAddr {
+    /// The host name or IP address, without the port.
+    pub fn host(&self) -> &str {
+        self.name
+            .rsplit_once(':')
+            .map_or(&self.name, |(host, _)| host)
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+/// An accepted connection.
+pub struct Conn {
+    io: Io,
+    /// The address of the other end of the TCP connection.
+    peer_addr: Option<SocketAddr>,
+    /// The client's address from a PROXY protocol header.
+    proxied_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    deadline: Deadline,
+    /// Held until the connection is closed.
+    _slot: Slot,
+}
+
+/// Information about the connection a request arrived on.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnInfo {
+    /// Whether the connection is using TLS.
+    pub tls: bool,
+    /// The address of the client, if it could be found, which is from the
+    /// PROXY protocol header, or the headers of a `--trusted-proxy`'s
+    /// requests.
+    pub remote_addr: Option<SocketAddr>,
+    /// The address of the other end of the TCP connection, which is a
+    /// proxy's or load balancer's when behind one.
+    pub peer_addr: Option<SocketAddr>,
+    /// The address the load balancer gave with `--proxy-protocol`, which is
+    /// the client's or the next proxy's.
+    pub proxied_addr: Option<SocketAddr>,
+    /// The address the client connected to, if it could be found.
+    pub local_addr: Option<SocketAddr>,
+}
+
+impl Conn {
+    pub fn info(&self) -> ConnInfo {
+        ConnInfo {
+            tls: match self.io {
+                Io::Plain(_) => false,
+                Io::Tls(_) => true,
+            },
+            remote_addr: self.remote_addr(),
+            peer_addr: self.peer_addr,
+            proxied_addr: self.proxied_addr,
+            local_addr: self.local_addr,
+        }
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.proxied_addr.or(self.peer_addr)
+    }
+
+    /// The handle for telling the connection when requests are handled.
+    pub fn activity(&self) -> Activity {
+        Activity {
+            requests: self.deadline.requests.clone(),
+        }
+    }
+}
+
+/// Tells a connection's timeouts when its requests are being handled.
+#[derive(Clone)]
+pub struct Activity {
+    requests: Arc<Requests>,
+}
+
+impl Activity {
+    /// Mark a request as being handled until the guard is dropped, which
+    /// turns off the connection's header and keep-alive timeouts.
+    pub fn start_request(&self) -> RequestGuard {
+        let number = self.requests.started.fetch_add(1, Ordering::SeqCst) + 1;
+        self.requests.handling.fetch_add(1, Ordering::SeqCst);
+        RequestGuard {
+            requests: self.requests.clone(),
+            number,
+        }
+    }
+}
+
+/// A request being handled, from `Activity::start_request`.
+pub struct RequestGuard {
+    requests: Arc<Requests>,
+    number: usize,
+}
+
+impl RequestGuard {
+    /// Which request on the connection this is, counting from 1.
+    pub fn number(&self) -> usize {
+        self.number
+    }
+
+    /// Mark the connection as upgraded by this request's response, which
+    /// turns off its timeouts for good.
+    pub fn upgraded(&self) {
+        self.requests.upgraded.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.requests.handling.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The requests of a connection.
+#[derive(Default)]
+struct Requests {
+    /// How many have been started, so that requests handled between two
+    /// polls of the connection aren't missed.
+    started: AtomicUsize,
+    /// How many are being handled.
+    handling: AtomicUsize,
+    /// Whether the connection has been upgraded to another protocol.
+    upgraded: AtomicBool,
+}
+
+/// What a connection is waiting for, which decides its timeout.
+#[derive(Clone, Copy, PartialEq)]
+enum Waiting {
+    /// The rest of a request's headers, up to `--header-timeout`.
+    Headers,
+    /// A request's response, with no timeout here.
+    Handler,
+    /// Any activity, up to `--keep-alive-timeout`. This is the state while a
+    /// response body is sent and between requests.
+    Activity,
+    /// Nothing, since the connection has been upgraded.
+    Tunnel,
+}
+
+/// The time by which a connection is closed, unless it makes progress.
+struct Deadline {
+    header_timeout: Option<Duration>,
+    keep_alive_timeout: Option<Duration>,
+    requests: Arc<Requests>,
+    /// The number of requests started when the connection was last polled.
+    started: usize,
+    waiting: Waiting,
+    delay: Option<Delay>,
+}
+
+impl Deadline {
+    fn new(timeouts: Timeouts) -> Deadline {
+        let mut deadline = Deadline {
+            header_timeout: timeouts.header,
+            keep_alive_timeout: timeouts.keep_alive,
+            requests: Arc::new(Requests::default()),
+            started: 0,
+            waiting: Waiting::Headers,
+            delay: None,
+        };
+        deadline.set(timeouts.header);
+        deadline
+    }
+
+    fn set(&mut self, timeout: Option<Duration>) {
+        match (timeout, &mut self.delay) {
+            (Some(timeout), Some(d)) => d.reset(Instant::now() + timeout),
+            (Some(timeout), None) => self.delay = Some(delay(Instant::now() + timeout)),
+            (None, _) => self.delay = None,
+        }
+    }
+
+    /// Check whether the deadline has passed, arranging to be woken when it
+    /// does.
+    fn poll_expired(&mut self, cx: &mut Context) -> bool {
+        if self.requests.upgraded.load(Ordering::SeqCst) {
+            self.waiting = Waiting::Tunnel;
+            self.delay = None;
+            return false;
+        }
+
+        if self.requests.handling.load(Ordering::SeqCst) > 0 {
+            self.waiting = Waiting::Handler;
+            return false;
+        }
+
+        let started = self.requests.started.load(Ordering::SeqCst);
+        if self.waiting == Waiting::Handler || started != self.started {
+            self.waiting = Waiting::Activity;
+            self.set(self.keep_alive_timeout);
+        }
+        self.started = started;
+
+        match &mut self.delay {
+            Some(d) => Pin::new(d).poll(cx).is_ready(),
+            None => false,
+        }
+    }
+
+    /// Note bytes read. The first bytes of a request start its header
+    /// timeout, which more bytes don't extend, so that clients can't send
+    /// their headers a byte at a time.
+    fn read(&mut self, n: usize) {
+        if n > 0 && self.waiting == Waiting::Activity {
+            self.waiting = Waiting::Headers;
+            self.set(self.header_timeout);
+        }
+    }
+
+    /// Note bytes written, which keeps a connection sending a response open.
+    fn wrote(&mut self, n: usize) {
+        if n > 0 && self.waiting == Waiting::Activity {
+            self.set(self.keep_alive_timeout);
+        }
+    }
+}
+
+/// The error that closes a connection when its deadline passes.
+fn timed_out(remote_addr: Option<SocketAddr>) -> io::Error {
+    debug!("closing slow connection from {:?}", remote_addr);
+    io::Error::new(io::ErrorKind::TimedOut, "connection timed out")
+}
+
+/// The timeouts of new connections, where `None` is no timeout.
+#[derive(Clone, Copy)]
+struct Timeouts {
+    header: Option<Duration>,
+    keep_alive: Option<Duration>,
+}
+
+/// A timeout in seconds from the configuration, where 0 is no timeout.
+pub fn timeout_secs(secs: u64) -> Option<Duration> {
+    if secs == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(secs))
+    }
+}
+
+enum Io {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// The count of open connections, shared by all listeners, and the
+/// timeouts for new ones, and whether they start with a PROXY protocol header.
+#[derive(Clone)]
+struct Connections {
+    open: Arc<AtomicUsize>,
+    max: Option<usize>,
+    timeouts: Timeouts,
+    proxy_protocol: bool,
+}
+
+/// One open connection in the count, given back when dropped.
+struct Slot {
+    open: Arc<AtomicUsize>,
+}
+
+impl Connections {
+    /// Count a new connection, unless there are too many already.
+    fn take_slot(&self) -> Option<Slot> {
+        let open = self.open.fetch_add(1, Ordering::SeqCst);
+        let slot = Slot {
+            open: self.open.clone(),
+        };
+        if self.max.is_some_and(|max| open >= max) {
+            return None;
+        }
+        Some(slot)
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Bind the listeners for the configuration.
+///
+/// Without `--tls-addr` there are listeners on the addresses of `--addr`,
+/// which use TLS if it is enabled. With `--tls-addr` there is also a TLS
+/// listener on that address, and the `--addr` listeners are plain HTTP.
+///
+/// Connections beyond `--max-connections` are closed as soon as they are
+/// accepted, after a 503 Service Unavailable response on plain HTTP.
+pub async fn listen(config: &Config, tls: Option<TlsAcceptor>) -> io::Result<Listening> {
+    let listeners = bind_all(&config.addr, config.port_retry.unwrap_or(0)).await?;
+    let addrs = listeners
+        .iter()
+        .map(TcpListener::local_addr)
+        .collect::<io::Result<_>>()?;
+    let conns = Connections {
+        open: Arc::new(AtomicUsize::new(0)),
+        max: config.max_connections,
+        timeouts: Timeouts {
+            header: timeout_secs(config.header_timeout),
+            keep_alive: timeout_secs(config.keep_alive_timeout),
+        },
+        proxy_protocol: config.proxy_protocol,
+    };
+
+    let (tls, mut tls_listener) = match (config.tls_addr, tls) {
+        (Some(tls_addr), Some(tls)) => {
+            let tls_listener = TcpListener::bind(&tls_addr).await?;
+            info!("listening on {} for TLS", tls_listener.local_addr()?);
+            (None, Some(incoming(tls_listener, Some(tls), conns.clone())))
+        }
+        (_, tls) => (tls, None),
+    };
+
+    let mut streams: Vec<Incoming> = listeners
+        .into_iter()
+        .map(|listener| incoming(listener, tls.clone(), conns.clone()))
+        .collect();
+    streams.extend(tls_listener.take());
+
+    Ok(Listening {
+        incoming: Box::pin(stream::select_all(streams)),
+        addrs,
+    })
+}
+
+/// Bind a listener to each address of `--addr`, trying up to `retries`
+/// higher ports if the port is in use.
+async fn bind_all(addr: &ListenAddr, retries: u16) -> io::Result<Vec<TcpListener>> {
+    let mut port = addr.addrs[0].port();
+    let mut retries_left = retries;
+
+    loop {
+        match bind_port(addr, port).await {
+            Ok(listeners) => {
+                for listener in &listeners {
+                    info!("listening on {}", listener.local_addr()?);
+                }
+                return Ok(listeners);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && retries_left > 0 && port != 0 => {
+                let next = port.checked_add(1).ok_or(e)?;
+                warn!("port {} is in use, trying {}", port, next);
+                port = next;
+                retries_left -= 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Bind a listener to each address of `--addr` on a port.
+///
+/// With port 0, the first listener gets a free port from the OS, and the
+/// others use the same one. A host name's addresses may include ones that
+/// can't be used, such as IPv6 addresses on a host without IPv6, so other
+/// failures are only an error if nothing could be bound.
+async fn bind_port(addr: &ListenAddr, mut port: u16) -> io::Result<Vec<TcpListener>> {
+    let mut listeners = Vec::new();
+    let mut first_err = None;
+
+    for addr in &addr.addrs {
+        let addr = SocketAddr::new(addr.ip(), port);
+        match TcpListener::bind(&addr).await {
+            Ok(listener) => {
+                port = listener.local_addr()?.port();
+                listeners.push(listener);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => return Err(e),
+            Err(e) => {
+                warn!("unable to listen on {}: {}", addr, e);
+                first_err.get_or_insert(e);
+            }
+        }
+    }
+
+    match first_err {
+        Some(e) if listeners.is_empty() => Err(e),
+        _ => Ok(listeners),
+    }
+}
+
+/// Accept connections from a listener, performing the TLS handshake if an
+/// acceptor is provided.
+///
+/// Failed accepts and handshakes are logged and skipped, since they only
+/// affect a single client, and returning them to hyper would stop the server.
+fn incoming(listener: TcpListener, tls: Option<TlsAcceptor>, conns: Connections) -> Incoming {
+    let is_tls = tls.is_some();
+    let timeouts = conns.timeouts;
+    let proxy_protocol = conns.proxy_protocol;
+
+    let conns = listener
+        .incoming()
+        .filter_map(move |stream| {
+            let conns = conns.clone();
+            async move {
+                match stream {
+                    Ok(stream) => match conns.take_slot() {
+                        Some(slot) => Some((stream, slot)),
+                        None => {
+                            refuse(stream, is_tls);
+                            None
+                        }
+                    },
+                    Err(e) => {
+                        // Errors like running out of file descriptors will
+                        // probably recur immediately, so wait a moment.
+                        error!("accept error: {}", e);
+                        tokio::timer::delay_for(Duration::from_secs(1)).await;
+                        None
+                    }
+                }
+            }
+        })
+        .map(move |(mut stream, slot)| {
+            let tls = tls.clone();
+            async move {
+                let peer_addr = stream.peer_addr().ok();
+                let mut proxied_addr = None;
+                let local_addr = stream.local_addr().ok();
+
+                // The load balancer's header comes before the TLS handshake,
+                // and gets the same time as request headers.
+                if proxy_protocol {
+                    let header = proxy_protocol::read_header(&mut stream);
+                    let header = match timeouts.header {
+                        Some(timeout) => Timeout::new(header, timeout)
+                            .await
+                            .unwrap_or_else(|_| Err(timed_out(peer_addr))),
+                        None => header.await,
+                    };
+                    match header {
+                        Ok(Some(addr)) => proxied_addr = Some(addr),
+                        Ok(None) => {}
+                        Err(e) => {
+                            debug!("PROXY protocol error from {:?}: {}", peer_addr, e);
+                            return None;
+                        }
+                    }
+                }
+
+                // The handshake gets the same time too, so that clients that
+                // never finish it can't hold every handshake slot.
+                let io = match tls {
+                    None => Io::Plain(stream),
+                    Some(tls) => {
+                        let handshake = tls.accept(stream);
+                        let handshake = match timeouts.header {
+                            Some(timeout) => Timeout::new(handshake, timeout)
+                                .await
+                                .unwrap_or_else(|_| Err(timed_out(peer_addr))),
+                            None => handshake.await,
+                        };
+                        match handshake {
+                            Ok(stream) => Io::Tls(Box::new(stream)),
+                            Err(e) => {
+                                debug!("TLS handshake error: {}", e);
+                                return None;
+                            }
+                        }
+                    }
+                };
+                Some(Conn {
+                    io,
+                    peer_addr,
+                    proxied_addr,
+                    local_addr,
+                    deadline: Deadline::new(timeouts),
+                    _slot: slot,
+                })
+            }
+        })
+        .buffer_unordered(MAX_HANDSHAKES)
+        .filter_map(future::ready)
+        .map(Ok);
+
+    Box::pin(conns)
+}
+
+/// Close a connection that is over the limit, telling plain HTTP clients
+/// that the server is busy.
+///
+/// TLS clients are just disconnected, since answering them would mean doing
+/// the handshake that the limit is there to avoid.
+fn refuse(mut stream: TcpStream, is_tls: bool) {
+    warn!(
+        "refusing connection from {}: too many connections",
+        stream
+            .peer_addr()
+            .map_or_else(|_| "unknown".to_string(), |a| a.to_string())
+    );
+    if !is_tls {
+        tokio::spawn(async move {
+            let _ = stream.write_all(BUSY_RESPONSE).await;
+        });
+    }
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let conn = self.get_mut();
+        if conn.deadline.poll_expired(cx) {
+            return Poll::Ready(Err(timed_out(conn.remote_addr())));
+        }
+
+        let poll = match &mut conn.io {
+            Io::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            Io::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        };
+        if let Poll::Ready(Ok(n)) = poll {
+            conn.deadline.read(n);
+        }
+        poll
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let conn = self.get_mut();
+        if conn.deadline.poll_expired(cx) {
+            return Poll::Ready(Err(timed_out(conn.remote_addr())));
+        }
+
+        let poll = match &mut conn.io {
+            Io::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            Io::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        };
+        if let Poll::Ready(Ok(n)) = poll {
+            conn.deadline.wrote(n);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().io {
+            Io::Plain(s) => Pin::new(s).poll_flush(cx),
+            Io::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().io {
+            Io::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            Io::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! Artificial latency, with `--delay`, for testing how clients behave when
+//! responses are slow, such as their spinners, timeouts and races.
+
+use super::Config;
+use globset::GlobMatcher;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::timer::delay_for;
+use tracing::debug;
+
+/// A delay before responding, optionally only for paths matching a glob.
+///
+/// Written on the command line as "MS", or "GLOB=MS", e.g. "/api/**=2000".
+#[derive(Clone, Debug)]
+pub struct Delay {
+    glob: Option<GlobMatcher>,
+    duration: Duration,
+}
+
+impl FromStr for Delay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Delay, String> {
+        let (glob, ms) = match s.rfind('=') {
+            Some(i) => (Some(super::headers::path_glob(&s[..i])?), &s[i + 1..]),
+            None => (None, s),
+        };
+
+        let ms = ms
+            .trim()
+            .parse::<u64>()
+            .map_err(|_| format!("expected milliseconds, found '{}'", ms))?;
+
+        Ok(Delay {
+            glob,
+            duration: Duration::from_millis(ms),
+        })
+    }
+}
+
+/// Sleep for the delay that applies to a request path, if any.
+///
+/// When several delays match, the last one given wins, so that a delay for
+/// some paths can follow one for every path.
+pub async fn sleep(config: &Config, path: &str) {
+    let delay = config
+        .delay
+        .iter()
+        .rev()
+        .find(|delay| delay.glob.as_ref().is_none_or(|g| g.is_match(path)));
+
+    if let Some(delay) = delay {
+        debug!("delaying {} by {:?}", path, delay.duration);
+        delay_for(delay.duration).await;
+    }
+}
@@ -0,0 +1,254 @@
+//! Navigation for serving a folder of markdown as a docs site, with `--docs`.
+//!
+//! Every rendered markdown page gets a sidebar of the markdown files under the
+//! root directory, like a lightweight mdBook, and links to the previous and
+//! next pages in the sidebar's order.
+//!
+//! The directory tree is read again for every page, on a blocking thread, so
+//! that new pages show up without a restart. Symlinked directories aren't
+//! followed, so links back up the tree can't make the walk endless.
+
+use super::ext::{escape_html, rewrite_md_link, split_front_matter, PATH_SET};
+use super::Config;
+use percent_encoding::utf8_percent_encode;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio_executor::blocking;
+use tracing::{debug, warn};
+
+/// Styles for the sidebar layout, widening the page to make room for it.
+static DOCS_STYLE: &str = r#"
+<style type="text/css">
+  main { max-width: 110ch; }
+  .docs { display: flex; gap: 3ch; align-items: flex-start; }
+  .docs-nav { flex: 0 0 26ch; position: sticky; top: 2ch; font-size: 0.9em; }
+  .docs-nav ul { list-style: none; padding-left: 1.5ch; }
+  .docs-nav > ul { padding-left: 0; }
+  .docs-nav [aria-current] { font-weight: bold; }
+  .docs-page { flex: 1; min-width: 0; }
+  .docs-pager { display: flex; justify-content: space-between; margin-top: 4ch; }
+</style>
+"#;
+
+/// A markdown page in the navigation.
+struct Doc {
+    path: PathBuf,
+    title: String,
+}
+
+/// A directory of markdown pages.
+#[derive(Default)]
+struct Section {
+    /// The "README.md" or "index.md" page of the directory.
+    index: Option<Doc>,
+    pages: Vec<Doc>,
+    /// Subdirectories containing markdown, with their names.
+    sections: Vec<(String, Section)>,
+}
+
+impl Section {
+    fn is_empty(&self) -> bool {
+        self.index.is_none() && self.pages.is_empty() && self.sections.is_empty()
+    }
+
+    /// All pages in the order they appear in the sidebar.
+    fn flatten<'a>(&'a self, docs: &mut Vec<&'a Doc>) {
+        docs.extend(&self.index);
+        docs.extend(&self.pages);
+        for (_, section) in &self.sections {
+            section.flatten(docs);
+        }
+    }
+}
+
+/// Put the sidebar next to the body of a rendered markdown page, and the
+/// previous and next links below it.
+///
+/// If the tree can't be read the page is returned without navigation.
+pub async fn add_navigation(config: &Config, path: &Path, body: String) -> String {
+    let config_ = config.clone();
+    let root = blocking::run(move || read_section(&config_, &config_.root_dir)).await;
+    let root = match root {
+        Ok(root) => root,
+        Err(e) => {
+            warn!("unable to read docs tree: {}", e);
+            return body;
+        }
+    };
+
+    let mut buf = String::new();
+    buf.push_str(DOCS_STYLE);
+    buf.push_str("<div class=\"docs\">\n<nav class=\"docs-nav\">\n");
+    write_section(&mut buf, config, path, &root, true);
+    buf.push_str("</nav>\n<div class=\"docs-page\">\n");
+    buf.push_str(&body);
+    write_pager(&mut buf, config, path, &root);
+    buf.push_str("</div>\n</div>\n");
+    buf
+}
+
+/// Collect the markdown pages in a directory and its subdirectories, sorted by
+/// file name, and leaving out hidden files and unreadable subdirectories.
+fn read_section(config: &Config, dir: &Path) -> io::Result<Section> {
+    let mut paths = Vec::new();
+    for dent in fs::read_dir(dir)? {
+        let path = dent?.path();
+        if !super::hide::is_hidden(config, &path) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut section = Section::default();
+    for path in paths {
+        let name = match path.file_name().and_then(OsStr::to_str) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        let is_dir = fs::symlink_metadata(&path)
+            .map(|meta| meta.is_dir())
+            .unwrap_or(false);
+        if is_dir {
+            match read_section(config, &path) {
+                Ok(subsection) if !subsection.is_empty() => {
+                    section.sections.push((name, subsection));
+                }
+                Ok(_) => {}
+                Err(e) => debug!("skipping {} in docs tree: {}", path.display(), e),
+            }
+        } else if path.extension() == Some(OsStr::new("md")) {
+            let is_index =
+                name.eq_ignore_ascii_case("README.md") || name.eq_ignore_ascii_case("index.md");
+            let doc = Doc {
+                title: page_title(config, &path),
+                path,
+            };
+            if is_index && section.index.is_none() {
+                section.index = Some(doc);
+            } else {
+                section.pages.push(doc);
+            }
+        }
+    }
+
+    Ok(section)
+}
+
+/// The title of a page from its front matter, or its first heading, or its
+/// file name.
+fn page_title(config: &Config, path: &Path) -> String {
+    let file_stem = || {
+        path.file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(_) => return file_stem(),
+    };
+    let (front_matter, body) = split_front_matter(config, &contents);
+
+    front_matter
+        .title
+        .or_else(|| {
+            body.lines()
+                .find(|line| line.starts_with("# "))
+                .map(|line| line[2..].trim().to_string())
+        })
+        .filter(|title| !title.is_empty())
+        .unwrap_or_else(file_stem)
+}
+
+/// Write the nested lists of the sidebar. A subdirectory is headed by a link
+/// to its index page, or just its name.
+fn write_section(buf: &mut String, config: &Config, current: &Path, section: &Section, root: bool) {
+    buf.push_str("<ul>\n");
+    if root {
+        if let Some(index) = &section.index {
+            buf.push_str("<li>");
+            write_link(buf, config, current, index);
+            buf.push_str("</li>\n");
+        }
+    }
+    for doc in &section.pages {
+        buf.push_str("<li>");
+        write_link(buf, config, current, doc);
+        buf.push_str("</li>\n");
+    }
+    for (name, subsection) in &section.sections {
+        buf.push_str("<li>");
+        match &subsection.index {
+            Some(index) => write_link(buf, config, current, index),
+            None => buf.push_str(&escape_html(name)),
+        }
+        buf.push('\n');
+        write_section(buf, config, current, subsection, false);
+        buf.push_str("</li>\n");
+    }
+    buf.push_str("</ul>\n");
+}
+
+/// Write the links to the pages before and after the current one.
+fn write_pager(buf: &mut String, config: &Config, current: &Path, root: &Section) {
+    let mut docs = Vec::new();
+    root.flatten(&mut docs);
+
+    let i = match docs.iter().position(|doc| doc.path == current) {
+        Some(i) => i,
+        None => return,
+    };
+    let prev = i.checked_sub(1).map(|i| docs[i]);
+    let next = docs.get(i + 1);
+
+    buf.push_str("<nav class=\"docs-pager\">\n<span>");
+    if let Some(prev) = prev {
+        buf.push_str(&format!(
+            "<a rel=\"prev\" href=\"{}\">&larr; {}</a>",
+            doc_url(config, prev),
+            escape_html(&prev.title)
+        ));
+    }
+    buf.push_str("</span>\n<span>");
+    if let Some(next) = next {
+        buf.push_str(&format!(
+            "<a rel=\"next\" href=\"{}\">{} &rarr;</a>",
+            doc_url(config, next),
+            escape_html(&next.title)
+        ));
+    }
+    buf.push_str("</span>\n</nav>\n");
+}
+
+/// Write a link to a page, marked if it is the current page.
+fn write_link(buf: &mut String, config: &Config, current: &Path, doc: &Doc) {
+    let current = if doc.path == current {
+        " aria-current=\"page\""
+    } else {
+        ""
+    };
+    buf.push_str(&format!(
+        "<a href=\"{}\"{}>{}</a>",
+        doc_url(config, doc),
+        current,
+        escape_html(&doc.title)
+    ));
+}
+
+/// The absolute URL of a page, with the `--md-link-query` if there is one.
+fn doc_url(config: &Config, doc: &Doc) -> String {
+    let rel_path = doc.path.strip_prefix(&config.root_dir).unwrap_or(&doc.path);
+    let segments: Vec<String> = rel_path
+        .components()
+        .map(|c| utf8_percent_encode(&c.as_os_str().to_string_lossy(), PATH_SET).to_string())
+        .collect();
+    let url = super::base_path::url(config, &format!("/{}", segments.join("/")));
+
+    match &config.md_link_query {
+        Some(query) => rewrite_md_link(query, &url),
+        None => url,
+    }
+}
@@ -0,0 +1,72 @@
+//! Setting options with `BASIC_HTTP_SERVER_*` environment variables, for
+//! containers and CI.
+//!
+//! Each variable is turned into the option with the same name, so
+//! `BASIC_HTTP_SERVER_MAX_AGE=60` is `--max-age=60`, and the options are put
+//! before the command line's, which win, since every option overrides itself.
+//! Flags are set with "true" and left off with "false" or an empty value.
+//! Options that can be repeated take one value per line, and are added to
+//! those on the command line. The root directory is `BASIC_HTTP_SERVER_ROOT`.
+//!
+//! This is also where the default subcommand, `serve`, is added to a command
+//! line that doesn't start with one.
+
+use std::env;
+use std::ffi::OsString;
+
+/// The prefix of the environment variables.
+const PREFIX: &str = "BASIC_HTTP_SERVER_";
+
+/// The variable for the root directory, which is an argument, not an option.
+const ROOT_VAR: &str = "BASIC_HTTP_SERVER_ROOT";
+
+/// The arguments that ask for help, the version or shell completions, which
+/// take none of the options.
+const STANDALONE_ARGS: &[&str] = &["-h", "--help", "help", "-V", "--version", "completions"];
+
+/// The command line arguments, with `serve` added if there's no subcommand
+/// from `commands`, and the options from the environment put after the
+/// subcommand.
+pub fn args(commands: &[&str]) -> Vec<OsString> {
+    let mut args: Vec<OsString> = env::args_os().collect();
+    let first = args.get(1).and_then(|arg| arg.to_str()).unwrap_or("");
+    if STANDALONE_ARGS.contains(&first) {
+        return args;
+    }
+    if !commands.contains(&first) {
+        let at = args.len().min(1);
+        args.insert(at, "serve".into());
+    }
+
+    let env_args = env_args(env::vars_os());
+    let at = args.len().min(2);
+    args.splice(at..at, env_args);
+    args
+}
+
+/// The options for the variables with the prefix, in name order.
+fn env_args(vars: impl Iterator<Item = (OsString, OsString)>) -> Vec<OsString> {
+    let mut vars: Vec<(String, String)> = vars
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .filter(|(name, _)| name.starts_with(PREFIX) && name != ROOT_VAR)
+        .collect();
+    vars.sort();
+
+    let mut args = Vec::new();
+    for (name, value) in vars {
+        let option = format!(
+            "--{}",
+            name[PREFIX.len()..].to_lowercase().replace('_', "-")
+        );
+        match value.trim() {
+            "true" => args.push(option.into()),
+            "false" | "" => {}
+            value => {
+                for line in value.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                    args.push(format!("{}={}", option, line).into());
+                }
+            }
+        }
+    }
+    args
+}
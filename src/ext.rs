@@ -1,54 +1,131 @@
 //! Developer extensions for basic-http-server
 //!
-//! This code is not as clean and well-documented as main.rs,
+//! This code is not as clean and well-documented as lib.rs,
 //! but could still be a useful read.
 
-use super::{Config, HtmlCfg};
-use comrak::ComrakOptions;
-use futures::{future, StreamExt};
-use http::{Request, Response, StatusCode};
+use super::{Config, Hook};
+use futures::future::{BoxFuture, FutureExt};
+use http::{Request, Response};
 use hyper::{header, Body};
-use log::{trace, warn};
-use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use percent_encoding::{percent_decode_str, AsciiSet, CONTROLS};
 use std::error::Error as StdError;
-use std::ffi::OsStr;
-use std::fmt::Write;
 use std::io;
-use std::path::{Path, PathBuf};
-use tokio_fs::DirEntry;
+use std::path::{Component, Path, PathBuf};
+use tracing::trace;
+
+#[cfg(any(feature = "markdown", feature = "dir-listing"))]
+use {
+    super::{HtmlCfg, Page},
+    http::StatusCode,
+    std::ffi::OsStr,
+    tracing::warn,
+};
+
+#[cfg(feature = "markdown")]
+use {
+    comrak::nodes::{AstNode, NodeValue},
+    comrak::options::Plugins,
+    comrak::plugins::syntect::{SyntectAdapter, SyntectAdapterBuilder},
+    comrak::{Anchorizer, Arena, Options},
+    regex::Regex,
+    serde::Deserialize,
+    std::collections::HashMap,
+    std::sync::{Arc, Mutex, OnceLock},
+};
+
+#[cfg(feature = "dir-listing")]
+use {
+    futures::{future, StreamExt},
+    percent_encoding::utf8_percent_encode,
+    std::fmt::Write,
+    tokio_fs::DirEntry,
+};
+
+/// The extensions, as the hook run after serving files.
+pub struct Extensions;
+
+impl Hook for Extensions {
+    fn after<'a>(
+        &'a self,
+        config: &'a Config,
+        req: &'a Request<Body>,
+        resp: super::Result<Response<Body>>,
+    ) -> BoxFuture<'a, super::Result<Response<Body>>> {
+        serve(config, req, resp).boxed()
+    }
+}
 
 /// The entry point to extensions. Extensions are given both the request and the
 /// response result from regular file serving, and have the opportunity to
 /// replace the response with their own response.
-pub async fn serve(
-    config: Config,
-    req: Request<Body>,
+async fn serve(
+    config: &Config,
+    req: &Request<Body>,
     resp: super::Result<Response<Body>>,
 ) -> super::Result<Response<Body>> {
     trace!("checking extensions");
 
+    let path = super::local_path_for_request(req.uri(), &config.root_dir)?;
+
+    // List the directory instead of serving its index.html, if asked to with
+    // `--list-dirs` or, with the other extensions, the `?list` query.
+    if config.list_dirs || (config.use_extensions && has_query_param(req, "list")) {
+        if let Some(resp) = maybe_list_dir(config, req, &path).await? {
+            trace!("using forced directory listing");
+            return Ok(resp);
+        }
+    }
+
     if !config.use_extensions {
         return resp;
     }
 
-    let path = super::local_path_for_request(&req.uri(), &config.root_dir)?;
-    let file_ext = path.extension().and_then(OsStr::to_str).unwrap_or("");
+    // Download directories as tarballs with `?tar.gz`, but nothing outside
+    // the root directory, as with "/.." or "//".
+    if super::archive::wants_tar_gz(req) {
+        match super::webdav::local_path(config, req.uri())? {
+            Some(dir) if dir.is_dir() => {
+                trace!("using tarball extension");
+                return super::archive::tar_gz_response(config, &dir);
+            }
+            Some(_) => {}
+            None => {
+                return super::make_error_response_from_code(config, http::StatusCode::FORBIDDEN)
+            }
+        }
+    }
 
-    if file_ext == "md" {
-        trace!("using markdown extension");
-        return Ok(md_path_to_html(&path).await?);
+    #[cfg(feature = "markdown")]
+    {
+        let file_ext = path.extension().and_then(OsStr::to_str).unwrap_or("");
+        if file_ext == "md" {
+            // Serve the source with `?raw` or `Accept: text/markdown`
+            if wants_raw_markdown(req) {
+                trace!("serving raw markdown");
+                return resp.map(|resp| raw_markdown_response(config, &path, resp));
+            }
+
+            trace!("using markdown extension");
+            let mut resp = md_path_to_html(config, &path).await?;
+            resp.headers_mut()
+                .append(header::VARY, header::HeaderValue::from_static("accept"));
+            return Ok(resp);
+        }
     }
 
     match resp {
         Ok(mut resp) => {
-            // Serve source code as plain text to render them in the browser
-            maybe_convert_mime_type_to_text(&req, &mut resp);
+            // Serve source code as plain text to render them in the browser,
+            // unless the user chose the type with `--mime`
+            if super::mime_type::mime_override(config, &path).is_none() {
+                maybe_convert_mime_type_to_text(config, req, &mut resp);
+            }
             Ok(resp)
         }
         Err(super::Error::Io(e)) => {
             // If the requested file was not found, then try doing a directory listing.
             if e.kind() == io::ErrorKind::NotFound {
-                let list_dir_resp = maybe_list_dir(&config.root_dir, &path).await?;
+                let list_dir_resp = maybe_list_dir(config, req, &path).await?;
                 trace!("using directory list extension");
                 if let Some(f) = list_dir_resp {
                     Ok(f)
@@ -63,27 +140,86 @@ pub async fn serve(
     }
 }
 
-/// Load a markdown file, render to HTML, and return the response.
-async fn md_path_to_html(path: &Path) -> Result<Response<Body>> {
-    // Render Markdown like GitHub
-    let mut options = ComrakOptions::default();
-    options.ext_autolink = true;
-    options.ext_header_ids = None;
-    options.ext_table = true;
-    options.ext_strikethrough = true;
-    options.ext_tagfilter = true;
-    options.ext_tasklist = true;
-    options.github_pre_lang = true;
-    options.ext_header_ids = Some("user-content-".to_string());
+/// The value of a query string parameter, decoded as from an HTML form.
+pub fn query_param(req: &Request<Body>, name: &str) -> Option<String> {
+    req.uri().query()?.split('&').find_map(|param| {
+        let mut parts = param.splitn(2, '=');
+        if parts.next()? != name {
+            return None;
+        }
+        let value = parts.next().unwrap_or("").replace('+', " ");
+        Some(percent_decode_str(&value).decode_utf8_lossy().into_owned())
+    })
+}
 
-    let buf = tokio::fs::read(path).await?;
-    let s = String::from_utf8(buf).map_err(|_| Error::MarkdownUtf8)?;
-    let html = comrak::markdown_to_html(&s, &options);
-    let cfg = HtmlCfg {
-        title: String::new(),
-        body: html,
-    };
-    let html = super::render_html(cfg)?;
+/// Whether the query string has a parameter, with or without a value, like
+/// `?list` or `?list=1`.
+pub fn has_query_param(req: &Request<Body>, name: &str) -> bool {
+    query_param(req, name).is_some()
+}
+
+/// Respond with JSON, for the endpoints that scripts call.
+pub fn json_response(
+    status: http::StatusCode,
+    json: serde_json::Value,
+) -> super::Result<Response<Body>> {
+    let body = json.to_string();
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(Body::from(body))
+        .map_err(super::Error::from)
+}
+
+/// Map a URL path without %-encoding, as given in a query string or JSON,
+/// to a local path, or `None` if it would be outside the root directory.
+pub fn url_path_to_local(config: &Config, url_path: &str) -> Option<PathBuf> {
+    let rel_path = Path::new(url_path.strip_prefix('/')?);
+    let outside = rel_path
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+    if outside {
+        return None;
+    }
+    Some(config.root_dir.join(rel_path))
+}
+
+/// Whether the client asked for the markdown source instead of the rendered
+/// HTML, with the `raw` query parameter or by accepting "text/markdown".
+#[cfg(feature = "markdown")]
+fn wants_raw_markdown(req: &Request<Body>) -> bool {
+    if has_query_param(req, "raw") {
+        return true;
+    }
+
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map(|accept| accept.contains("text/markdown"))
+        .unwrap_or(false)
+}
+
+/// Label a markdown file response as "text/markdown", unless the user chose
+/// another type with `--mime`.
+#[cfg(feature = "markdown")]
+fn raw_markdown_response(config: &Config, path: &Path, mut resp: Response<Body>) -> Response<Body> {
+    let mime = super::mime_type::mime_override(config, path)
+        .unwrap_or_else(|| "text/markdown".parse().expect("mime"));
+    let headers = resp.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        super::mime_type::content_type(config, &mime),
+    );
+    headers.append(header::VARY, header::HeaderValue::from_static("accept"));
+    resp
+}
+
+/// Load a markdown file, render to HTML, and return the response.
+#[cfg(feature = "markdown")]
+async fn md_path_to_html(config: &Config, path: &Path) -> Result<Response<Body>> {
+    let cfg = render_markdown(config, path).await?;
+    let html = super::render_html(config, Page::Markdown, cfg)?;
 
     Response::builder()
         .status(StatusCode::OK)
@@ -93,7 +229,323 @@ async fn md_path_to_html(path: &Path) -> Result<Response<Body>> {
         .map_err(Error::from)
 }
 
-fn maybe_convert_mime_type_to_text(req: &Request<Body>, resp: &mut Response<Body>) {
+/// Load a markdown file and render it to HTML, to be put in a page template.
+#[cfg(feature = "markdown")]
+async fn render_markdown(config: &Config, path: &Path) -> Result<HtmlCfg> {
+    // Render Markdown like GitHub
+    let mut options = Options::default();
+    options.extension.autolink = true;
+    options.extension.table = true;
+    options.extension.strikethrough = true;
+    options.extension.tasklist = true;
+    options.render.github_pre_lang = true;
+    options.extension.header_id_prefix = Some("user-content-".to_string());
+    options.extension.math_dollars = config.md_math;
+
+    // Extensions for matching other platforms
+    options.extension.footnotes = config.md_footnotes;
+    options.extension.description_lists = config.md_description_lists;
+    options.extension.superscript = config.md_superscript;
+    options.parse.smart = config.md_smart;
+
+    // Raw HTML is left out, unless it is going to be sanitized
+    options.render.r#unsafe = config.md_sanitize;
+
+    // Mark links to other markdown pages, so they are rendered too
+    if let Some(query) = &config.md_link_query {
+        let query = query.clone();
+        options.extension.link_url_rewriter =
+            Some(Arc::new(move |url: &str| rewrite_md_link(&query, url)));
+    }
+
+    let highlighter = highlighter(config);
+    let mut plugins = Plugins::default();
+    if !config.md_no_highlight {
+        plugins.render.codefence_syntax_highlighter = Some(&*highlighter);
+    }
+
+    let buf = tokio::fs::read(path).await?;
+    let s = String::from_utf8(buf).map_err(|_| Error::MarkdownUtf8)?;
+    let (front_matter, s) = split_front_matter(config, &s);
+
+    // The arena isn't `Send`, so it's dropped before awaiting the navigation
+    let mut html = String::new();
+    {
+        let arena = Arena::new();
+        let root = comrak::parse_document(&arena, s, &options);
+        if config.md_toc {
+            html.push_str(&make_toc(root));
+        }
+        comrak::format_html_with_plugins(root, &options, &mut html, &plugins)
+            .map_err(Error::WriteInMarkdown)?;
+    }
+    if config.md_sanitize {
+        html = sanitize_html(&filter_tags(&html));
+    }
+    if config.md_math {
+        html.push_str(KATEX_ASSETS);
+    }
+    if config.docs {
+        html = super::docs::add_navigation(config, path, html).await;
+    }
+
+    Ok(HtmlCfg {
+        title: front_matter.title.unwrap_or_default(),
+        body: html,
+    })
+}
+
+/// Add the `--md-link-query` to a link if it is to a markdown file on this
+/// site, as in "guide.md#intro" to "guide.md?render=1#intro".
+#[cfg(feature = "markdown")]
+pub fn rewrite_md_link(query: &str, url: &str) -> String {
+    // Links with a scheme, like "https:", or a host go to other sites
+    let first_segment = url.split('/').next().unwrap_or("");
+    if first_segment.contains(':') || url.starts_with("//") {
+        return url.to_string();
+    }
+
+    let (rest, fragment) = match url.find('#') {
+        Some(i) => url.split_at(i),
+        None => (url, ""),
+    };
+    let (path, old_query) = match rest.find('?') {
+        Some(i) => (&rest[..i], &rest[i + 1..]),
+        None => (rest, ""),
+    };
+
+    if !path.to_ascii_lowercase().ends_with(".md") {
+        return url.to_string();
+    }
+
+    if old_query.is_empty() {
+        format!("{}?{}{}", path, query, fragment)
+    } else {
+        format!("{}?{}&{}{}", path, old_query, query, fragment)
+    }
+}
+
+/// Escape the raw HTML tags that GitHub's "tagfilter" extension disallows,
+/// which comrak no longer does itself, so that they show as text.
+///
+/// https://github.github.com/gfm/#disallowed-raw-html-extension-
+#[cfg(feature = "markdown")]
+fn filter_tags(html: &str) -> String {
+    static DISALLOWED: OnceLock<Regex> = OnceLock::new();
+    let disallowed = DISALLOWED.get_or_init(|| {
+        Regex::new(
+            r"(?i)<(/?(?:title|textarea|style|xmp|iframe|noembed|noframes|script|plaintext)(?:[\s/>]|$))",
+        )
+        .expect("tag filter regex")
+    });
+    disallowed.replace_all(html, "&lt;$1").into_owned()
+}
+
+/// Remove scripts and other dangerous HTML from rendered markdown, for
+/// `--md-sanitize`.
+///
+/// Besides what ammonia allows by default, this keeps the elements and
+/// attributes that the markdown options produce, like heading ids, task list
+/// checkboxes and highlighting colors. Ids must look like the ones comrak
+/// makes, so they can't replace the page's globals, and styles can only set
+/// colors and fonts, so they can't cover up the page.
+#[cfg(feature = "markdown")]
+fn sanitize_html(html: &str) -> String {
+    ammonia::Builder::default()
+        .add_tags(&["input", "nav", "section"])
+        .add_tag_attributes("input", &["type", "checked", "disabled"])
+        .add_tag_attributes("pre", &["style"])
+        .add_tag_attributes("span", &["style"])
+        .filter_style_properties(
+            [
+                "color",
+                "background-color",
+                "font-style",
+                "font-weight",
+                "text-decoration",
+            ]
+            .iter()
+            .copied()
+            .collect(),
+        )
+        .add_generic_attributes(&["id", "class", "aria-label"])
+        .add_generic_attribute_prefixes(&["data-"])
+        .attribute_filter(|_, attr, value| {
+            let comrak_id = ["user-content-", "fn-", "fnref-"]
+                .iter()
+                .any(|prefix| value.starts_with(prefix));
+            if attr == "id" && !comrak_id {
+                None
+            } else {
+                Some(value.into())
+            }
+        })
+        .clean(html)
+        .to_string()
+}
+
+/// The KaTeX stylesheet and script, and a script that renders the math that
+/// comrak leaves in `data-math-style` elements.
+///
+/// https://katex.org/docs/browser
+#[cfg(feature = "markdown")]
+static KATEX_ASSETS: &str = r#"
+<link rel="stylesheet" href="https://cdn.jsdelivr.net/npm/katex@0.16.11/dist/katex.min.css">
+<script defer src="https://cdn.jsdelivr.net/npm/katex@0.16.11/dist/katex.min.js"
+  onload="document.querySelectorAll('[data-math-style]').forEach(function (el) {
+    katex.render(el.textContent, el, {
+      displayMode: el.dataset.mathStyle === 'display',
+      throwOnError: false
+    });
+  })"></script>
+"#;
+
+/// The metadata at the top of a markdown document.
+#[cfg(feature = "markdown")]
+#[derive(Default, Deserialize)]
+pub struct FrontMatter {
+    pub title: Option<String>,
+}
+
+/// Split the front matter from the top of a markdown document, and parse it.
+///
+/// Front matter is YAML between "---" lines, or the `--md-front-matter`
+/// delimiter, or TOML between "+++" lines, as used by static site generators
+/// like Jekyll and Hugo. Front matter that doesn't parse is still removed,
+/// with a warning.
+#[cfg(feature = "markdown")]
+pub fn split_front_matter<'a>(config: &Config, s: &'a str) -> (FrontMatter, &'a str) {
+    let yaml_delimiter = config.md_front_matter_delimiter.as_str();
+    let (delimiter, is_toml) = if s.starts_with(yaml_delimiter) {
+        (yaml_delimiter, false)
+    } else if s.starts_with("+++") {
+        ("+++", true)
+    } else {
+        return (FrontMatter::default(), s);
+    };
+
+    // The delimiters must be on lines of their own.
+    let mut lines = s.split_inclusive('\n');
+    let first = lines.next().unwrap_or("");
+    if first.trim_end() != delimiter {
+        return (FrontMatter::default(), s);
+    }
+
+    let start = first.len();
+    let mut end = start;
+    for line in lines {
+        if line.trim_end() == delimiter {
+            let front_matter = &s[start..end];
+            let body = &s[end + line.len()..];
+
+            if front_matter.trim().is_empty() {
+                return (FrontMatter::default(), body);
+            }
+
+            let parsed = if is_toml {
+                toml::from_str(front_matter).map_err(|e| e.to_string())
+            } else {
+                serde_yaml::from_str(front_matter).map_err(|e| e.to_string())
+            };
+            let front_matter = parsed.unwrap_or_else(|e| {
+                warn!("invalid front matter: {}", e);
+                FrontMatter::default()
+            });
+
+            return (front_matter, body);
+        }
+        end += line.len();
+    }
+
+    // Without a closing delimiter it's not front matter.
+    (FrontMatter::default(), s)
+}
+
+/// Make a table of contents for a markdown document, as nested lists of links
+/// to its headings.
+#[cfg(feature = "markdown")]
+fn make_toc<'a>(root: &'a AstNode<'a>) -> String {
+    // The ids are made the same way as when rendering the headings.
+    let mut anchorizer = Anchorizer::new();
+    let mut buf = String::new();
+    let mut levels: Vec<u8> = Vec::new();
+
+    for node in root.descendants() {
+        let level = match node.data().value {
+            NodeValue::Heading(ref heading) => heading.level,
+            _ => continue,
+        };
+        let text = node.collect_text();
+        let id = anchorizer.anchorize(&text);
+
+        while levels.last().is_some_and(|&l| l > level) {
+            buf.push_str("</li>\n</ul>\n");
+            levels.pop();
+        }
+        if levels.last() == Some(&level) {
+            buf.push_str("</li>\n<li>");
+        } else {
+            buf.push_str("<ul>\n<li>");
+            levels.push(level);
+        }
+        buf.push_str(&format!(
+            "<a href=\"#user-content-{}\">{}</a>",
+            id,
+            escape_html(&text)
+        ));
+    }
+
+    if levels.is_empty() {
+        return buf;
+    }
+    for _ in levels {
+        buf.push_str("</li>\n</ul>\n");
+    }
+
+    format!("<nav class=\"toc\">\n{}</nav>\n", buf)
+}
+
+pub fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The syntax highlighting themes built into syntect, for `--md-theme`.
+pub static MD_THEMES: &[&str] = &[
+    "InspiredGitHub",
+    "Solarized (dark)",
+    "Solarized (light)",
+    "base16-eighties.dark",
+    "base16-mocha.dark",
+    "base16-ocean.dark",
+    "base16-ocean.light",
+];
+
+/// The syntax highlighter for fenced code blocks, which colors code with
+/// inline styles from the `--md-theme`.
+///
+/// Loading the syntax definitions is slow, so this is done once for each
+/// theme, which virtual hosts may set differently.
+#[cfg(feature = "markdown")]
+fn highlighter(config: &Config) -> Arc<SyntectAdapter> {
+    static HIGHLIGHTERS: OnceLock<Mutex<HashMap<String, Arc<SyntectAdapter>>>> = OnceLock::new();
+    let mut highlighters = HIGHLIGHTERS
+        .get_or_init(Default::default)
+        .lock()
+        .expect("highlighter lock");
+    highlighters
+        .entry(config.md_theme.clone())
+        .or_insert_with(|| Arc::new(SyntectAdapterBuilder::new().theme(&config.md_theme).build()))
+        .clone()
+}
+
+fn maybe_convert_mime_type_to_text(
+    config: &Config,
+    req: &Request<Body>,
+    resp: &mut Response<Body>,
+) {
     let path = req.uri().path();
     let file_name = path.rsplit('/').next();
     if let Some(file_name) = file_name {
@@ -111,9 +563,7 @@ fn maybe_convert_mime_type_to_text(req: &Request<Body>, resp: &mut Response<Body
         }
 
         if do_convert {
-            use http::header::HeaderValue;
-            let val =
-                HeaderValue::from_str(mime::TEXT_PLAIN.as_ref()).expect("mime is valid header");
+            let val = super::mime_type::content_type(config, &mime::TEXT_PLAIN);
             resp.headers_mut().insert(header::CONTENT_TYPE, val);
         }
     }
@@ -158,18 +608,40 @@ static TEXT_FILES: &[&'static str] = &[
     "rust-toolchain",
 ];
 
-/// Try to treat the path as a directory and list the contents as HTML.
-async fn maybe_list_dir(root_dir: &Path, path: &Path) -> Result<Option<Response<Body>>> {
-    let meta = tokio::fs::metadata(path).await?;
+/// Try to treat the path as a directory and list the contents.
+#[cfg(feature = "dir-listing")]
+async fn maybe_list_dir(
+    config: &Config,
+    req: &Request<Body>,
+    path: &Path,
+) -> Result<Option<Response<Body>>> {
+    let meta = match tokio::fs::metadata(path).await {
+        Ok(meta) => meta,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
     if meta.is_dir() {
-        Ok(Some(list_dir(&root_dir, path).await?))
+        Ok(Some(list_dir(config, req, path).await?))
     } else {
         Ok(None)
     }
 }
 
-/// List the contents of a directory as HTML.
-async fn list_dir(root_dir: &Path, path: &Path) -> Result<Response<Body>> {
+/// Directories aren't listed without the "dir-listing" feature.
+#[cfg(not(feature = "dir-listing"))]
+async fn maybe_list_dir(
+    _config: &Config,
+    _req: &Request<Body>,
+    _path: &Path,
+) -> Result<Option<Response<Body>>> {
+    Ok(None)
+}
+
+/// List the contents of a directory as HTML, or as plain text if the client
+/// asks for it.
+#[cfg(feature = "dir-listing")]
+async fn list_dir(config: &Config, req: &Request<Body>, path: &Path) -> Result<Response<Body>> {
+    let root_dir = &config.root_dir;
     let up_dir = path.join("..");
     let path = path.to_owned();
     let dents = tokio::fs::read_dir(path).await?;
@@ -181,25 +653,125 @@ async fn list_dir(root_dir: &Path, path: &Path) -> Result<Response<Body>> {
         }
     });
     let paths = dents.map(|dent| DirEntry::path(&dent));
+    let paths = paths.filter(|path| future::ready(!super::hide::is_hidden(config, path)));
     let mut paths: Vec<_> = paths.collect().await;
     paths.sort();
-    let paths = Some(up_dir).into_iter().chain(paths);
-    let paths: Vec<_> = paths.collect();
-    let html = make_dir_list_body(&root_dir, &paths)?;
-    let resp = super::html_str_to_response(html, StatusCode::OK)?;
+
+    let mut resp = if wants_text_list(req) {
+        let text = make_dir_list_text(root_dir, &paths)?;
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_LENGTH, text.len() as u64)
+            .header(header::CONTENT_TYPE, mime::TEXT_PLAIN_UTF_8.as_ref())
+            .body(Body::from(text))?
+    } else {
+        // Show the README above the list, like GitHub does.
+        #[cfg(feature = "markdown")]
+        let readme = match find_readme(config, &paths) {
+            Some(readme) => Some(render_markdown(config, readme).await?),
+            None => None,
+        };
+        #[cfg(not(feature = "markdown"))]
+        let readme = None;
+        let paths = Some(up_dir).into_iter().chain(paths);
+        let paths: Vec<_> = paths.collect();
+        let html = make_dir_list_body(config, &paths, readme)?;
+        super::html_str_to_response(html, StatusCode::OK)?
+    };
+
+    // The format can depend on the Accept header, so caches must too.
+    resp.headers_mut()
+        .append(header::VARY, header::HeaderValue::from_static("accept"));
+
     Ok(resp)
 }
 
-fn make_dir_list_body(root_dir: &Path, paths: &[PathBuf]) -> Result<String> {
+/// Find the README.md among the files of a directory, to render with the
+/// listing, when the other extensions are on.
+#[cfg(all(feature = "dir-listing", feature = "markdown"))]
+fn find_readme<'a>(config: &Config, paths: &'a [PathBuf]) -> Option<&'a Path> {
+    if !config.use_extensions {
+        return None;
+    }
+
+    paths
+        .iter()
+        .find(|path| {
+            let is_readme = path
+                .file_name()
+                .and_then(OsStr::to_str)
+                .is_some_and(|name| name.eq_ignore_ascii_case("README.md"));
+            is_readme && path.is_file()
+        })
+        .map(PathBuf::as_path)
+}
+
+/// Whether the client asked for a plain text directory listing, with either
+/// `?format=txt` or an Accept header that wants text/plain but not HTML.
+#[cfg(feature = "dir-listing")]
+fn wants_text_list(req: &Request<Body>) -> bool {
+    if query_param(req, "format").as_deref() == Some("txt") {
+        return true;
+    }
+
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    accept.contains("text/plain") && !accept.contains("text/html")
+}
+
+/// List paths one per line, relative to the root and with a trailing "/" for
+/// directories, for use by scripts.
+#[cfg(feature = "dir-listing")]
+fn make_dir_list_text(root_dir: &Path, paths: &[PathBuf]) -> Result<String> {
     let mut buf = String::new();
 
-    writeln!(buf, "<div>").map_err(Error::WriteInDirList)?;
+    for path in paths {
+        let rel_path = path
+            .strip_prefix(root_dir)
+            .map_err(Error::StripPrefixInDirList)?;
+        if let Some(rel_path) = rel_path.to_str() {
+            let slash = if path.is_dir() { "/" } else { "" };
+            writeln!(buf, "/{}{}", rel_path, slash).map_err(Error::WriteInDirList)?;
+        } else {
+            warn!("non-unicode path: {}", rel_path.to_string_lossy());
+        }
+    }
+
+    Ok(buf)
+}
+
+/// The characters to %-encode in file paths used in URLs.
+///
+/// https://url.spec.whatwg.org/#fragment-percent-encode-set
+pub const PATH_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'<')
+    .add(b'>')
+    .add(b'`')
+    .add(b'#')
+    .add(b'?')
+    .add(b'{')
+    .add(b'}');
+
+#[cfg(feature = "dir-listing")]
+fn make_dir_list_body(
+    config: &Config,
+    paths: &[PathBuf],
+    readme: Option<HtmlCfg>,
+) -> Result<String> {
+    let mut buf = String::new();
+
+    writeln!(buf, "<div class=\"listing\">").map_err(Error::WriteInDirList)?;
 
     let dot_dot = OsStr::new("..");
 
     for path in paths {
         let full_url = path
-            .strip_prefix(root_dir)
+            .strip_prefix(&config.root_dir)
             .map_err(Error::StripPrefixInDirList)?;
         let maybe_dot_dot = || {
             if path.ends_with("..") {
@@ -212,15 +784,11 @@ fn make_dir_list_body(root_dir: &Path, paths: &[PathBuf]) -> Result<String> {
             if let Some(file_name) = file_name.to_str() {
                 if let Some(full_url) = full_url.to_str() {
                     // %-encode filenames
-                    // https://url.spec.whatwg.org/#fragment-percent-encode-set
-                    const FRAGMENT_SET: &AsciiSet =
-                        &CONTROLS.add(b' ').add(b'"').add(b'<').add(b'>').add(b'`');
-                    const PATH_SET: &AsciiSet =
-                        &FRAGMENT_SET.add(b'#').add(b'?').add(b'{').add(b'}');
-                    let full_url = utf8_percent_encode(full_url, &PATH_SET);
+                    let full_url = utf8_percent_encode(full_url, PATH_SET);
 
                     // TODO: Make this a relative URL
-                    writeln!(buf, "<div><a href='/{}'>{}</a></div>", full_url, file_name)
+                    let full_url = super::base_path::url(config, &format!("/{}", full_url));
+                    writeln!(buf, "<div><a href='{}'>{}</a></div>", full_url, file_name)
                         .map_err(Error::WriteInDirList)?;
                 } else {
                     warn!("non-unicode url: {}", full_url.to_string_lossy());
@@ -235,12 +803,38 @@ fn make_dir_list_body(root_dir: &Path, paths: &[PathBuf]) -> Result<String> {
 
     writeln!(buf, "</div>").map_err(Error::WriteInDirList)?;
 
-    let cfg = HtmlCfg {
-        title: String::new(),
-        body: buf,
+    if config.use_extensions {
+        writeln!(
+            buf,
+            "<p><a href='?tar.gz' download>Download as .tar.gz</a></p>"
+        )
+        .map_err(Error::WriteInDirList)?;
+    }
+
+    if super::upload::is_enabled(config) {
+        buf.push_str(super::upload::UPLOAD_FORM);
+    }
+    if super::file_manager::is_enabled(config) {
+        buf.push_str(&super::file_manager::script(config));
+    }
+
+    let mut cfg = match readme {
+        Some(readme) => HtmlCfg {
+            title: readme.title,
+            body: readme.body + &buf,
+        },
+        None => HtmlCfg {
+            title: String::new(),
+            body: buf,
+        },
     };
 
-    Ok(super::render_html(cfg)?)
+    // The search box goes at the top, above any README.
+    if config.use_extensions {
+        cfg.body.insert_str(0, super::search::SEARCH_FORM);
+    }
+
+    Ok(super::render_html(config, Page::Listing, cfg)?)
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -258,14 +852,21 @@ pub enum Error {
     Io(io::Error),
 
     // custom "semantic" error types
+    #[cfg(feature = "markdown")]
     #[display(fmt = "markdown is not UTF-8")]
     MarkdownUtf8,
 
+    #[cfg(feature = "dir-listing")]
     #[display(fmt = "failed to strip prefix in directory listing")]
     StripPrefixInDirList(std::path::StripPrefixError),
 
+    #[cfg(feature = "dir-listing")]
     #[display(fmt = "formatting error while creating directory listing")]
     WriteInDirList(std::fmt::Error),
+
+    #[cfg(feature = "markdown")]
+    #[display(fmt = "formatting error while rendering markdown")]
+    WriteInMarkdown(std::fmt::Error),
 }
 
 impl StdError for Error {
@@ -276,9 +877,14 @@ impl StdError for Error {
             Engine(e) => Some(e),
             Io(e) => Some(e),
             Http(e) => Some(e),
+            #[cfg(feature = "markdown")]
             MarkdownUtf8 => None,
+            #[cfg(feature = "dir-listing")]
             StripPrefixInDirList(e) => Some(e),
+            #[cfg(feature = "dir-listing")]
             WriteInDirList(e) => Some(e),
+            #[cfg(feature = "markdown")]
+            WriteInMarkdown(e) => Some(e),
         }
     }
 }
@@ -0,0 +1,309 @@
+//! Sending requests to FastCGI servers, like php-fpm, with
+//! `--fastcgi EXT=ADDR`.
+//!
+//! Requests for files with the extension are described to the FastCGI server
+//! with the same variables as CGI scripts get, and its output is parsed the
+//! same way. One connection is made per request.
+//!
+//! https://fast-cgi.github.io/spec
+
+use super::cgi::{self, Script};
+use super::{Config, ConnInfo, Error, Result};
+use http::{Request, Response, StatusCode};
+use hyper::body::Sender;
+use hyper::Body;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, warn};
+
+const VERSION: u8 = 1;
+
+// Record types.
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+
+/// The role of a server that answers HTTP requests.
+const RESPONDER: u16 = 1;
+
+/// There is only one request per connection.
+const REQUEST_ID: u16 = 1;
+
+/// The most content a record can have.
+const MAX_CONTENT_LEN: usize = 0xffff;
+
+/// A file extension and the FastCGI server for its files, from the
+/// `--fastcgi EXT=ADDR` option.
+#[derive(Clone, Debug)]
+pub struct FastCgiBackend {
+    /// The extension, without a ".".
+    ext: String,
+    addr: SocketAddr,
+}
+
+impl FromStr for FastCgiBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<FastCgiBackend, String> {
+        let mut parts = s.splitn(2, '=');
+        let ext = parts.next().unwrap_or("").trim_start_matches('.');
+        let addr = parts.next().unwrap_or("");
+
+        if ext.is_empty() || addr.is_empty() {
+            return Err(format!("expected EXT=ADDR, found '{}'", s));
+        }
+
+        let addr = addr
+            .parse()
+            .map_err(|e| format!("invalid address '{}': {}", addr, e))?;
+
+        Ok(FastCgiBackend {
+            ext: ext.to_string(),
+            addr,
+        })
+    }
+}
+
+/// Find the FastCGI server and script for a request, if the request is for a
+/// file with one of the `--fastcgi` extensions.
+///
+/// A directory's script is its index file, like "index.php". Any of the URL
+/// path after the script's name is its PATH_INFO, as in
+/// "/index.php/2019/hello".
+pub fn find_script<'a>(
+    config: &'a Config,
+    req: &Request<Body>,
+) -> Option<(&'a FastCgiBackend, Script)> {
+    if config.fastcgi.is_empty() {
+        return None;
+    }
+
+    // Map the path as WebDAV does, so that "/../" and "//" can't name a
+    // script outside the root directory.
+    let path = super::webdav::local_path(config, req.uri()).ok()??;
+    if !path.starts_with(&config.root_dir) {
+        return None;
+    }
+
+    let backend_for = |p: &Path| {
+        let ext = p.extension()?.to_str()?;
+        config
+            .fastcgi
+            .iter()
+            .find(|backend| backend.ext.eq_ignore_ascii_case(ext))
+    };
+
+    let found = if path.is_dir() {
+        config.fastcgi.iter().find_map(|backend| {
+            let index = path.join(format!("index.{}", backend.ext));
+            if index.starts_with(&config.root_dir) && index.is_file() {
+                Some((backend, index.clone(), index))
+            } else {
+                None
+            }
+        })
+    } else {
+        path.ancestors()
+            .take_while(|p| p.starts_with(&config.root_dir) && *p != config.root_dir)
+            .filter(|p| p.is_file())
+            .find_map(|p| Some((backend_for(p)?, p.to_owned(), path.clone())))
+    };
+
+    let (backend, script, request_path) = found?;
+    if super::hide::is_hidden(config, &script) {
+        return None;
+    }
+
+    Some((backend, Script::new(config, &script, &request_path)))
+}
+
+/// Send a request to a FastCGI server, and stream its output as the
+/// response, or return a 502 Bad Gateway error page if the server can't be
+/// reached.
+pub async fn serve(
+    config: &Config,
+    conn: ConnInfo,
+    backend: &FastCgiBackend,
+    script: Script,
+    req: Request<Body>,
+) -> Result<Response<Body>> {
+    debug!(
+        "sending {} to FastCGI server {}",
+        script.path.display(),
+        backend.addr
+    );
+
+    let mut stream = match TcpStream::connect(&backend.addr).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!(
+                "unable to connect to FastCGI server {}: {}",
+                backend.addr, e
+            );
+            return super::make_error_response_from_code(config, StatusCode::BAD_GATEWAY);
+        }
+    };
+
+    let mut params = Vec::new();
+    for (name, value) in cgi::meta_variables(config, conn, &req, &script) {
+        write_name_value(&mut params, &name, &value);
+    }
+    // PHP refuses to run without this, to stop it being run as a plain CGI
+    // script by accident.
+    write_name_value(&mut params, "REDIRECT_STATUS", "200");
+
+    let mut begin = Vec::new();
+    begin.extend_from_slice(&RESPONDER.to_be_bytes());
+    // Flags of 0 ask the server to close the connection when it is done.
+    begin.extend_from_slice(&[0; 6]);
+
+    let mut head = record(BEGIN_REQUEST, &begin);
+    for chunk in params.chunks(MAX_CONTENT_LEN) {
+        head.extend(record(PARAMS, chunk));
+    }
+    head.extend(record(PARAMS, &[]));
+    stream.write_all(&head).await.map_err(Error::FastCgi)?;
+
+    // The server reads the whole request body before it answers.
+    let mut body = req.into_body();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        for chunk in chunk.chunks(MAX_CONTENT_LEN) {
+            stream
+                .write_all(&record(STDIN, chunk))
+                .await
+                .map_err(Error::FastCgi)?;
+        }
+    }
+    stream
+        .write_all(&record(STDIN, &[]))
+        .await
+        .map_err(Error::FastCgi)?;
+
+    // Read output until the end of the headers.
+    let mut output = Vec::new();
+    let (headers_end, body_start) = loop {
+        if let Some(end) = find_headers_end(&output) {
+            break end;
+        }
+        if output.len() > cgi::MAX_HEADERS_LEN {
+            return Err(cgi::missing_end_of_headers());
+        }
+        match read_output(&mut stream).await.map_err(Error::FastCgi)? {
+            Some(data) => output.extend(data),
+            None => return Err(cgi::missing_end_of_headers()),
+        }
+    };
+
+    let headers = String::from_utf8_lossy(&output[..headers_end]);
+    let mut resp = cgi::response_from_headers(&headers)?;
+
+    let (mut sender, body) = Body::channel();
+    let rest = output.split_off(body_start);
+    tokio::spawn(async move {
+        if !rest.is_empty() && sender.send_data(rest.into()).await.is_err() {
+            return;
+        }
+        stream_output(stream, sender).await
+    });
+    *resp.body_mut() = body;
+
+    Ok(resp)
+}
+
+/// Send the rest of the server's output as the response body.
+async fn stream_output(mut stream: TcpStream, mut sender: Sender) {
+    loop {
+        match read_output(&mut stream).await {
+            Ok(Some(data)) => {
+                if sender.send_data(data.into()).await.is_err() {
+                    // The client went away.
+                    return;
+                }
+            }
+            Ok(None) => return,
+            Err(e) => {
+                warn!("FastCGI error: {}", e);
+                sender.abort();
+                return;
+            }
+        }
+    }
+}
+
+/// Read records up to the next output, logging errors written by the server.
+///
+/// Returns `None` when the request has ended.
+async fn read_output<R>(stream: &mut R) -> io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let mut header = [0; 8];
+        stream.read_exact(&mut header).await?;
+        let kind = header[1];
+        let content_len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let padding_len = header[6] as usize;
+
+        let mut content = vec![0; content_len + padding_len];
+        stream.read_exact(&mut content).await?;
+        content.truncate(content_len);
+
+        match kind {
+            STDOUT if !content.is_empty() => return Ok(Some(content)),
+            STDERR if !content.is_empty() => {
+                warn!("FastCGI: {}", String::from_utf8_lossy(&content).trim_end())
+            }
+            END_REQUEST => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
+/// Find the blank line after the headers in the server's output, returning
+/// the end of the headers and the start of the body.
+fn find_headers_end(output: &[u8]) -> Option<(usize, usize)> {
+    let crlf = output.windows(4).position(|w| w == b"\r\n\r\n");
+    let lf = output.windows(2).position(|w| w == b"\n\n");
+    match (crlf, lf) {
+        (Some(crlf), Some(lf)) if lf < crlf => Some((lf, lf + 2)),
+        (Some(crlf), _) => Some((crlf, crlf + 4)),
+        (None, Some(lf)) => Some((lf, lf + 2)),
+        (None, None) => None,
+    }
+}
+
+/// Make a record, padded to a multiple of 8 bytes.
+fn record(kind: u8, content: &[u8]) -> Vec<u8> {
+    let padding_len = (8 - content.len() % 8) % 8;
+
+    let mut record = vec![VERSION, kind];
+    record.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    record.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    record.push(padding_len as u8);
+    record.push(0);
+    record.extend_from_slice(content);
+    record.resize(record.len() + padding_len, 0);
+    record
+}
+
+/// Encode a parameter. Lengths under 128 take one byte, and others four,
+/// with the high bit set.
+fn write_name_value(buf: &mut Vec<u8>, name: &str, value: &str) {
+    for len in &[name.len(), value.len()] {
+        if *len < 0x80 {
+            buf.push(*len as u8);
+        } else {
+            buf.extend_from_slice(&(*len as u32 | 0x8000_0000).to_be_bytes());
+        }
+    }
+    buf.extend_from_slice(name.as_bytes());
+    buf.extend_from_slice(value.as_bytes());
+}
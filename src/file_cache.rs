@@ -0,0 +1,157 @@
+//! Keeping small files in memory, with `--cache-size`, so that the hot
+//! assets of a site are served without reading them for every request.
+//!
+//! Files are looked up by path, and are only used while their modification
+//! time and size are the same as when they were read, so they are still
+//! checked with a `stat` each time, unless `--metadata-cache-ttl` saves it.
+//! When the cache is full the least recently used files are dropped.
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tracing::trace;
+
+/// The share of the cache a file may take, so that a big file can't push out
+/// many small ones.
+const MAX_FILE_FRACTION: u64 = 16;
+
+/// A number of bytes, written as e.g. "64MB", "512K" or "1048576". The units
+/// are powers of 1024.
+#[derive(Clone, Copy, Debug)]
+pub struct Size(u64);
+
+impl FromStr for Size {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Size, String> {
+        let err = || format!("expected a size like \"64MB\", found '{}'", s);
+
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let shift = match unit.trim().to_ascii_uppercase().as_str() {
+            "" | "B" => 0,
+            "K" | "KB" | "KIB" => 10,
+            "M" | "MB" | "MIB" => 20,
+            "G" | "GB" | "GIB" => 30,
+            _ => return Err(err()),
+        };
+        let number: u64 = number.parse().map_err(|_| err())?;
+
+        number.checked_mul(1 << shift).map(Size).ok_or_else(err)
+    }
+}
+
+/// The cached files, shared by every connection.
+#[derive(Clone)]
+pub struct FileCache {
+    capacity: u64,
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    files: HashMap<PathBuf, Entry>,
+    len: u64,
+    /// Counts lookups, to tell which file was used least recently.
+    clock: u64,
+}
+
+struct Entry {
+    modified: SystemTime,
+    data: Bytes,
+    last_used: u64,
+}
+
+impl FileCache {
+    /// Create the cache, if `--cache-size` is given.
+    pub fn new(size: Option<Size>) -> Option<FileCache> {
+        let Size(capacity) = size?;
+        Some(FileCache {
+            capacity,
+            inner: Arc::new(Mutex::new(Inner {
+                files: HashMap::new(),
+                len: 0,
+                clock: 0,
+            })),
+        })
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Get a file's metadata and contents, from the cache if they're there
+    /// and current, or else by reading it and caching it.
+    ///
+    /// Returns `None` for files too big for the cache, which should be
+    /// streamed instead.
+    pub async fn get(&self, path: &Path) -> io::Result<Option<(Metadata, Bytes)>> {
+        let meta = super::meta_cache::metadata(path)?;
+        if !meta.is_file() || meta.len() > self.capacity / MAX_FILE_FRACTION {
+            return Ok(None);
+        }
+        let modified = meta.modified()?;
+
+        if let Some(data) = self.lookup(path, modified, meta.len()) {
+            trace!("serving {} from the file cache", path.display());
+            return Ok(Some((meta, data)));
+        }
+
+        let data = Bytes::from(tokio::fs::read(path).await?);
+        // The file changed after the metadata was read, so the length is wrong.
+        if data.len() as u64 != meta.len() {
+            return Ok(None);
+        }
+        self.insert(path, modified, data.clone());
+
+        Ok(Some((meta, data)))
+    }
+
+    fn lookup(&self, path: &Path, modified: SystemTime, len: u64) -> Option<Bytes> {
+        let mut inner = self.inner.lock().expect("file cache lock");
+        inner.clock += 1;
+        let now = inner.clock;
+
+        let entry = inner.files.get_mut(path)?;
+        if entry.modified != modified || entry.data.len() as u64 != len {
+            return None;
+        }
+        entry.last_used = now;
+        Some(entry.data.clone())
+    }
+
+    fn insert(&self, path: &Path, modified: SystemTime, data: Bytes) {
+        let mut inner = self.inner.lock().expect("file cache lock");
+        let len = data.len() as u64;
+
+        if let Some(old) = inner.files.remove(path) {
+            inner.len -= old.data.len() as u64;
+        }
+        while inner.len + len > self.capacity {
+            let oldest = inner
+                .files
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone());
+            match oldest.and_then(|oldest| inner.files.remove(&oldest)) {
+                Some(old) => inner.len -= old.data.len() as u64,
+                None => break,
+            }
+        }
+
+        inner.len += len;
+        let last_used = inner.clock;
+        inner.files.insert(
+            path.to_owned(),
+            Entry {
+                modified,
+                data,
+                last_used,
+            },
+        );
+    }
+}
@@ -0,0 +1,237 @@
+//! Managing files from the browser, with `--webdav` and `-x`.
+//!
+//! Directory listings get buttons to rename, move and delete files and to
+//! create folders, which call small JSON endpoints under "/_files/":
+//!
+//! - `POST /_files/mkdir` with `{"path": "/docs/new"}`
+//! - `POST /_files/move` with `{"from": "/docs/a.md", "to": "/b.md"}`
+//! - `POST /_files/delete` with `{"path": "/docs/b.md"}`
+//!
+//! Paths are URL paths from the root directory, without %-encoding. Each
+//! endpoint answers with `{}` on success, or `{"error": "..."}` and an error
+//! status. Renaming is moving within a directory, and nothing is replaced.
+//! Requests must be sent as `application/json`, and not from another site.
+//! A user's site from `--userdir` has its own endpoints, under
+//! "/~USER/_files/", which work on the user's directory.
+
+use super::ext::json_response;
+use super::{Config, Error, Result};
+use http::{header, Method, Request, Response, StatusCode};
+use hyper::Body;
+use serde::Deserialize;
+use serde_json::json;
+use std::io;
+use std::path::Path;
+use tokio_executor::blocking;
+use tracing::debug;
+
+/// The path that the endpoints are under.
+pub static FILES_PATH: &str = "/_files/";
+
+/// The most bytes a request to an endpoint may have.
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+/// The script that adds the buttons to directory listings.
+#[cfg(feature = "dir-listing")]
+static FILE_MANAGER_SCRIPT: &str = r#"
+<script>
+(function () {
+  var listing = document.querySelector(".listing");
+  if (!listing) return;
+
+  function call(action, body) {
+    fetch("/_files/" + action, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify(body)
+    }).then(function (resp) {
+      return resp.json().then(function (json) {
+        if (!resp.ok) throw new Error(json.error || resp.statusText);
+      });
+    }).then(function () {
+      location.reload();
+    }, function (e) {
+      alert(e.message);
+    });
+  }
+
+  function button(label, onclick) {
+    var b = document.createElement("button");
+    b.type = "button";
+    b.textContent = label;
+    b.onclick = onclick;
+    return b;
+  }
+
+  listing.querySelectorAll("a").forEach(function (a) {
+    if (a.textContent === "..") return;
+    var path = decodeURIComponent(a.getAttribute("href"));
+    a.parentNode.append(" ", button("Rename", function () {
+      var to = prompt("Rename or move to", path);
+      if (to && to !== path) call("move", { from: path, to: to });
+    }), " ", button("Delete", function () {
+      if (confirm("Delete " + path + "?")) call("delete", { path: path });
+    }));
+  });
+
+  var dir = decodeURIComponent(location.pathname).replace(/\/?$/, "/");
+  listing.before(button("New folder", function () {
+    var name = prompt("Folder name");
+    if (name) call("mkdir", { path: dir + name });
+  }));
+})();
+</script>
+"#;
+
+/// The script for a listing, calling the endpoints under the site's base
+/// path, which in a user's directory is "/~USER".
+#[cfg(feature = "dir-listing")]
+pub fn script(config: &Config) -> String {
+    FILE_MANAGER_SCRIPT.replace(FILES_PATH, &super::base_path::url(config, FILES_PATH))
+}
+
+#[derive(Deserialize)]
+struct PathRequest {
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct MoveRequest {
+    from: String,
+    to: String,
+}
+
+/// Whether the file manager is on, which needs both writable mode and the
+/// developer extensions that list directories.
+pub fn is_enabled(config: &Config) -> bool {
+    super::upload::is_enabled(config)
+}
+
+/// Whether a request is for one of the endpoints.
+pub fn is_file_manager_request(config: &Config, req: &Request<Body>) -> bool {
+    is_enabled(config) && req.uri().path().starts_with(FILES_PATH)
+}
+
+/// Handle a request to one of the endpoints.
+pub async fn serve(config: &Config, req: Request<Body>) -> Result<Response<Body>> {
+    if req.method() != Method::POST {
+        return json_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            json!({ "error": "use POST" }),
+        );
+    }
+
+    // A page on another site can post a form, or text without a preflight,
+    // but not JSON.
+    if super::upload::is_cross_site(&req) {
+        debug!("refusing file manager request from another site");
+        return json_response(
+            StatusCode::FORBIDDEN,
+            json!({ "error": "not allowed from another site" }),
+        );
+    }
+    if !is_json(&req) {
+        return json_response(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            json!({ "error": "expected application/json" }),
+        );
+    }
+
+    let action = req.uri().path()[FILES_PATH.len()..].to_string();
+    let body = match read_body(req.into_body()).await? {
+        Some(body) => body,
+        None => return error_response(StatusCode::PAYLOAD_TOO_LARGE),
+    };
+
+    // Deleting and moving directories can take a while.
+    let config = config.clone();
+    blocking::run(move || run_action(&config, &action, &body)).await
+}
+
+/// Run the operation an endpoint is for.
+fn run_action(config: &Config, action: &str, body: &[u8]) -> Result<Response<Body>> {
+    let result = match action {
+        "mkdir" => match serde_json::from_slice::<PathRequest>(body) {
+            Ok(r) => with_path(config, &r.path, super::webdav::make_dir),
+            Err(e) => return bad_request(e),
+        },
+        "delete" => match serde_json::from_slice::<PathRequest>(body) {
+            Ok(r) => with_path(config, &r.path, |path| super::webdav::delete(config, path)),
+            Err(e) => return bad_request(e),
+        },
+        "move" => match serde_json::from_slice::<MoveRequest>(body) {
+            Ok(r) => with_path(config, &r.from, |from| {
+                with_path(config, &r.to, |to| {
+                    super::webdav::move_path(config, from, to)
+                })
+            }),
+            Err(e) => return bad_request(e),
+        },
+        _ => return error_response(StatusCode::NOT_FOUND),
+    };
+
+    match result {
+        Ok(status) if status.is_success() => json_response(StatusCode::OK, json!({})),
+        Ok(status) => error_response(status),
+        Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+            error_response(StatusCode::NOT_FOUND)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether the request body is JSON, by its Content-Type.
+fn is_json(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<mime::Mime>().ok())
+        .map(|mime| mime.type_() == mime::APPLICATION && mime.subtype() == mime::JSON)
+        .unwrap_or(false)
+}
+
+/// Read a small request body.
+async fn read_body(mut body: Body) -> Result<Option<Vec<u8>>> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.next().await {
+        buf.extend_from_slice(&chunk?);
+        if buf.len() > MAX_REQUEST_LEN {
+            return Ok(None);
+        }
+    }
+    Ok(Some(buf))
+}
+
+/// Run an operation on the local path for a URL path, or answer 403
+/// Forbidden for hidden paths and paths outside the root directory.
+fn with_path<F>(config: &Config, url_path: &str, f: F) -> Result<StatusCode>
+where
+    F: FnOnce(&Path) -> Result<StatusCode>,
+{
+    let local_path = super::base_path::strip_path(config, url_path)
+        .and_then(|url_path| super::ext::url_path_to_local(config, url_path));
+    match local_path {
+        Some(path) if !super::hide::is_hidden(config, &path) => f(&path),
+        _ => Ok(StatusCode::FORBIDDEN),
+    }
+}
+
+fn bad_request(e: serde_json::Error) -> Result<Response<Body>> {
+    debug!("bad file manager request: {}", e);
+    json_response(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() }))
+}
+
+/// Answer with an error status, turning the statuses of the WebDAV
+/// operations into messages for people.
+fn error_response(status: StatusCode) -> Result<Response<Body>> {
+    let (status, msg) = match status {
+        StatusCode::NOT_FOUND => (status, "not found"),
+        StatusCode::FORBIDDEN => (status, "not allowed"),
+        StatusCode::METHOD_NOT_ALLOWED | StatusCode::PRECONDITION_FAILED => {
+            (StatusCode::CONFLICT, "already exists")
+        }
+        StatusCode::CONFLICT => (status, "the folder doesn't exist"),
+        _ => (status, status.canonical_reason().unwrap_or("error")),
+    };
+    json_response(status, json!({ "error": msg }))
+}
@@ -0,0 +1,188 @@
+//! Rendering files with external commands, with `--handler EXT=COMMAND`, like
+//! turning Graphviz files into SVG with "dot=dot -Tsvg".
+//!
+//! The command is run by the shell in the file's directory, with the file on
+//! its standard input and its path as `$1`, and what it writes to its
+//! standard output is the response. The response's type is the one given to
+//! the extension with `--mime`, or HTML.
+//!
+//! Only files under the root directory are run through commands, and a
+//! command is killed if it runs for longer than a minute or writes more
+//! than 16 MiB.
+
+use super::{Config, Error, Result};
+use futures::future;
+use http::header::{self, HeaderValue};
+use http::{Request, Response, StatusCode};
+use hyper::Body;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::process::Command;
+use tokio::timer::Timeout;
+use tokio_executor::blocking;
+use tracing::{debug, warn};
+
+/// The most a command may write to its standard output.
+const MAX_OUTPUT_LEN: usize = 16 * 1024 * 1024;
+
+/// The most of a command's standard error that is kept for the log.
+const MAX_ERROR_LEN: usize = 64 * 1024;
+
+/// How long a command may run.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A file extension and the command for its files, from the
+/// `--handler EXT=COMMAND` option.
+#[derive(Clone, Debug)]
+pub struct Handler {
+    /// The extension, without a ".".
+    ext: String,
+    command: String,
+}
+
+impl FromStr for Handler {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Handler, String> {
+        let mut parts = s.splitn(2, '=');
+        let ext = parts.next().unwrap_or("").trim().trim_start_matches('.');
+        let command = parts.next().unwrap_or("").trim();
+
+        if ext.is_empty() || command.is_empty() {
+            return Err(format!("expected EXT=COMMAND, found '{}'", s));
+        }
+
+        Ok(Handler {
+            ext: ext.to_string(),
+            command: command.to_string(),
+        })
+    }
+}
+
+/// Find the handler and file for a request, if it is for a file with one of
+/// the `--handler` extensions. The last handler given for an extension wins.
+pub fn find<'a>(config: &'a Config, req: &Request<Body>) -> Option<(&'a Handler, PathBuf)> {
+    if config.handlers.is_empty() {
+        return None;
+    }
+
+    // Mapped like WebDAV paths, so that `..` can't reach files outside the
+    // root directory.
+    let mut path = super::webdav::local_path(config, req.uri()).ok()??;
+    if !path.starts_with(&config.root_dir) {
+        return None;
+    }
+    if super::meta_cache::is_dir(&path) {
+        path.push("index.html");
+    }
+    let ext = path.extension()?.to_str()?;
+    let handler = config
+        .handlers
+        .iter()
+        .rev()
+        .find(|h| h.ext.eq_ignore_ascii_case(ext))?;
+
+    if !super::meta_cache::is_file(&path) || super::hide::is_hidden(config, &path) {
+        return None;
+    }
+    Some((handler, path))
+}
+
+/// Run a handler's command on a file, and respond with its output.
+pub async fn serve(config: &Config, handler: &Handler, path: &Path) -> Result<Response<Body>> {
+    debug!("running '{}' for {}", handler.command, path.display());
+
+    let file = {
+        let path = path.to_owned();
+        blocking::run(move || File::open(path)).await?
+    };
+    let mut cmd = shell_command(&handler.command);
+    cmd.arg(path)
+        .stdin(file)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(dir) = path.parent() {
+        cmd.current_dir(dir);
+    }
+    let mut child = cmd.spawn().map_err(Error::HandlerSpawn)?;
+    let stdout = child.stdout().take().expect("piped stdout");
+    let stderr = child.stderr().take().expect("piped stderr");
+
+    // The child is killed when it's dropped, if it times out or writes too
+    // much.
+    let run = async {
+        let (stdout, stderr) = future::join(
+            read_limited(stdout, MAX_OUTPUT_LEN),
+            read_limited(stderr, MAX_ERROR_LEN),
+        )
+        .await;
+        let (stdout, stderr) = (stdout?, stderr?);
+        if stdout.len() > MAX_OUTPUT_LEN {
+            let msg = format!("wrote more than {} bytes", MAX_OUTPUT_LEN);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        Ok((child.await?, stdout, stderr))
+    };
+    let (status, stdout, stderr) = Timeout::new(run, TIMEOUT)
+        .await
+        .map_err(|_| {
+            let msg = format!("ran for longer than {} seconds", TIMEOUT.as_secs());
+            io::Error::new(io::ErrorKind::TimedOut, msg)
+        })
+        .and_then(|result| result)
+        .map_err(Error::HandlerOutput)?;
+
+    if !status.success() {
+        warn!(
+            "'{}' exited with {} for {}: {}",
+            handler.command,
+            status,
+            path.display(),
+            String::from_utf8_lossy(&stderr).trim_end()
+        );
+        return Err(Error::HandlerFailed(status));
+    }
+
+    let content_type = match super::mime_type::mime_override(config, path) {
+        Some(mime) => super::mime_type::content_type(config, &mime),
+        None => HeaderValue::from_static("text/html; charset=utf-8"),
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_LENGTH, stdout.len() as u64)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from(stdout))
+        .map_err(Error::from)
+}
+
+/// Read up to one byte more than `limit` from a pipe, so that going over the
+/// limit can be told apart from reaching it.
+async fn read_limited<R: AsyncRead + Unpin>(pipe: R, limit: usize) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    pipe.take(limit as u64 + 1).read_to_end(&mut buf).await?;
+    Ok(buf)
+}
+
+/// A command that runs a command line with the shell, which is given the
+/// next argument as `$1`.
+#[cfg(unix)]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command).arg("sh");
+    cmd
+}
+
+/// A command that runs a command line with `cmd`, which has no `$1`, so the
+/// path is added to the end of the command line.
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
@@ -0,0 +1,94 @@
+//! Custom response headers from the `--header` option, and the
+//! `--secure-headers` bundle.
+
+use globset::{GlobBuilder, GlobMatcher};
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use std::str::FromStr;
+
+/// A header to add to responses, optionally only for paths matching a glob.
+///
+/// Written on the command line as "Name: Value", or "GLOB Name: Value", e.g.
+/// "/sw/** Service-Worker-Allowed: /".
+#[derive(Clone, Debug)]
+pub struct CustomHeader {
+    glob: Option<GlobMatcher>,
+    name: HeaderName,
+    value: HeaderValue,
+}
+
+impl FromStr for CustomHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CustomHeader, String> {
+        let s = s.trim();
+
+        // Header names can't contain "/", so a leading "/" starts a glob.
+        let (glob, header) = if s.starts_with('/') {
+            let mut parts = s.splitn(2, char::is_whitespace);
+            let glob = parts.next().unwrap_or("");
+            let header = parts.next().unwrap_or("").trim_start();
+            (Some(path_glob(glob)?), header)
+        } else {
+            (None, s)
+        };
+
+        let mut parts = header.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = parts
+            .next()
+            .ok_or_else(|| format!("expected \"Name: Value\", found '{}'", s))?
+            .trim();
+
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("invalid header name '{}'", name))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| format!("invalid header value '{}'", value))?;
+
+        Ok(CustomHeader { glob, name, value })
+    }
+}
+
+/// Compile a glob for matching URL paths, where `*` doesn't match across `/`
+/// but `**` does.
+pub fn path_glob(glob: &str) -> Result<GlobMatcher, String> {
+    GlobBuilder::new(glob)
+        .literal_separator(true)
+        .build()
+        .map(|g| g.compile_matcher())
+        .map_err(|e| e.to_string())
+}
+
+/// Add the custom headers that apply to a request path, replacing any headers
+/// of the same name.
+pub fn add_custom_headers(custom: &[CustomHeader], path: &str, headers: &mut HeaderMap) {
+    for header in custom {
+        let applies = header.glob.as_ref().is_none_or(|g| g.is_match(path));
+        if applies {
+            headers.insert(header.name.clone(), header.value.clone());
+        }
+    }
+}
+
+/// The hardening headers added by `--secure-headers`.
+#[rustfmt::skip]
+static SECURE_HEADERS: &[(&str, &str)] = &[
+    // Don't let browsers guess a different content type than the one sent.
+    ("x-content-type-options", "nosniff"),
+    // Don't allow other sites to frame the pages, to prevent clickjacking.
+    ("x-frame-options", "DENY"),
+    // Don't leak local URLs to other sites through the Referer header.
+    ("referrer-policy", "no-referrer"),
+    // Isolate the pages from cross-origin windows that open them.
+    ("cross-origin-opener-policy", "same-origin"),
+];
+
+/// Add the `--secure-headers` bundle, leaving alone any of those headers that
+/// the response already has.
+pub fn add_secure_headers(headers: &mut HeaderMap) {
+    for (name, value) in SECURE_HEADERS {
+        let name = HeaderName::from_static(name);
+        if !headers.contains_key(&name) {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Hiding files from clients, with `--hide-dotfiles` and `--ignore`.
+//!
+//! Hidden files are left out of directory listings, and requests for them are
+//! answered as if they don't exist. The temporary files of uploads in progress
+//! are always hidden.
+
+use super::Config;
+use globset::GlobMatcher;
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+/// A glob from `--ignore` for files to hide.
+///
+/// Like in `.gitignore`, a glob without a "/", e.g. "*.key", matches a file
+/// name in any directory, and a glob with one, e.g. "target/**", matches paths
+/// from the root directory.
+#[derive(Clone, Debug)]
+pub struct IgnoreGlob {
+    matcher: GlobMatcher,
+    file_name_only: bool,
+}
+
+impl FromStr for IgnoreGlob {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IgnoreGlob, String> {
+        let file_name_only = !s.contains('/');
+        let glob = s.trim_start_matches('/');
+        let matcher = super::headers::path_glob(glob)?;
+
+        Ok(IgnoreGlob {
+            matcher,
+            file_name_only,
+        })
+    }
+}
+
+impl IgnoreGlob {
+    fn is_match(&self, rel_path: &Path) -> bool {
+        if self.file_name_only {
+            rel_path
+                .file_name()
+                .is_some_and(|name| self.matcher.is_match(name))
+        } else {
+            self.matcher.is_match(rel_path)
+        }
+    }
+}
+
+/// Whether a path under the root directory is hidden from clients.
+///
+/// Everything inside a hidden directory is hidden too.
+pub fn is_hidden(config: &Config, path: &Path) -> bool {
+    let rel_path = path.strip_prefix(&config.root_dir).unwrap_or(path);
+
+    // Uploads in progress are never shown.
+    let is_upload_temp = rel_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(super::webdav::UploadTemp::is_temp_name);
+    if is_upload_temp {
+        return true;
+    }
+
+    if config.hide_dotfiles {
+        let dotfile = rel_path.components().any(|c| match c {
+            Component::Normal(name) => name.to_string_lossy().starts_with('.'),
+            _ => false,
+        });
+        if dotfile {
+            return true;
+        }
+    }
+
+    rel_path
+        .ancestors()
+        .filter(|p| !p.as_os_str().is_empty())
+        .any(|p| config.ignore.iter().any(|glob| glob.is_match(p)))
+}
@@ -0,0 +1,39 @@
+//! Hooks, run before and after a request is served, for changing how the
+//! server answers without changing the server.
+//!
+//! The plugins are the built-in hook run before serving, and the developer
+//! extensions the one run after. Library users can add their own to a
+//! `FileService`, which run after the built-in ones.
+
+use super::{Config, Result};
+use futures::future::{self, BoxFuture, FutureExt};
+use hyper::{Body, Request, Response};
+
+/// Something run before and after requests are served.
+///
+/// Hooks before serving are run once the request has been rewritten, just
+/// before it is proxied or resolved to a file, and the first one to answer
+/// it wins. Hooks after serving are given the result of serving a file, in
+/// order, and may change or replace it.
+pub trait Hook: Send + Sync {
+    /// Look at a request before it's served, and answer it by returning a
+    /// response. By default nothing is done.
+    fn before<'a>(
+        &'a self,
+        _config: &'a Config,
+        _req: &'a mut Request<Body>,
+    ) -> BoxFuture<'a, Result<Option<Response<Body>>>> {
+        future::ok(None).boxed()
+    }
+
+    /// Change or replace the result of serving a request for a file. By
+    /// default it's returned as it is.
+    fn after<'a>(
+        &'a self,
+        _config: &'a Config,
+        _req: &'a Request<Body>,
+        resp: Result<Response<Body>>,
+    ) -> BoxFuture<'a, Result<Response<Body>>> {
+        future::ready(resp).boxed()
+    }
+}
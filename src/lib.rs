@@ -0,0 +1,2193 @@
+//! A simple HTTP server, for learning and local development.
+//!
+//! The server can also be used as a library: make a `Config` with
+//! `Config::parse_args`, and serve requests with a `FileService`, which is a
+//! hyper `Service`.
+
+#[macro_use]
+extern crate derive_more;
+
+use access_log::AccessLog;
+use bytes::BytesMut;
+use conn::{Conn, ConnInfo};
+use futures::future;
+use futures::stream::StreamExt;
+use futures::FutureExt;
+use http::header::{HeaderMap, HeaderValue};
+use http::status::StatusCode;
+use http::Uri;
+use hyper::server::accept;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use std::error::Error as StdError;
+use std::fs::Metadata;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Instant, UNIX_EPOCH};
+use structopt::StructOpt;
+use tokio::codec::{BytesCodec, FramedRead};
+use tokio::fs::File;
+use tokio::runtime::Runtime;
+use tokio::timer::Timeout;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, field, info, info_span, trace, warn, Instrument, Span};
+
+// Developer extensions. These are contained in their own module so that the
+// principle HTTP server behavior is not obscured.
+mod ext;
+
+// Compression of response bodies.
+mod compress;
+
+// Accepting plain TCP and TLS connections.
+mod conn;
+
+// TLS configuration and self-signed certificates.
+mod tls;
+
+// Custom response headers.
+mod headers;
+
+// Hiding dotfiles and ignored files.
+mod hide;
+
+// Choosing the Content-Type of files.
+mod mime_type;
+
+// Logging requests to a file.
+mod access_log;
+
+// Log output.
+mod logging;
+
+// Watching for changes, for live reload.
+mod watch;
+
+// Navigation between markdown pages, for serving docs.
+#[cfg(feature = "markdown")]
+mod docs;
+
+// Forwarding requests to upstream servers.
+mod proxy;
+
+// Caching proxied responses.
+mod proxy_cache;
+
+// Running CGI scripts.
+mod cgi;
+
+// Sending requests to FastCGI servers.
+mod fastcgi;
+
+// Rendering files with external commands.
+mod handler;
+
+// WebDAV, for mounting the root directory as a network drive.
+mod webdav;
+
+// Uploading files from the browser.
+mod upload;
+
+// Managing files from the browser.
+mod file_manager;
+
+// Downloading directories as tarballs.
+mod archive;
+
+// Searching for files by name.
+mod search;
+
+// The directory tree and file metadata as JSON.
+mod tree;
+
+// Artificial latency for testing clients.
+mod delay;
+
+// Opening the browser on startup.
+mod browser;
+
+// Printing a QR code of the site's URL.
+mod qr;
+
+// Advertising the server on the local network.
+mod mdns;
+
+// Options from environment variables.
+mod env;
+
+// Checking the configuration without serving.
+mod check;
+
+// Serving a root directory per host name.
+mod vhost;
+
+// Falling back to other root directories for missing files.
+mod overlay;
+
+// Rewriting request URLs.
+mod rewrite;
+
+// Redirects from a file in the root directory.
+mod redirects;
+
+// Serving the site under a URL prefix.
+mod base_path;
+
+// Serving users' own directories.
+mod userdir;
+
+// Limiting the rate of requests from each client.
+mod rate_limit;
+
+// Finding clients' addresses behind reverse proxies.
+mod trusted_proxy;
+
+// Reading clients' addresses from TCP load balancers.
+mod proxy_protocol;
+
+// Refusing requests for unknown host names.
+mod allowed_hosts;
+
+// Handling requests with WebAssembly plugins.
+mod plugin;
+
+// Hooks run before and after serving requests.
+mod hook;
+pub use hook::Hook;
+
+// Keeping small files in memory.
+mod file_cache;
+
+// Caching file metadata for a moment.
+mod meta_cache;
+
+// Serving files from memory maps.
+mod mmap;
+
+/// Basic error reporting, including the "cause chain". This is used both by the
+/// top-level error reporting and to report internal server errors.
+pub fn log_error_chain(mut e: &dyn StdError) {
+    error!("error: {}", e);
+    while let Some(source) = e.source() {
+        error!("caused by: {}", source);
+        e = source;
+    }
+}
+
+// The subcommands, which all take the configuration options. `serve` is the
+// default, so that `basic-http-server -x site` works as it always has. (These
+// are `//` comments because structopt would show doc comments as the about.)
+#[derive(StructOpt)]
+#[structopt(
+    about = "A basic HTTP file server",
+    after_help = "Without a subcommand, the arguments are given to `serve`. \
+                  Every option can also be set with an environment variable, e.g. \
+                  BASIC_HTTP_SERVER_MAX_AGE=60 for --max-age=60. Flags are set with \"true\", \
+                  and options that can be repeated take one value per line, which are added to \
+                  those on the command line. Otherwise, the command line wins.",
+    raw(global_settings = "&[structopt::clap::AppSettings::AllArgsOverrideSelf]")
+)]
+enum Command {
+    /// Serve the root directory. This is the default.
+    #[structopt(name = "serve")]
+    Serve(Config),
+
+    /// Check the configuration, such as that the root directory, TLS
+    /// certificates and templates can be loaded, print a report, and exit,
+    /// failing if there are problems.
+    #[structopt(name = "check")]
+    Check(Config),
+
+    /// Print the configuration from the command line and environment.
+    #[structopt(name = "print-config")]
+    PrintConfig(Config),
+
+    /// Write a self-signed certificate and key to --tls-cert and --tls-key
+    /// (default "cert.pem" and "key.pem").
+    #[structopt(name = "gen-cert")]
+    GenCert(Config),
+
+    /// Print the completions of the subcommands and options for a shell, e.g.
+    /// `basic-http-server completions bash > /etc/bash_completion.d/basic-http-server`.
+    #[structopt(name = "completions")]
+    Completions {
+        #[structopt(
+            name = "SHELL",
+            raw(possible_values = "&structopt::clap::Shell::variants()")
+        )]
+        shell: structopt::clap::Shell,
+    },
+}
+
+/// The names of the subcommands, to tell them from a root directory.
+const COMMANDS: &[&str] = &["serve", "check", "print-config", "gen-cert"];
+
+/// The configuration object, parsed from command line options.
+#[derive(Clone, Debug, StructOpt)]
+// The markdown options are still accepted in builds without markdown.
+#[cfg_attr(not(feature = "markdown"), allow(dead_code))]
+pub struct Config {
+    /// The IP:PORT combination, or a HOST:PORT, e.g. "localhost:4000", to
+    /// listen on all of the host's addresses.
+    #[structopt(
+        name = "ADDR",
+        short = "a",
+        long = "addr",
+        parse(try_from_str),
+        default_value = "127.0.0.1:4000"
+    )]
+    addr: conn::ListenAddr,
+
+    /// When the port of --addr is in use, try up to this many higher ports.
+    /// The port that is used is logged, as it is for port 0, which picks any
+    /// free port.
+    #[structopt(long = "port-retry", name = "RETRIES")]
+    port_retry: Option<u16>,
+
+    /// The root directory for serving files.
+    #[structopt(
+        name = "ROOT",
+        parse(from_os_str),
+        default_value = ".",
+        env = "BASIC_HTTP_SERVER_ROOT"
+    )]
+    root_dir: PathBuf,
+
+    /// Host names to allow requests for, besides IP addresses, "localhost",
+    /// and the names of --addr, --vhost and --mdns, which protects against
+    /// DNS rebinding. A name starting with "." allows its subdomains, and "*"
+    /// allows any host. May be repeated, or separated by commas.
+    #[structopt(
+        long = "allowed-hosts",
+        name = "HOSTS",
+        raw(number_of_values = "1", use_delimiter = "true")
+    )]
+    allowed_hosts: Vec<String>,
+
+    /// A host name to serve from its own root directory, as "HOST=DIR", e.g.
+    /// "docs.local=./docs". May be repeated. Requests for other hosts are
+    /// served from ROOT.
+    #[structopt(long = "vhost", name = "HOST=DIR", raw(number_of_values = "1"))]
+    vhost: Vec<vhost::VirtualHost>,
+
+    /// A directory to serve files from when they aren't in ROOT, e.g. a
+    /// theme under a project's overrides. May be repeated, and the
+    /// directories are tried in order.
+    #[structopt(
+        long = "fallback-root",
+        name = "FALLBACK_DIR",
+        parse(from_os_str),
+        raw(number_of_values = "1")
+    )]
+    fallback_roots: Vec<PathBuf>,
+
+    /// Serve requests whose path matches a regex PATTERN as if they were for
+    /// REPLACEMENT, which may use the pattern's groups, as in
+    /// `--rewrite '^/v1/(.*)$' '/api/$1'`. May be repeated, and the first
+    /// matching rule is used.
+    #[structopt(
+        long = "rewrite",
+        name = "REWRITE",
+        raw(number_of_values = "2", value_names = r#"&["PATTERN", "REPLACEMENT"]"#)
+    )]
+    rewrite: Vec<String>,
+
+    /// Serve the site under a URL prefix, e.g. "/PROJECT" to preview a GitHub
+    /// Pages project site. Requests outside it get 404 Not Found.
+    #[structopt(
+        long = "base-path",
+        name = "BASE_PATH",
+        parse(try_from_str = "base_path::parse")
+    )]
+    base_path: Option<String>,
+
+    /// Serve "/~USER/" from each user's directory: USER_DIR in their home
+    /// directory if it's relative, e.g. "public_html", USER_DIR with "*"
+    /// replaced by the user name, e.g. "/srv/www/*/public", or else
+    /// USER_DIR/USER.
+    #[structopt(long = "userdir", name = "USER_DIR")]
+    userdir: Option<String>,
+
+    /// A WebAssembly module to give requests to before serving them, which
+    /// may answer them itself. May be repeated, and the plugins are tried in
+    /// order. Needs the "wasm" cargo feature.
+    #[structopt(
+        long = "plugin",
+        name = "WASM_FILE",
+        parse(from_os_str),
+        raw(number_of_values = "1")
+    )]
+    plugin: Vec<PathBuf>,
+
+    /// Send the redirects listed in the root directory's `_redirects` file,
+    /// in the Netlify format, or its `redirects.toml` file.
+    #[structopt(long = "redirects")]
+    redirects: bool,
+
+    /// The most connections that may be open at once, counting TLS handshakes
+    /// in progress. Connections beyond it are closed as soon as they are
+    /// accepted, after a 503 Service Unavailable response on plain HTTP.
+    #[structopt(long = "max-connections", name = "CONNECTIONS")]
+    max_connections: Option<usize>,
+
+    /// The most requests a client address may make, e.g. "10r/s" or
+    /// "600r/m". Requests beyond it get 429 Too Many Requests.
+    #[structopt(long = "rate-limit", name = "RATE")]
+    rate_limit: Option<rate_limit::Rate>,
+
+    /// The requests a client may make at once before --rate-limit applies.
+    /// Defaults to a second's worth.
+    #[structopt(long = "burst", name = "BURST")]
+    burst: Option<u32>,
+
+    /// An address or CIDR range, e.g. "10.0.0.0/8", of a reverse proxy whose
+    /// `Forwarded` or `X-Forwarded-For` headers give the client's address.
+    /// May be repeated.
+    #[structopt(long = "trusted-proxy", name = "CIDR", raw(number_of_values = "1"))]
+    trusted_proxy: Vec<trusted_proxy::Cidr>,
+
+    /// Read the client's address from the PROXY protocol header that a TCP
+    /// load balancer, such as HAProxy, sends first on each connection.
+    /// Connections without one are closed.
+    #[structopt(long = "proxy-protocol")]
+    proxy_protocol: bool,
+
+    /// The seconds a client has to finish the TLS handshake, and to send a
+    /// request's headers, after which the connection is closed. 0 turns it
+    /// off.
+    #[structopt(long = "header-timeout", name = "HEADER_SECONDS", default_value = "30")]
+    header_timeout: u64,
+
+    /// The seconds a connection may go without sending or receiving anything
+    /// between requests, or while a response is sent, before it is closed. 0
+    /// turns it off.
+    #[structopt(
+        long = "keep-alive-timeout",
+        name = "IDLE_SECONDS",
+        default_value = "75"
+    )]
+    keep_alive_timeout: u64,
+
+    /// Close every connection after one request, for debugging clients on
+    /// fresh connections.
+    #[structopt(long = "no-keep-alive")]
+    no_keep_alive: bool,
+
+    /// Close connections after this many requests.
+    #[structopt(long = "max-requests-per-connection", name = "REQUESTS")]
+    max_requests_per_connection: Option<usize>,
+
+    /// The most seconds a request may take to handle, including reading its
+    /// body, before it is answered with 408 Request Timeout.
+    #[structopt(long = "request-timeout", name = "REQUEST_SECONDS")]
+    request_timeout: Option<u64>,
+
+    /// Enable developer extensions.
+    #[structopt(short = "x", long = "extensions")]
+    use_extensions: bool,
+
+    /// The Cache-Control header sent with every response.
+    #[structopt(
+        long = "cache-control",
+        parse(try_from_str),
+        default_value = "no-cache"
+    )]
+    cache_control: HeaderValue,
+
+    /// Send `Cache-Control: max-age=SECONDS`, overriding --cache-control.
+    #[structopt(long = "max-age", name = "SECONDS")]
+    max_age: Option<u64>,
+
+    /// Keep small files in memory, in a cache of this size, e.g. "64MB", so
+    /// that they aren't read for every request. Files are still checked for
+    /// changes, and those over a sixteenth of the size aren't cached.
+    #[structopt(long = "cache-size", name = "CACHE_SIZE")]
+    cache_size: Option<file_cache::Size>,
+
+    /// Remember whether paths are files or directories for this many
+    /// milliseconds, saving the file system checks made for each request.
+    /// With --watch, changes are seen right away anyway.
+    #[structopt(long = "metadata-cache-ttl", name = "TTL_MS")]
+    metadata_cache_ttl: Option<u64>,
+
+    /// Send files by copying them from memory maps instead of reading them,
+    /// a chunk at a time, which is faster for large files that are in the
+    /// page cache. Only on Linux and Windows; files elsewhere are read
+    /// instead.
+    #[structopt(long = "mmap")]
+    mmap: bool,
+
+    /// Don't compress responses, even if the client accepts it.
+    #[structopt(long = "no-compress")]
+    no_compress: bool,
+
+    /// Don't compress responses smaller than this many bytes.
+    #[structopt(long = "compress-min-size", name = "BYTES", default_value = "1024")]
+    compress_min_size: u64,
+
+    /// Only compress responses of this MIME type, e.g. "text/*". May be repeated.
+    #[structopt(long = "compress-type", name = "MIME", raw(number_of_values = "1"))]
+    compress_types: Vec<String>,
+
+    /// Never compress responses of this MIME type, in addition to the built-in
+    /// list of already-compressed media types. May be repeated.
+    #[structopt(
+        long = "no-compress-type",
+        name = "NO_MIME",
+        raw(number_of_values = "1")
+    )]
+    no_compress_types: Vec<String>,
+
+    /// A PEM-encoded TLS certificate chain. Serves HTTPS when given along with
+    /// --tls-key.
+    #[structopt(long = "tls-cert", name = "CERT", parse(from_os_str))]
+    tls_cert: Option<PathBuf>,
+
+    /// The PEM-encoded private key for --tls-cert.
+    #[structopt(long = "tls-key", name = "KEY", parse(from_os_str))]
+    tls_key: Option<PathBuf>,
+
+    /// A certificate to use for one host name, chosen by SNI, as
+    /// "HOST=CERT,KEY". May be repeated. Other host names use --tls-cert or
+    /// --tls-auto.
+    #[structopt(long = "tls-sni", name = "HOST=CERT,KEY", raw(number_of_values = "1"))]
+    tls_sni: Vec<tls::SniCert>,
+
+    /// Serve HTTPS with an ephemeral self-signed certificate.
+    #[structopt(long = "tls-auto")]
+    tls_auto: bool,
+
+    /// Serve HTTPS on this IP:PORT, and plain HTTP on --addr.
+    #[structopt(long = "tls-addr", name = "TLS_ADDR", parse(try_from_str))]
+    tls_addr: Option<SocketAddr>,
+
+    /// Redirect all plain HTTP requests to HTTPS on --tls-addr.
+    #[structopt(long = "https-redirect")]
+    https_redirect: bool,
+
+    /// Allow cross-origin requests from ORIGIN, e.g. --cors=https://example.com
+    /// (default "*"), and answer CORS preflight OPTIONS requests.
+    #[structopt(long = "cors", name = "ORIGIN", raw(require_equals = "true"))]
+    cors: Option<Option<HeaderValue>>,
+
+    /// Add a header to every response, as "Name: Value". Prefix with a path
+    /// glob and a space, as in "/api/** Name: Value", to add it only to
+    /// matching paths. May be repeated.
+    #[structopt(long = "header", name = "HEADER", raw(number_of_values = "1"))]
+    headers: Vec<headers::CustomHeader>,
+
+    /// Add a set of hardening headers to every response, such as
+    /// `X-Content-Type-Options: nosniff` and `X-Frame-Options: DENY`.
+    #[structopt(long = "secure-headers")]
+    secure_headers: bool,
+
+    /// Serve "page.html" for requests for "page" that doesn't exist.
+    #[structopt(long = "clean-urls")]
+    clean_urls: bool,
+
+    /// Always list directories, even those containing an "index.html".
+    #[structopt(long = "list-dirs")]
+    list_dirs: bool,
+
+    /// Hide files and directories whose names start with ".", both from
+    /// directory listings and from direct requests.
+    #[structopt(long = "hide-dotfiles")]
+    hide_dotfiles: bool,
+
+    /// Hide files matching a glob, e.g. "*.key" or "node_modules/**", both
+    /// from directory listings and from direct requests. May be repeated.
+    #[structopt(long = "ignore", name = "GLOB", raw(number_of_values = "1"))]
+    ignore: Vec<hide::IgnoreGlob>,
+
+    /// Serve files with an extension as a MIME type, e.g.
+    /// "wasm=application/wasm". May be repeated.
+    #[structopt(long = "mime", name = "EXT=TYPE", raw(number_of_values = "1"))]
+    mime_types: Vec<mime_type::MimeOverride>,
+
+    /// Load MIME types for file extensions from a file in the mime.types
+    /// format used by Apache and nginx, e.g. "/etc/mime.types".
+    #[structopt(
+        long = "mime-types",
+        name = "FILE",
+        parse(try_from_os_str = "mime_type::MimeTypesFile::load")
+    )]
+    mime_types_file: Option<mime_type::MimeTypesFile>,
+
+    /// Only use the MIME types from --mime and --mime-types, serving other
+    /// files as "application/octet-stream".
+    #[structopt(long = "mime-types-only")]
+    mime_types_only: bool,
+
+    /// The charset to add to the Content-Type of text files.
+    #[structopt(
+        long = "charset",
+        name = "CHARSET",
+        default_value = "utf-8",
+        parse(try_from_str = "mime_type::parse_charset")
+    )]
+    charset: String,
+
+    /// Don't add a charset to the Content-Type of text files.
+    #[structopt(long = "no-charset")]
+    no_charset: bool,
+
+    /// A directory of handlebars templates, "error.html", "listing.html" and
+    /// "markdown.html", to use instead of the built-in page template.
+    #[structopt(long = "template-dir", name = "DIR", parse(from_os_str))]
+    template_dir: Option<PathBuf>,
+
+    /// How to treat a trailing "/" on directory URLs: "redirect" to add it,
+    /// "serve" without redirecting, or "strip" to redirect without it.
+    #[structopt(
+        long = "trailing-slash",
+        name = "POLICY",
+        default_value = "redirect",
+        raw(possible_values = r#"&["redirect", "serve", "strip"]"#)
+    )]
+    trailing_slash: TrailingSlash,
+
+    /// The status code of trailing slash redirects, e.g. 301 or 308.
+    #[structopt(
+        long = "dir-redirect-status",
+        name = "STATUS",
+        default_value = "302",
+        parse(try_from_str = "parse_redirect_status")
+    )]
+    dir_redirect_status: StatusCode,
+
+    /// Send the Strict-Transport-Security header when serving HTTPS, with an
+    /// optional max-age, e.g. --hsts=3600 (default one year).
+    #[structopt(long = "hsts", name = "HSTS_SECONDS", raw(require_equals = "true"))]
+    hsts: Option<Option<u64>>,
+
+    /// Add includeSubDomains to the Strict-Transport-Security header.
+    #[structopt(long = "hsts-include-subdomains")]
+    hsts_include_subdomains: bool,
+
+    /// Log requests to a file, in the Combined Log Format.
+    #[structopt(long = "access-log", name = "LOG_FILE", parse(from_os_str))]
+    access_log: Option<PathBuf>,
+
+    /// Rotate the access log when it would grow larger than this.
+    #[structopt(long = "access-log-max-size", name = "LOG_BYTES")]
+    access_log_max_size: Option<u64>,
+
+    /// Rotate the access log every day.
+    #[structopt(long = "access-log-daily")]
+    access_log_daily: bool,
+
+    /// The number of rotated access logs to keep.
+    #[structopt(long = "access-log-keep", name = "COUNT", default_value = "5")]
+    access_log_keep: u32,
+
+    /// The theme for highlighting code in markdown.
+    #[structopt(
+        long = "md-theme",
+        name = "THEME",
+        default_value = "InspiredGitHub",
+        raw(possible_values = "ext::MD_THEMES")
+    )]
+    md_theme: String,
+
+    /// Don't highlight code in markdown.
+    #[structopt(long = "md-no-highlight")]
+    md_no_highlight: bool,
+
+    /// Add a table of contents to markdown pages, linking to their headings.
+    #[structopt(long = "md-toc")]
+    md_toc: bool,
+
+    /// Render $inline$ and $$display$$ math in markdown with KaTeX, which is
+    /// loaded from a CDN.
+    #[structopt(long = "md-math")]
+    md_math: bool,
+
+    /// Render footnotes in markdown.
+    #[structopt(long = "md-footnotes")]
+    md_footnotes: bool,
+
+    /// Render description lists in markdown.
+    #[structopt(long = "md-description-lists")]
+    md_description_lists: bool,
+
+    /// Render ^superscript^ in markdown.
+    #[structopt(long = "md-superscript")]
+    md_superscript: bool,
+
+    /// Use smart punctuation in markdown, turning straight quotes into curly
+    /// quotes, "--" into en dashes and "---" into em dashes.
+    #[structopt(long = "md-smart")]
+    md_smart: bool,
+
+    /// Allow raw HTML in markdown, sanitized with ammonia to remove scripts
+    /// and other dangerous content, for serving untrusted markdown.
+    #[structopt(long = "md-sanitize")]
+    md_sanitize: bool,
+
+    /// Append a query, e.g. "render=1", to links from markdown pages to
+    /// other markdown files on the site.
+    #[structopt(long = "md-link-query", name = "QUERY")]
+    md_link_query: Option<String>,
+
+    /// The delimiter of YAML front matter in markdown.
+    #[structopt(
+        long = "md-front-matter-delimiter",
+        name = "DELIMITER",
+        default_value = "---"
+    )]
+    md_front_matter_delimiter: String,
+
+    /// Forward requests under a path prefix to an upstream server, e.g.
+    /// "/api=http://localhost:8080". The whole request path is sent upstream.
+    /// Several comma-separated URLs take turns, skipping any that are down.
+    /// May be repeated.
+    #[structopt(
+        long = "proxy",
+        name = "PREFIX=URL[,URL...]",
+        raw(number_of_values = "1")
+    )]
+    proxy: Vec<proxy::ProxyRoute>,
+
+    /// Add the standard `Forwarded` header to proxied requests, as well as
+    /// the `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`
+    /// headers.
+    #[structopt(long = "proxy-forwarded")]
+    proxy_forwarded: bool,
+
+    /// Cache proxied GET responses in a directory, following their
+    /// Cache-Control, Expires, ETag and Last-Modified headers. Cached
+    /// responses are served even when the upstream server is down.
+    #[structopt(long = "proxy-cache", name = "CACHE_DIR", parse(from_os_str))]
+    proxy_cache: Option<PathBuf>,
+
+    /// A directory under the root whose files are run as CGI scripts, e.g.
+    /// "cgi-bin". Any of the URL path after the script's name is given to it
+    /// as PATH_INFO.
+    #[structopt(long = "cgi-dir", name = "CGI_DIR", parse(from_os_str))]
+    cgi_dir: Option<PathBuf>,
+
+    /// Send requests for files with an extension to a FastCGI server, e.g.
+    /// ".php=127.0.0.1:9000" for php-fpm. May be repeated.
+    #[structopt(long = "fastcgi", name = "EXT=ADDR", raw(number_of_values = "1"))]
+    fastcgi: Vec<fastcgi::FastCgiBackend>,
+
+    /// Render files with an extension by running a command, e.g.
+    /// "dot=dot -Tsvg", and sending what it prints. The shell runs it in the
+    /// file's directory, with the file on standard input and its path as $1.
+    /// The output is HTML unless --mime gives the extension a type. May be
+    /// repeated.
+    #[structopt(long = "handler", name = "EXT=COMMAND", raw(number_of_values = "1"))]
+    handlers: Vec<handler::Handler>,
+
+    /// Allow WebDAV clients to list and change files, so the root directory
+    /// can be mounted as a network drive. With `-x`, directory listings also
+    /// get a form for uploading files, and buttons to rename, move and delete
+    /// them.
+    #[structopt(long = "webdav")]
+    webdav: bool,
+
+    /// The largest request body, in bytes, that may be uploaded with WebDAV
+    /// or the upload form. Bigger uploads are refused with 413 Payload Too
+    /// Large, and any partly written file is removed.
+    #[structopt(long = "max-upload-size", name = "UPLOAD_BYTES")]
+    max_upload_size: Option<u64>,
+
+    /// Wait this many milliseconds before responding, for testing clients
+    /// against slow servers. Prefix with a path glob, as in "/api/**=2000",
+    /// to delay only matching paths; the last matching delay is used. May be
+    /// repeated.
+    #[structopt(long = "delay", name = "[GLOB=]MS", raw(number_of_values = "1"))]
+    delay: Vec<delay::Delay>,
+
+    /// Serve markdown as a docs site, with a sidebar of the markdown files
+    /// under the root directory and links to the previous and next pages.
+    /// Implies `-x`.
+    #[structopt(long = "docs")]
+    docs: bool,
+
+    /// Open the site in the browser once the server is listening, at a path,
+    /// e.g. --open=/docs/ (default "/").
+    #[structopt(long = "open", name = "URL_PATH", raw(require_equals = "true"))]
+    open: Option<Option<String>>,
+
+    /// Print a QR code of the site's URL on the local network, for opening it
+    /// on a phone. Needs an --addr that isn't loopback, like 0.0.0.0:4000.
+    #[structopt(long = "qr")]
+    qr: bool,
+
+    /// Advertise the server on the local network with mDNS, so other
+    /// machines can reach it as "NAME.local" and find it in service browsers.
+    /// Needs an --addr that isn't loopback, like 0.0.0.0:4000.
+    #[structopt(long = "mdns", name = "MDNS_NAME")]
+    mdns: Option<String>,
+
+    /// Watch the root directory for changes, and send them as Server-Sent
+    /// Events from "/_events", with a script added to HTML pages that reloads
+    /// them.
+    #[structopt(long = "watch")]
+    watch: bool,
+}
+
+impl Config {
+    /// Parse the options of `serve` from arguments starting with a program
+    /// name, like `["basic-http-server", "-x", "site"]`, for making a
+    /// configuration when using the server as a library.
+    pub fn parse_args<I>(args: I) -> std::result::Result<Config, structopt::clap::Error>
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString> + Clone,
+    {
+        Config::from_iter_safe(args)
+    }
+}
+
+/// Run the command given on the command line, which is usually to serve the
+/// root directory until the process is killed.
+pub fn run() -> Result<()> {
+    // Initialize logging, and log the "info" level for this crate only, unless
+    // the environment contains `RUST_LOG`.
+    logging::init();
+
+    // Create the configuration from the command line arguments. It
+    // includes the IP address and port to listen on and the path to use
+    // as the HTTP server's root directory.
+    let command = Command::from_iter(env::args(COMMANDS));
+    let config = match &command {
+        Command::Serve(config)
+        | Command::Check(config)
+        | Command::PrintConfig(config)
+        | Command::GenCert(config) => config.clone(),
+        Command::Completions { shell } => {
+            let bin_name = env!("CARGO_PKG_NAME");
+            Command::clap().gen_completions_to(bin_name, *shell, &mut io::stdout());
+            return Ok(());
+        }
+    };
+
+    let config = finish_config(config)?;
+
+    // The other subcommands don't run the server.
+    match command {
+        Command::Serve(_) | Command::Completions { .. } => {}
+        Command::Check(_) => return check::run(&config),
+        Command::PrintConfig(_) => {
+            println!("{:#?}", config);
+            return Ok(());
+        }
+        Command::GenCert(_) => return tls::gen_cert_files(&config),
+    }
+
+    // Load the TLS certificate, or generate one, if serving HTTPS.
+    let tls_config = tls::server_config(&config)?;
+
+    if config.tls_addr.is_some() && tls_config.is_none() {
+        return Err(Error::TlsAddrWithoutTls);
+    }
+    if config.https_redirect && config.tls_addr.is_none() {
+        return Err(Error::HttpsRedirectWithoutTlsAddr);
+    }
+
+    // Without --tls-addr, the one listener serves HTTPS if TLS is enabled.
+    let scheme = if tls_config.is_some() && config.tls_addr.is_none() {
+        "https"
+    } else {
+        "http"
+    };
+
+    // Display the configuration to be helpful
+    info!("basic-http-server {}", env!("CARGO_PKG_VERSION"));
+    info!("addr: {}://{}", scheme, config.addr);
+    if let Some(tls_addr) = config.tls_addr {
+        info!("tls addr: https://{}", tls_addr);
+        info!("https redirect: {}", config.https_redirect);
+    }
+    info!("root dir: {}", config.root_dir.display());
+    for vhost in &config.vhost {
+        info!("vhost: {} -> {}", vhost.host(), vhost.root_dir().display());
+    }
+    for root in &config.fallback_roots {
+        info!("fallback root: {}", root.display());
+    }
+    info!("extensions: {}", config.use_extensions);
+    info!("cache-control: {:?}", cache_control_value(&config));
+    info!("compression: {}", !config.no_compress);
+    info!("secure headers: {}", config.secure_headers);
+    if let Some(origin) = &config.cors {
+        info!(
+            "cors: {:?}",
+            origin.as_ref().map_or("*", |o| o.to_str().unwrap_or("?"))
+        );
+    }
+
+    if config.hsts.is_some() && tls_config.is_none() {
+        warn!("--hsts has no effect without TLS");
+    }
+
+    let service = FileService::new(config.clone())?;
+    if let Some(path) = &config.access_log {
+        info!("access log: {}", path.display());
+    }
+    if let Some(ttl) = config.metadata_cache_ttl {
+        info!("metadata cache: {} ms", ttl);
+    }
+    if let Some(cache) = &service.state.file_cache {
+        info!("file cache: {} bytes", cache.capacity());
+    }
+    if service.state.events.is_some() {
+        info!("live reload events: {}", watch::EVENTS_PATH);
+    }
+
+    // Create the MakeService object that creates a new Hyper service for every
+    // connection. Both these closures need to return a Future of Result, and we
+    // use two different mechanisms to achieve that.
+    let make_service = make_service_fn(|conn: &Conn| {
+        let conn_info = conn.info();
+        let activity = conn.activity();
+        let file_service = service.clone();
+
+        let service = service_fn(move |req| {
+            let config = file_service.config.clone();
+            let state = file_service.state.clone();
+
+            // Keep the connection's own timeouts off until the response is
+            // ready.
+            let request = activity.start_request();
+            let last_request = config.no_keep_alive
+                || config
+                    .max_requests_per_connection
+                    .is_some_and(|max| request.number() >= max);
+
+            // Run the request in a span, which is given the status and
+            // duration when the response is ready.
+            let span = info_span!(
+                "request",
+                method = %req.method(),
+                path = %req.uri().path(),
+                status = field::Empty,
+                duration_ms = field::Empty,
+            );
+
+            // Handle the request, returning a Future of Response,
+            // and map it to a Future of Result of Response.
+            serve(config, conn_info, state, req)
+                .instrument(span)
+                .map(move |mut resp| {
+                    if resp.status() == StatusCode::SWITCHING_PROTOCOLS {
+                        request.upgraded();
+                    }
+                    drop(request);
+                    if last_request {
+                        // Hyper closes the connection after sending this.
+                        resp.headers_mut()
+                            .insert(header::CONNECTION, HeaderValue::from_static("close"));
+                    }
+                    Ok::<_, Error>(resp)
+                })
+        });
+
+        // Convert the concrete (non-future) service function to a Future of Result.
+        future::ok::<_, Error>(service)
+    });
+
+    // Create a Tokio runtime and block on Hyper forever.
+    let rt = Runtime::new()?;
+    rt.block_on(async {
+        // Bind to the addresses, and create the stream of incoming
+        // connections, which are wrapped in TLS when serving HTTPS.
+        let tls_acceptor = tls_config.map(|(tls_config, cert_watcher)| {
+            // Reload the certificates in the background when they change.
+            tokio::spawn(cert_watcher.run());
+            TlsAcceptor::from(tls_config)
+        });
+        let listening = conn::listen(&config, tls_acceptor).await?;
+
+        // "0.0.0.0" can't be browsed to, so log the URLs that can.
+        for &addr in listening.addrs.iter().filter(|a| a.ip().is_unspecified()) {
+            info!("local url: {}", browser::local_url(scheme, addr, "/"));
+            for lan_addr in browser::lan_addrs(addr) {
+                info!("network url: {}", browser::url(scheme, lan_addr, "/"));
+            }
+        }
+
+        // Show the site now that it can be connected to.
+        if let (Some(path), Some(&addr)) = (&config.open, listening.addrs.first()) {
+            let path = path.as_deref().unwrap_or("/");
+            browser::open(&browser::local_url(scheme, addr, path));
+        }
+
+        // Let other machines find the server by name. Advertising stops when
+        // the daemon is dropped.
+        let _mdns = mdns::advertise(&config, scheme, &listening.addrs)?;
+
+        // Make the site easy to open from a phone.
+        if config.qr {
+            let lan_addr = listening
+                .addrs
+                .iter()
+                .flat_map(|&addr| browser::lan_addrs(addr))
+                .next();
+            match lan_addr {
+                Some(addr) => qr::print(&browser::url(scheme, addr, "/")),
+                None => {
+                    warn!("--qr needs an --addr that other machines can reach, like 0.0.0.0:4000")
+                }
+            }
+        }
+
+        // Keep track of which proxy upstreams are up.
+        tokio::spawn(proxy::check_health(config.clone()));
+
+        // Create a Hyper Server from the connections, and use our service
+        // builder.
+        let server = Server::builder(accept::from_stream(listening.incoming))
+            .http1_keepalive(!config.no_keep_alive)
+            .serve(make_service);
+
+        server.await?;
+
+        Ok(())
+    })
+}
+
+/// Fill in the options implied by others, and check that this build can use
+/// them.
+fn finish_config(mut config: Config) -> Result<Config> {
+    // Docs are rendered by the markdown extension.
+    if config.docs {
+        config.use_extensions = true;
+    }
+
+    check_features(&config)?;
+    Ok(config)
+}
+
+/// Refuse the options that need a cargo feature this build was made without.
+fn check_features(config: &Config) -> Result<()> {
+    let needs = [
+        (
+            "--docs",
+            "markdown",
+            config.docs,
+            cfg!(feature = "markdown"),
+        ),
+        (
+            "--list-dirs",
+            "dir-listing",
+            config.list_dirs,
+            cfg!(feature = "dir-listing"),
+        ),
+        (
+            "--template-dir",
+            "templates",
+            config.template_dir.is_some(),
+            cfg!(feature = "templates"),
+        ),
+    ];
+
+    match needs.iter().find(|(_, _, used, enabled)| *used && !enabled) {
+        Some((option, feature, ..)) => Err(Error::FeatureDisabled(option, feature)),
+        None => Ok(()),
+    }
+}
+
+/// The state of the server shared by every request, other than the
+/// configuration.
+#[derive(Clone)]
+struct State {
+    access_log: Option<AccessLog>,
+    events: Option<watch::Events>,
+    proxy: Option<proxy::Proxy>,
+    rewrites: rewrite::Rewrites,
+    rate_limiter: Option<rate_limit::RateLimiter>,
+    /// The built-in hooks, then those added to a `FileService`.
+    hooks: Arc<Vec<Arc<dyn Hook>>>,
+    file_cache: Option<file_cache::FileCache>,
+}
+
+/// Serving files as `serve` does, as a hyper `Service`, for using the server
+/// from another program.
+///
+/// Each request is served as if from a connection with unknown addresses.
+/// The service can be cloned for each connection, in a `make_service_fn`,
+/// and to serve a site under a sub-route of another one, set `--base-path`
+/// to the sub-route.
+#[derive(Clone)]
+pub struct FileService {
+    config: Config,
+    state: State,
+}
+
+impl FileService {
+    /// Make a service for a configuration, starting what `serve` starts other
+    /// than the listeners, like the file watcher for `--live-reload`.
+    pub fn new(config: Config) -> Result<FileService> {
+        let config = finish_config(config)?;
+        meta_cache::init(&config);
+
+        let state = State {
+            access_log: AccessLog::open(&config)?,
+            events: watch::start(&config)?,
+            proxy: proxy::Proxy::new(&config)?,
+            rewrites: rewrite::Rewrites::new(&config)?,
+            rate_limiter: rate_limit::RateLimiter::new(&config),
+            hooks: Arc::new(vec![
+                Arc::new(plugin::Plugins::load(&config)?),
+                Arc::new(ext::Extensions),
+            ]),
+            file_cache: file_cache::FileCache::new(config.cache_size),
+        };
+
+        Ok(FileService { config, state })
+    }
+
+    /// Add a hook, which is run after the ones added before it.
+    pub fn hook<H: Hook + 'static>(mut self, hook: H) -> FileService {
+        Arc::make_mut(&mut self.state.hooks).push(Arc::new(hook));
+        self
+    }
+}
+
+impl tower_service::Service<Request<Body>> for FileService {
+    type Response = Response<Body>;
+    type Error = Error;
+    type Future = future::BoxFuture<'static, Result<Response<Body>>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let conn = ConnInfo::default();
+        serve(self.config.clone(), conn, self.state.clone(), req)
+            .map(Ok)
+            .boxed()
+    }
+}
+
+/// Create an HTTP Response future for each Request.
+///
+/// Errors are turned into an appropriate HTTP error response, and never
+/// propagated upward for hyper to deal with.
+async fn serve(
+    config: Config,
+    conn: ConnInfo,
+    state: State,
+    mut req: Request<Body>,
+) -> Response<Body> {
+    let start = Instant::now();
+    let method = req.method().clone();
+
+    // Use the client's address from a trusted proxy's headers.
+    let conn = trusted_proxy::resolve(&config, conn, &req);
+
+    // Take --base-path off the URL, before anything else looks at it.
+    let in_base_path = base_path::strip(&config, &mut req);
+
+    // Serve "/~USER/" from the user's directory, as its own site.
+    let config = if in_base_path {
+        userdir::resolve(config, &mut req).await
+    } else {
+        config
+    };
+
+    // Remember the path for choosing the response headers.
+    let path = req.uri().path().to_string();
+
+    // Start the access log record before the request is consumed.
+    let mut record = state
+        .access_log
+        .as_ref()
+        .map(|_| access_log::Record::new(conn, &req));
+
+    // Pick the content encoding before the request is consumed.
+    let encoding = if config.no_compress {
+        None
+    } else {
+        compress::negotiate(req.headers())
+    };
+
+    // Pretend to be a slow server.
+    delay::sleep(&config, &path).await;
+
+    // Serve the requested file, giving up after --request-timeout.
+    let resp = if in_base_path {
+        let resp = serve_or_error(config.clone(), conn, &state, req);
+        match config.request_timeout.and_then(conn::timeout_secs) {
+            Some(timeout) => Timeout::new(resp, timeout)
+                .await
+                .unwrap_or(Err(Error::RequestTimeout)),
+            None => resp.await,
+        }
+    } else {
+        base_path::outside(&config, &req)
+    };
+
+    // Transform internal errors to error responses.
+    let mut resp = transform_error(&config, resp);
+
+    // Make redirects to the site's paths go under --base-path.
+    base_path::add_to_location(&config, &mut resp);
+
+    // Make HTML pages reload themselves when files change.
+    let resp = if state.events.is_some() {
+        watch::inject_reload_script(resp)
+    } else {
+        resp
+    };
+
+    // Compress the response body if the client accepts it and the
+    // compression policy allows it.
+    let mut resp = match encoding {
+        Some(encoding) if compress::should_compress(&config, &resp) => {
+            compress::compress_response(encoding, resp)
+        }
+        _ => resp,
+    };
+
+    // Add the headers that every response carries.
+    add_config_headers(&config, conn, &path, &mut resp);
+
+    if let (Some(access_log), Some(record)) = (&state.access_log, &mut record) {
+        record.finish(&resp);
+        access_log.write(record);
+    }
+
+    let elapsed = start.elapsed();
+    let span = Span::current();
+    span.record("status", resp.status().as_u16());
+    span.record("duration_ms", elapsed.as_millis() as u64);
+    debug!("{} {} {} in {:?}", method, path, resp.status(), elapsed);
+
+    resp
+}
+
+/// Add the headers configured on the command line to a response.
+///
+/// Headers already set by the response itself are left alone, except by
+/// `--header`, which always wins.
+fn add_config_headers(config: &Config, conn: ConnInfo, path: &str, resp: &mut Response<Body>) {
+    let headers = resp.headers_mut();
+
+    if !headers.contains_key(header::CACHE_CONTROL) {
+        headers.insert(header::CACHE_CONTROL, cache_control_value(config));
+    }
+
+    if let Some(origin) = &config.cors {
+        let origin = origin
+            .clone()
+            .unwrap_or_else(|| HeaderValue::from_static("*"));
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    }
+
+    if let Some(hsts) = hsts_value(config, conn) {
+        headers.insert(header::STRICT_TRANSPORT_SECURITY, hsts);
+    }
+
+    if config.secure_headers {
+        headers::add_secure_headers(headers);
+    }
+
+    headers::add_custom_headers(&config.headers, path, headers);
+}
+
+/// The default max-age of the Strict-Transport-Security header, one year.
+const DEFAULT_HSTS_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+
+/// Get the value of the Strict-Transport-Security header, if `--hsts` is on
+/// and the connection is using TLS.
+///
+/// Browsers ignore the header over plain HTTP.
+fn hsts_value(config: &Config, conn: ConnInfo) -> Option<HeaderValue> {
+    let max_age = config.hsts?.unwrap_or(DEFAULT_HSTS_MAX_AGE);
+
+    if !conn.tls {
+        return None;
+    }
+
+    let mut value = format!("max-age={}", max_age);
+    if config.hsts_include_subdomains {
+        value.push_str("; includeSubDomains");
+    }
+
+    Some(HeaderValue::from_str(&value).expect("hsts is valid header"))
+}
+
+/// Get the value of the Cache-Control header, from either `--max-age` or
+/// `--cache-control`.
+fn cache_control_value(config: &Config) -> HeaderValue {
+    if let Some(max_age) = config.max_age {
+        HeaderValue::from_str(&format!("max-age={}", max_age)).expect("max-age is valid header")
+    } else {
+        config.cache_control.clone()
+    }
+}
+
+/// Handle all types of requests, but don't deal with transforming internal
+/// errors to HTTP error responses.
+async fn serve_or_error(
+    config: Config,
+    conn: ConnInfo,
+    state: &State,
+    req: Request<Body>,
+) -> Result<Response<Body>> {
+    // Turn away clients making too many requests.
+    if let (Some(limiter), Some(addr)) = (&state.rate_limiter, conn.remote_addr) {
+        if let Err(wait) = limiter.check(addr.ip()) {
+            return rate_limit::too_many_requests(&config, wait);
+        }
+    }
+
+    // Refuse requests for unknown hosts, which may come from DNS rebinding.
+    if !allowed_hosts::is_allowed(&config, &req) {
+        return make_error_response_from_code(&config, StatusCode::FORBIDDEN);
+    }
+
+    // Serve virtual hosts from their own root directories.
+    let config = vhost::resolve(config, &req);
+
+    // Send plain HTTP requests over to HTTPS, if configured to.
+    if config.https_redirect && !conn.tls {
+        return https_redirect(&config, &req);
+    }
+
+    // Add the trailing slash to "/~USER".
+    if let Some(resp) = userdir::redirect(&config, &req).await {
+        return Ok(resp);
+    }
+
+    // Send the redirects from the redirects file.
+    if let Some(resp) = redirects::redirect(&config, &req).await? {
+        return Ok(resp);
+    }
+
+    // Rewrite the URL before it's proxied or resolved to a file.
+    let mut req = state.rewrites.apply(req)?;
+
+    // Let the hooks, like the plugins, answer the request first.
+    for hook in state.hooks.iter() {
+        if let Some(resp) = hook.before(&config, &mut req).await? {
+            return Ok(resp);
+        }
+    }
+
+    // Find the file in the fallback roots if it isn't in the root directory.
+    let config = overlay::resolve(config, &req);
+
+    // Forward requests for proxied paths, whatever their method.
+    if let Some(proxy) = &state.proxy {
+        if let Some(route) = proxy::find_route(&config, req.uri()) {
+            return proxy.forward(&config, conn, route, req).await;
+        }
+    }
+
+    // Run CGI scripts, whatever the method.
+    if cgi::is_cgi_request(&config, &req) {
+        return cgi::serve(&config, conn, req).await;
+    }
+    if let Some((backend, script)) = fastcgi::find_script(&config, &req) {
+        return fastcgi::serve(&config, conn, backend, script, req).await;
+    }
+
+    // List and change files over WebDAV, or from a directory listing.
+    if webdav::is_webdav_request(&config, &req) {
+        return webdav::serve(&config, req).await;
+    }
+    if file_manager::is_file_manager_request(&config, &req) {
+        return file_manager::serve(&config, req).await;
+    }
+    if upload::is_upload_request(&config, &req) {
+        return upload::serve(&config, req).await;
+    }
+
+    // Answer CORS preflight requests, which browsers send before
+    // cross-origin requests they consider unsafe, and WebDAV clients' OPTIONS
+    // requests.
+    if (config.cors.is_some() || config.webdav) && req.method() == Method::OPTIONS {
+        return make_preflight_response(&config, &req);
+    }
+
+    // This server only supports the GET method. Return an appropriate
+    // response otherwise.
+    if let Some(resp) = handle_unsupported_request(&config, &req) {
+        return resp;
+    }
+
+    // Stream file changes to live reload clients.
+    if let Some(events) = &state.events {
+        if req.uri().path() == watch::EVENTS_PATH {
+            return events.response();
+        }
+    }
+
+    // Search for files by name.
+    if search::is_search_request(&config, &req) {
+        return search::serve(&config, &req).await;
+    }
+
+    // Describe the directory tree and files to scripts.
+    if tree::is_tree_request(&config, &req) {
+        return tree::serve(&config, &req).await;
+    }
+    if tree::is_stat_request(&config, &req) {
+        return tree::serve_stat(&config, &req);
+    }
+
+    // Pretend that hidden files don't exist. Paths with `..` are refused,
+    // since `--ignore` globs wouldn't match them but the OS would resolve them.
+    let path = match webdav::local_path(&config, req.uri())? {
+        Some(path) => path,
+        None => {
+            debug!("refusing {} outside the root", req.uri());
+            return make_error_response_from_code(&config, StatusCode::NOT_FOUND);
+        }
+    };
+    if hide::is_hidden(&config, &path) {
+        debug!("hiding {}", req.uri());
+        return make_error_response_from_code(&config, StatusCode::NOT_FOUND);
+    }
+
+    // Render files with the commands given for their extensions.
+    if let Some((handler, path)) = handler::find(&config, &req) {
+        return handler::serve(&config, handler, &path).await;
+    }
+
+    // Serve the requested file.
+    let mut resp = serve_file(&req, &config, state.file_cache.as_ref()).await;
+
+    // Give the hooks, like the developer extensions, an opportunity to
+    // post-process the request/response pair.
+    for hook in state.hooks.iter() {
+        resp = hook.after(&config, &req, resp).await;
+    }
+
+    resp
+}
+
+/// Serve static files from a root directory.
+async fn serve_file(
+    req: &Request<Body>,
+    config: &Config,
+    cache: Option<&file_cache::FileCache>,
+) -> Result<Response<Body>> {
+    let root_dir = &config.root_dir;
+
+    // First, try to do a redirect. If that doesn't happen, then find the path
+    // to the static file we want to serve - which may be `index.html` for
+    // directories - and send a response containing that file.
+    let maybe_redir_resp = try_dir_redirect(req, config)?;
+
+    if let Some(redir_resp) = maybe_redir_resp {
+        return Ok(redir_resp);
+    }
+
+    let mut path = local_path_with_maybe_index(req.uri(), &root_dir)?;
+
+    if config.clean_urls {
+        path = maybe_clean_url_path(path);
+    }
+
+    // The request may have been mapped to a hidden file, like an ignored
+    // `index.html`.
+    if hide::is_hidden(config, &path) {
+        let msg = format!("{} is hidden", path.display());
+        return Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, msg)));
+    }
+
+    Ok(respond_with_file(req, config, cache, path).await?)
+}
+
+/// With `--clean-urls`, map a path that doesn't exist, like `about`, to the
+/// HTML file `about.html`, if that exists.
+///
+/// This is how static site hosts like Netlify and GitHub Pages serve pages
+/// that are linked to without their extension.
+fn maybe_clean_url_path(path: PathBuf) -> PathBuf {
+    if meta_cache::exists(&path) {
+        return path;
+    }
+
+    let mut html_path = path.clone().into_os_string();
+    html_path.push(".html");
+    let html_path = PathBuf::from(html_path);
+
+    if meta_cache::is_file(&html_path) {
+        debug!("trying {} for clean URL", html_path.display());
+        html_path
+    } else {
+        path
+    }
+}
+
+/// Try to do a redirect for directories, according to `--trailing-slash`.
+///
+/// By default, if we get a URL without trailing "/" that can be mapped to a
+/// directory, then return a 302 redirect to the path with the trailing "/".
+///
+/// Without this we couldn't correctly return the contents of `index.html` for a
+/// directory - for the purpose of building absolute URLs from relative URLs,
+/// agents appear to only treat paths with trailing "/" as directories, so we
+/// have to redirect to the proper directory URL first.
+///
+/// In other words, if we returned the contents of `index.html` for URL `docs`
+/// then all the relative links in that file would be broken, but that is not
+/// the case for URL `docs/`.
+///
+/// This seems to match the behavior of other static web servers. Some sites
+/// are built to expect other canonical URLs though, so the policy can also be
+/// to serve directories without redirecting, or to redirect `docs/` to `docs`.
+fn try_dir_redirect(req: &Request<Body>, config: &Config) -> Result<Option<Response<Body>>> {
+    let uri_path = req.uri().path();
+
+    let new_path = match config.trailing_slash {
+        TrailingSlash::Serve => return Ok(None),
+        TrailingSlash::Redirect => {
+            if uri_path.ends_with('/') {
+                return Ok(None);
+            }
+
+            debug!("path does not end with /");
+            format!("{}/", uri_path)
+        }
+        TrailingSlash::Strip => {
+            if !uri_path.ends_with('/') || uri_path == "/" {
+                return Ok(None);
+            }
+
+            debug!("path ends with /");
+            uri_path.trim_end_matches('/').to_string()
+        }
+    };
+
+    let path = local_path_for_request(req.uri(), &config.root_dir)?;
+
+    if !meta_cache::is_dir(&path) {
+        return Ok(None);
+    }
+
+    let mut new_loc = new_path;
+    if let Some(query) = req.uri().query() {
+        new_loc.push('?');
+        new_loc.push_str(query);
+    }
+
+    info!("redirecting {} to {}", req.uri(), new_loc);
+    Response::builder()
+        .status(config.dir_redirect_status)
+        .header(header::LOCATION, new_loc)
+        .body(Body::empty())
+        .map(Some)
+        .map_err(Error::from)
+}
+
+/// How to treat trailing slashes on directory URLs.
+#[derive(Clone, Copy, Debug)]
+enum TrailingSlash {
+    /// Redirect `docs` to `docs/`.
+    Redirect,
+    /// Serve both `docs` and `docs/` without redirecting.
+    Serve,
+    /// Redirect `docs/` to `docs`.
+    Strip,
+}
+
+impl std::str::FromStr for TrailingSlash {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<TrailingSlash, String> {
+        match s {
+            "redirect" => Ok(TrailingSlash::Redirect),
+            "serve" => Ok(TrailingSlash::Serve),
+            "strip" => Ok(TrailingSlash::Strip),
+            _ => Err(format!("expected redirect, serve or strip, found '{}'", s)),
+        }
+    }
+}
+
+/// Parse the status code for directory redirects, which must be a redirect.
+fn parse_redirect_status(s: &str) -> std::result::Result<StatusCode, String> {
+    match s.parse::<StatusCode>() {
+        Ok(status) if status.is_redirection() => Ok(status),
+        _ => Err(format!("expected a 3xx status code, found '{}'", s)),
+    }
+}
+
+/// Make a 301 redirect to the same URL on the HTTPS listener.
+fn https_redirect(config: &Config, req: &Request<Body>) -> Result<Response<Body>> {
+    let tls_addr = config
+        .tls_addr
+        .expect("--https-redirect requires --tls-addr");
+
+    // Use the host name the client asked for, but with the HTTPS port.
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<http::uri::Authority>().ok())
+        .map(|a| a.host().to_string())
+        .unwrap_or_else(|| tls_addr.ip().to_string());
+    let host = if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]", host)
+    } else {
+        host
+    };
+
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let path_and_query = base_path::url(config, path_and_query);
+
+    let new_loc = if tls_addr.port() == 443 {
+        format!("https://{}{}", host, path_and_query)
+    } else {
+        format!("https://{}:{}{}", host, tls_addr.port(), path_and_query)
+    };
+
+    info!("redirecting {} to {}", req.uri(), new_loc);
+    Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header(header::LOCATION, new_loc)
+        .body(Body::empty())
+        .map_err(Error::from)
+}
+
+/// Construct a 200 response with the file as the body, streaming it to avoid
+/// loading it fully into memory, unless it's small enough for the file cache,
+/// or from a memory map with `--mmap`.
+///
+/// If the request carries an `If-None-Match` header matching the file's ETag
+/// then a 304 response with no body is returned instead.
+///
+/// If the I/O here fails then an error future will be returned, and `serve`
+/// will convert it into the appropriate HTTP error response.
+async fn respond_with_file(
+    req: &Request<Body>,
+    config: &Config,
+    cache: Option<&file_cache::FileCache>,
+    path: PathBuf,
+) -> Result<Response<Body>> {
+    let mime_type = mime_type::file_path_mime(config, &path);
+    let content_type = mime_type::content_type(config, &mime_type);
+
+    let cached = match cache {
+        Some(cache) => cache.get(&path).await?,
+        None => None,
+    };
+    let (meta, body) = match cached {
+        Some((meta, data)) => (meta, Body::from(data)),
+        None => {
+            let mapped = if config.mmap {
+                mmap::file_body(path.clone()).await?
+            } else {
+                None
+            };
+            match mapped {
+                Some(mapped) => mapped,
+                None => {
+                    let file = File::open(path).await?;
+                    let meta = file.metadata().await?;
+                    (meta, file_body(file))
+                }
+            }
+        }
+    };
+    let len = meta.len();
+    let etag = file_etag(&meta)?;
+
+    if etag_matches(req, &etag) {
+        debug!("etag {} matches, not modified", etag);
+        let resp = Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, etag)
+            .body(Body::empty())?;
+        return Ok(resp);
+    }
+
+    let resp = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_LENGTH, len)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::ETAG, etag)
+        .body(body)?;
+
+    Ok(resp)
+}
+
+/// Stream a file as a response body.
+fn file_body(file: File) -> Body {
+    // Here's the streaming code. How to do this isn't documented in the
+    // Tokio/Hyper API docs. Codecs are how Tokio creates Streams; a FramedRead
+    // turns an AsyncRead plus a Decoder into a Stream; and BytesCodec is a
+    // Decoder. FramedRead though creates a Stream<Result<BytesMut>> and Hyper's
+    // Body wants a Stream<Result<Bytes>>, and BytesMut::freeze will give us a
+    // Bytes.
+
+    let codec = BytesCodec::new();
+    let stream = FramedRead::new(file, codec);
+    let stream = stream.map(|b| b.map(BytesMut::freeze));
+    Body::wrap_stream(stream)
+}
+
+/// Create an ETag for a file from its modification time and size.
+///
+/// This is cheap to compute, and good enough to tell when a file has been
+/// rewritten during development.
+fn file_etag(meta: &Metadata) -> Result<String> {
+    let mtime = meta.modified()?;
+    let mtime = mtime
+        .duration_since(UNIX_EPOCH)
+        .map_err(Error::MtimeBeforeEpoch)?;
+    Ok(format!(
+        "\"{:x}.{:x}-{:x}\"",
+        mtime.as_secs(),
+        mtime.subsec_nanos(),
+        meta.len()
+    ))
+}
+
+/// Check whether the request's `If-None-Match` header matches an ETag.
+///
+/// `If-None-Match` uses the "weak comparison" function, so a `W/` prefix on
+/// the client's tags is ignored.
+///
+/// https://tools.ietf.org/html/rfc7232#section-3.2
+fn etag_matches(req: &Request<Body>, etag: &str) -> bool {
+    let if_none_match = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok());
+
+    if let Some(if_none_match) = if_none_match {
+        if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+    } else {
+        false
+    }
+}
+
+/// Find the local path for a request URI, converting directories to the
+/// `index.html` file.
+fn local_path_with_maybe_index(uri: &Uri, root_dir: &Path) -> Result<PathBuf> {
+    local_path_for_request(uri, root_dir).map(|mut p: PathBuf| {
+        if meta_cache::is_dir(&p) {
+            p.push("index.html");
+            debug!("trying {} for directory URL", p.display());
+        } else {
+            trace!("trying path as from URL");
+        }
+        p
+    })
+}
+
+/// Map the request's URI to a local path
+fn local_path_for_request(uri: &Uri, root_dir: &Path) -> Result<PathBuf> {
+    debug!("raw URI: {}", uri);
+
+    let request_path = uri.path();
+
+    debug!("raw URI to path: {}", request_path);
+
+    // Trim off the url parameters starting with '?'
+    let end = request_path.find('?').unwrap_or(request_path.len());
+    let request_path = &request_path[0..end];
+
+    // Convert %-encoding to actual values
+    let decoded = percent_decode_str(&request_path);
+    let request_path = if let Ok(p) = decoded.decode_utf8() {
+        p
+    } else {
+        error!("non utf-8 URL: {}", request_path);
+        return Err(Error::UriNotUtf8);
+    };
+
+    // Append the requested path to the root directory
+    let mut path = root_dir.to_owned();
+    if request_path.starts_with('/') {
+        path.push(&request_path[1..]);
+    } else {
+        warn!("found non-absolute path {}", request_path);
+        return Err(Error::UriNotAbsolute);
+    }
+
+    debug!("URL · path : {} · {}", uri, path.display());
+
+    Ok(path)
+}
+
+/// Create an error response if the request contains unsupported methods,
+/// headers, etc.
+fn handle_unsupported_request(
+    config: &Config,
+    req: &Request<Body>,
+) -> Option<Result<Response<Body>>> {
+    get_unsupported_request_message(config, req)
+        .map(|unsup| make_error_response_from_code_and_headers(config, unsup.code, unsup.headers))
+}
+
+/// Description of an unsupported request.
+struct Unsupported {
+    code: StatusCode,
+    headers: HeaderMap,
+}
+
+/// Create messages for unsupported requests.
+fn get_unsupported_request_message(config: &Config, req: &Request<Body>) -> Option<Unsupported> {
+    use std::iter::FromIterator;
+
+    // https://tools.ietf.org/html/rfc7231#section-6.5.5
+    if req.method() != Method::GET {
+        return Some(Unsupported {
+            code: StatusCode::METHOD_NOT_ALLOWED,
+            headers: HeaderMap::from_iter(vec![(header::ALLOW, allowed_methods(config))]),
+        });
+    }
+
+    None
+}
+
+/// The methods this server supports, for the `Allow` header.
+fn allowed_methods(config: &Config) -> HeaderValue {
+    if config.webdav {
+        HeaderValue::from_static(webdav::ALLOWED_METHODS)
+    } else if config.cors.is_some() {
+        HeaderValue::from_static("GET, OPTIONS")
+    } else {
+        HeaderValue::from_static("GET")
+    }
+}
+
+/// Respond to a CORS preflight request with a 204.
+///
+/// WebDAV clients get the same response to their OPTIONS requests, with the
+/// `DAV` header saying which parts of WebDAV are supported.
+///
+/// The preflight asks whether a method and set of request headers are allowed.
+/// Any headers are allowed, since the server ignores them anyway.
+///
+/// https://fetch.spec.whatwg.org/#http-cors-protocol
+fn make_preflight_response(config: &Config, req: &Request<Body>) -> Result<Response<Body>> {
+    let mut builder = Response::builder();
+    builder
+        .status(StatusCode::NO_CONTENT)
+        .header(header::ALLOW, allowed_methods(config))
+        .header(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            allowed_methods(config),
+        )
+        .header(header::ACCESS_CONTROL_MAX_AGE, "86400");
+
+    if config.webdav {
+        builder.header("dav", webdav::DAV_CLASSES);
+    }
+
+    if let Some(headers) = req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+        builder.header(header::ACCESS_CONTROL_ALLOW_HEADERS, headers.clone());
+    }
+
+    builder.body(Body::empty()).map_err(Error::from)
+}
+
+/// Turn any errors into an HTTP error response.
+fn transform_error(config: &Config, resp: Result<Response<Body>>) -> Response<Body> {
+    match resp {
+        Ok(r) => r,
+        Err(e) => {
+            let resp = make_error_response(config, e);
+            match resp {
+                Ok(r) => r,
+                Err(e) => {
+                    // Last-ditch error reporting if even making the error response failed.
+                    error!("unexpected internal error: {}", e);
+                    Response::new(Body::from(format!("unexpected internal error: {}", e)))
+                }
+            }
+        }
+    }
+}
+
+/// Convert an error to an HTTP error response future, with correct response code.
+fn make_error_response(config: &Config, e: Error) -> Result<Response<Body>> {
+    let resp = match e {
+        Error::Io(e) => make_io_error_response(config, e)?,
+        Error::Ext(ext::Error::Io(e)) => make_io_error_response(config, e)?,
+        Error::UploadTooLarge => {
+            debug!("{}", e);
+            make_error_response_from_code(config, StatusCode::PAYLOAD_TOO_LARGE)?
+        }
+        Error::RequestTimeout => {
+            debug!("{}", e);
+            make_error_response_from_code(config, StatusCode::REQUEST_TIMEOUT)?
+        }
+        e => make_internal_server_error_response(config, e)?,
+    };
+    Ok(resp)
+}
+
+/// Convert an error into a 500 internal server error, and log it.
+fn make_internal_server_error_response(config: &Config, err: Error) -> Result<Response<Body>> {
+    log_error_chain(&err);
+    let resp = make_error_response_from_code(config, StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(resp)
+}
+
+/// Handle the one special IO error (file not found) by returning a 404, otherwise
+/// return a 500.
+fn make_io_error_response(config: &Config, error: io::Error) -> Result<Response<Body>> {
+    let resp = match error.kind() {
+        io::ErrorKind::NotFound => {
+            debug!("{}", error);
+            make_error_response_from_code(config, StatusCode::NOT_FOUND)?
+        }
+        _ => make_internal_server_error_response(config, Error::Io(error))?,
+    };
+    Ok(resp)
+}
+
+/// Make an error response given an HTTP status code.
+fn make_error_response_from_code(config: &Config, status: StatusCode) -> Result<Response<Body>> {
+    make_error_response_from_code_and_headers(config, status, HeaderMap::new())
+}
+
+/// Make an error response given an HTTP status code and response headers.
+fn make_error_response_from_code_and_headers(
+    config: &Config,
+    status: StatusCode,
+    headers: HeaderMap,
+) -> Result<Response<Body>> {
+    let body = render_error_html(config, status)?;
+    let resp = html_str_to_response_with_headers(body, status, headers)?;
+    Ok(resp)
+}
+
+/// Make an HTTP response from a HTML string.
+fn html_str_to_response(body: String, status: StatusCode) -> Result<Response<Body>> {
+    html_str_to_response_with_headers(body, status, HeaderMap::new())
+}
+
+/// Make an HTTP response from a HTML string and response headers.
+fn html_str_to_response_with_headers(
+    body: String,
+    status: StatusCode,
+    headers: HeaderMap,
+) -> Result<Response<Body>> {
+    let mut builder = Response::builder();
+
+    builder.headers_mut().map(|h| h.extend(headers));
+
+    builder
+        .status(status)
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::CONTENT_TYPE, mime::TEXT_HTML.as_ref())
+        .body(Body::from(body))
+        .map_err(Error::from)
+}
+
+/// A handlebars HTML template.
+static HTML_TEMPLATE: &str = include_str!("template.html");
+
+/// The data for the handlebars HTML template. Handlebars will use serde to get
+/// the data out of the struct and mapped onto the template.
+#[derive(Serialize)]
+struct HtmlCfg {
+    title: String,
+    body: String,
+}
+
+/// The kinds of generated HTML pages.
+///
+/// Each can have its own template in `--template-dir`.
+#[derive(Clone, Copy, Debug)]
+enum Page {
+    Error,
+    Listing,
+    Markdown,
+}
+
+impl Page {
+    /// The file name of the page's template in `--template-dir`.
+    fn template_file_name(self) -> &'static str {
+        match self {
+            Page::Error => "error.html",
+            Page::Listing => "listing.html",
+            Page::Markdown => "markdown.html",
+        }
+    }
+}
+
+/// Render an HTML page with handlebars, the template and the configuration data.
+///
+/// The template comes from `--template-dir` if it has one for the page, or
+/// is the built-in template otherwise. Templates are read on every request so
+/// that they can be edited without restarting the server.
+#[cfg(feature = "templates")]
+fn render_html(config: &Config, page: Page, cfg: HtmlCfg) -> Result<String> {
+    let template = match &config.template_dir {
+        Some(dir) => read_template(dir, page)?,
+        None => None,
+    };
+    let template = template.as_deref().unwrap_or(HTML_TEMPLATE);
+
+    let reg = handlebars::Handlebars::new();
+    let rendered = reg
+        .render_template(template, &cfg)
+        .map_err(|e| Error::TemplateRender(Box::new(e)))?;
+    Ok(rendered)
+}
+
+/// Render an HTML page by filling in the title and body of the built-in
+/// template, in builds without handlebars.
+#[cfg(not(feature = "templates"))]
+fn render_html(_config: &Config, _page: Page, cfg: HtmlCfg) -> Result<String> {
+    Ok(HTML_TEMPLATE
+        .replace("{{title}}", &ext::escape_html(&cfg.title))
+        .replace("{{{body}}}", &cfg.body))
+}
+
+/// Read a page's template from the template directory, if it is there.
+#[cfg(feature = "templates")]
+fn read_template(dir: &Path, page: Page) -> Result<Option<String>> {
+    let path = dir.join(page.template_file_name());
+    match std::fs::read_to_string(&path) {
+        Ok(template) => {
+            trace!("using template {}", path.display());
+            Ok(Some(template))
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::TemplateRead(e)),
+    }
+}
+
+/// Render an HTML page from an HTTP status code
+fn render_error_html(config: &Config, status: StatusCode) -> Result<String> {
+    render_html(
+        config,
+        Page::Error,
+        HtmlCfg {
+            title: format!("{}", status),
+            body: String::new(),
+        },
+    )
+}
+
+/// A custom `Result` typedef
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The basic-http-server error type.
+///
+/// This is divided into two types of errors: "semantic" errors and "blanket"
+/// errors. Semantic errors are custom to the local application semantics and
+/// are usually preferred, since they add context and meaning to the error
+/// chain. They don't require boilerplate `From` implementations, but do require
+/// `map_err` to create when they have interior `causes`.
+///
+/// Blanket errors are just wrappers around other types, like `Io(io::Error)`.
+/// These are common errors that occur in many places so are easier to code and
+/// maintain, since e.g. every occurrence of an I/O error doesn't need to be
+/// given local semantics.
+///
+/// The criteria of when to use which type of error variant, and their pros and
+/// cons, aren't obvious.
+///
+/// These errors use `derive(Display)` from the `derive-more` crate to reduce
+/// boilerplate.
+#[derive(Debug, Display)]
+pub enum Error {
+    // blanket "pass-through" error types
+    #[display(fmt = "Extension error")]
+    Ext(ext::Error),
+
+    #[display(fmt = "HTTP error")]
+    Http(http::Error),
+
+    #[display(fmt = "Hyper error")]
+    Hyper(hyper::Error),
+
+    #[display(fmt = "I/O error")]
+    Io(io::Error),
+
+    // custom "semantic" error types
+    #[display(fmt = "failed to open access log")]
+    AccessLogOpen(io::Error),
+
+    #[display(fmt = "failed to watch the root directory")]
+    Watch(notify::Error),
+
+    #[display(fmt = "failed to parse IP address")]
+    AddrParse(std::net::AddrParseError),
+
+    #[display(fmt = "file modification time is before the Unix epoch")]
+    MtimeBeforeEpoch(std::time::SystemTimeError),
+
+    #[display(fmt = "failed to generate certificate")]
+    CertGenerate(rcgen::RcgenError),
+
+    #[display(fmt = "failed to write certificate")]
+    CertWrite(io::Error),
+
+    #[display(fmt = "failed to parse TLS certificate")]
+    TlsCertParse,
+
+    #[display(fmt = "failed to read TLS certificate or key")]
+    TlsFileRead(io::Error),
+
+    #[display(fmt = "failed to parse TLS private key")]
+    TlsKeyParse,
+
+    #[display(fmt = "certificate is not valid for its --tls-sni host name")]
+    TlsSniCert(rustls::TLSError),
+
+    #[display(fmt = "--https-redirect requires --tls-addr")]
+    HttpsRedirectWithoutTlsAddr,
+
+    #[display(fmt = "{} requires the \"{}\" cargo feature", _0, _1)]
+    FeatureDisabled(&'static str, &'static str),
+
+    #[display(fmt = "--tls-addr requires a TLS certificate")]
+    TlsAddrWithoutTls,
+
+    #[display(fmt = "use either --tls-cert with --tls-key, or --tls-auto")]
+    TlsOptions,
+
+    #[cfg(feature = "templates")]
+    #[display(fmt = "failed to read template")]
+    TemplateRead(io::Error),
+
+    #[cfg(feature = "templates")]
+    #[display(fmt = "failed to render template")]
+    TemplateRender(Box<handlebars::TemplateRenderError>),
+
+    #[display(fmt = "failed to create proxy cache directory")]
+    ProxyCacheDir(io::Error),
+
+    #[display(fmt = "failed to run CGI script")]
+    CgiSpawn(io::Error),
+
+    #[display(fmt = "invalid CGI script output")]
+    CgiOutput(io::Error),
+
+    #[display(fmt = "FastCGI error")]
+    FastCgi(io::Error),
+
+    #[display(fmt = "failed to run --handler command")]
+    HandlerSpawn(io::Error),
+
+    #[display(fmt = "--handler command failed with {}", _0)]
+    HandlerFailed(std::process::ExitStatus),
+
+    #[display(fmt = "--handler command output failed")]
+    HandlerOutput(io::Error),
+
+    #[display(fmt = "invalid upload")]
+    Upload(io::Error),
+
+    #[display(fmt = "upload is bigger than --max-upload-size")]
+    UploadTooLarge,
+
+    #[display(fmt = "request took longer than --request-timeout")]
+    RequestTimeout,
+
+    #[display(fmt = "invalid --rewrite pattern")]
+    RewritePattern(regex::Error),
+
+    #[display(fmt = "failed to read redirects file")]
+    RedirectsRead(io::Error),
+
+    #[display(fmt = "failed to parse redirects.toml")]
+    RedirectsParse(toml::de::Error),
+
+    #[display(fmt = "WebAssembly plugin failed")]
+    Plugin(plugin::PluginError),
+
+    #[display(fmt = "the configuration has problems")]
+    ConfigCheck,
+
+    #[display(fmt = "failed to advertise with mDNS")]
+    Mdns(mdns_sd::Error),
+
+    #[display(fmt = "requested URI is not an absolute path")]
+    UriNotAbsolute,
+
+    #[display(fmt = "requested URI is not UTF-8")]
+    UriNotUtf8,
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        use Error::*;
+
+        match self {
+            Ext(e) => Some(e),
+            Io(e) => Some(e),
+            Http(e) => Some(e),
+            Hyper(e) => Some(e),
+            AccessLogOpen(e) => Some(e),
+            Watch(e) => Some(e),
+            AddrParse(e) => Some(e),
+            MtimeBeforeEpoch(e) => Some(e),
+            CertGenerate(e) => Some(e),
+            CertWrite(e) => Some(e),
+            TlsCertParse => None,
+            TlsFileRead(e) => Some(e),
+            TlsKeyParse => None,
+            TlsOptions => None,
+            HttpsRedirectWithoutTlsAddr => None,
+            FeatureDisabled(..) => None,
+            TlsAddrWithoutTls => None,
+            TlsSniCert(e) => Some(e),
+            #[cfg(feature = "templates")]
+            TemplateRead(e) => Some(e),
+            ProxyCacheDir(e) => Some(e),
+            CgiSpawn(e) => Some(e),
+            CgiOutput(e) => Some(e),
+            FastCgi(e) => Some(e),
+            HandlerSpawn(e) => Some(e),
+            HandlerFailed(_) => None,
+            HandlerOutput(e) => Some(e),
+            Upload(e) => Some(e),
+            UploadTooLarge => None,
+            RequestTimeout => None,
+            Mdns(e) => Some(e),
+            RewritePattern(e) => Some(e),
+            RedirectsRead(e) => Some(e),
+            RedirectsParse(e) => Some(e),
+            Plugin(e) => Some(e),
+            ConfigCheck => None,
+            #[cfg(feature = "templates")]
+            TemplateRender(e) => Some(e),
+            UriNotAbsolute => None,
+            UriNotUtf8 => None,
+        }
+    }
+}
+
+impl From<ext::Error> for Error {
+    fn from(e: ext::Error) -> Error {
+        Error::Ext(e)
+    }
+}
+
+impl From<http::Error> for Error {
+    fn from(e: http::Error) -> Error {
+        Error::Http(e)
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(e: hyper::Error) -> Error {
+        Error::Hyper(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,47 @@
+//! Log output, using `tracing`.
+//!
+//! Events are printed like `env_logger` used to print them, as
+//! "[INFO ] message", without timestamps, module paths or spans. Each request
+//! runs in a "request" span, for subscribers that want more structure.
+
+use std::fmt;
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::EnvFilter;
+
+/// Initialize logging, and log the "info" level for this crate only, unless
+/// the environment contains `RUST_LOG`.
+///
+/// Logs from dependencies that use the `log` crate are included too.
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("basic_http_server=info"));
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .event_format(PlainFormat)
+        .init();
+}
+
+/// Formats an event as its level and message.
+struct PlainFormat;
+
+impl<S, N> FormatEvent<S, N> for PlainFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        write!(writer, "[{:<5}] ", event.metadata().level())?;
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
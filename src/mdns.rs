@@ -0,0 +1,73 @@
+//! Advertising the server on the local network with mDNS and DNS-SD, with
+//! `--mdns NAME`, so that other machines can find it as "NAME.local" and in
+//! service browsers, without knowing its IP address.
+
+use super::{Config, Error, Result};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use std::net::{IpAddr, SocketAddr};
+use tracing::{info, warn};
+
+/// Start advertising the listeners, if `--mdns` is given.
+///
+/// The advertisement lasts as long as the returned daemon.
+pub fn advertise(
+    config: &Config,
+    scheme: &str,
+    addrs: &[SocketAddr],
+) -> Result<Option<ServiceDaemon>> {
+    let name = match &config.mdns {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+
+    let port = match addrs.first() {
+        Some(addr) => addr.port(),
+        None => return Ok(None),
+    };
+    let ips: Vec<IpAddr> = addrs
+        .iter()
+        .flat_map(|&addr| super::browser::lan_addrs(addr))
+        .map(|addr| addr.ip())
+        .collect();
+    if ips.is_empty() {
+        warn!("--mdns needs an --addr that other machines can reach, like 0.0.0.0:4000");
+        return Ok(None);
+    }
+
+    let host_name = format!("{}.", host_name(name));
+    let service_type = format!("_{}._tcp.local.", scheme);
+    let properties = [("path", "/")];
+    let service = ServiceInfo::new(
+        &service_type,
+        name,
+        &host_name,
+        &ips[..],
+        port,
+        &properties[..],
+    )
+    .map_err(Error::Mdns)?;
+
+    let daemon = ServiceDaemon::new().map_err(Error::Mdns)?;
+    daemon.register(service).map_err(Error::Mdns)?;
+    info!(
+        "advertising {}://{}:{} with mDNS",
+        scheme,
+        host_name.trim_end_matches('.'),
+        port
+    );
+
+    Ok(Some(daemon))
+}
+
+/// The host name the server is advertised as, "LABEL.local".
+pub fn host_name(name: &str) -> String {
+    format!("{}.local", host_label(name).to_ascii_lowercase())
+}
+
+/// A DNS label for a name, with anything but letters, digits and hyphens
+/// replaced by hyphens.
+fn host_label(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
@@ -0,0 +1,109 @@
+//! Caching file metadata for a moment, with `--metadata-cache-ttl`, so that
+//! the checks made while resolving a request, like whether its path is a
+//! directory with an `index.html`, don't each cost a `stat`.
+//!
+//! A path's metadata, or that it doesn't exist, is kept until the TTL runs
+//! out, or with `--watch` until files change. Files are still opened and
+//! their own metadata read before they're streamed, so a response's length is
+//! never stale.
+//!
+//! The cache is global, like the highlighter in `ext`, since it is used by
+//! the path helpers that every module calls without the server's state.
+
+use super::Config;
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::trace;
+
+/// How many paths are cached before the expired ones are dropped.
+const PRUNE_THRESHOLD: usize = 4096;
+
+static CACHE: OnceLock<MetadataCache> = OnceLock::new();
+
+struct MetadataCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<PathBuf, Entry>>,
+}
+
+struct Entry {
+    /// `None` if the path doesn't exist.
+    meta: Option<Metadata>,
+    expires: Instant,
+}
+
+/// Turn on the cache, if `--metadata-cache-ttl` is given.
+pub fn init(config: &Config) {
+    if let Some(ttl) = config.metadata_cache_ttl {
+        let _ = CACHE.set(MetadataCache {
+            ttl: Duration::from_millis(ttl),
+            entries: Mutex::new(HashMap::new()),
+        });
+    }
+}
+
+/// Forget every path's metadata, after files change.
+pub fn clear() {
+    if let Some(cache) = CACHE.get() {
+        cache.entries.lock().expect("metadata cache lock").clear();
+    }
+}
+
+/// A path's metadata, following symlinks like `fs::metadata`.
+pub fn metadata(path: &Path) -> io::Result<Metadata> {
+    let cache = match CACHE.get() {
+        Some(cache) => cache,
+        None => return fs::metadata(path),
+    };
+
+    let now = Instant::now();
+    if let Some(entry) = cache.entries.lock().expect("metadata cache lock").get(path) {
+        if entry.expires > now {
+            trace!("cached metadata for {}", path.display());
+            return entry
+                .meta
+                .clone()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound));
+        }
+    }
+
+    let result = fs::metadata(path);
+    let meta = match &result {
+        Ok(meta) => Some(meta.clone()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        // Other errors, like permissions, are left for the caller to report.
+        Err(_) => return result,
+    };
+
+    let mut entries = cache.entries.lock().expect("metadata cache lock");
+    if entries.len() >= PRUNE_THRESHOLD {
+        entries.retain(|_, entry| entry.expires > now);
+    }
+    entries.insert(
+        path.to_owned(),
+        Entry {
+            meta,
+            expires: now + cache.ttl,
+        },
+    );
+
+    result
+}
+
+/// Like `Path::exists`, with cached metadata.
+pub fn exists(path: &Path) -> bool {
+    metadata(path).is_ok()
+}
+
+/// Like `Path::is_dir`, with cached metadata.
+pub fn is_dir(path: &Path) -> bool {
+    metadata(path).is_ok_and(|meta| meta.is_dir())
+}
+
+/// Like `Path::is_file`, with cached metadata.
+pub fn is_file(path: &Path) -> bool {
+    metadata(path).is_ok_and(|meta| meta.is_file())
+}
@@ -0,0 +1,167 @@
+//! Choosing the Content-Type of files, from the `--mime` and `--mime-types`
+//! options or the built-in table of `mime_guess`.
+
+use super::Config;
+use http::header::HeaderValue;
+use mime::Mime;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Get a MIME type based on the file extension.
+///
+/// If the extension is unknown then return "application/octet-stream".
+pub fn file_path_mime(config: &Config, file_path: &Path) -> Mime {
+    if let Some(mime) = mime_override(config, file_path) {
+        return mime;
+    }
+
+    let from_file = config
+        .mime_types_file
+        .as_ref()
+        .and_then(|file| file.lookup(file_path));
+    if let Some(mime) = from_file {
+        return mime;
+    }
+
+    if config.mime_types_only {
+        return mime::APPLICATION_OCTET_STREAM;
+    }
+
+    mime_guess::from_path(file_path).first_or_octet_stream()
+}
+
+/// Get the Content-Type header for a MIME type, adding the `--charset` to
+/// text types that don't have one.
+///
+/// Without a charset browsers guess the encoding, and often guess wrong for
+/// UTF-8.
+pub fn content_type(config: &Config, mime: &Mime) -> HeaderValue {
+    let add_charset =
+        !config.no_charset && mime.type_() == mime::TEXT && mime.get_param(mime::CHARSET).is_none();
+
+    let value = if add_charset {
+        format!("{}; charset={}", mime, config.charset)
+    } else {
+        mime.to_string()
+    };
+
+    HeaderValue::from_str(&value).expect("mime is valid header")
+}
+
+/// Parse the `--charset` option, which must be a valid MIME parameter value.
+pub fn parse_charset(s: &str) -> Result<String, String> {
+    let valid = !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'+-^_`{}~.".contains(c));
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!("invalid charset '{}'", s))
+    }
+}
+
+/// Get the MIME type given for the file extension by `--mime`, if any.
+pub fn mime_override(config: &Config, file_path: &Path) -> Option<Mime> {
+    let ext = file_path.extension()?.to_str()?;
+    config
+        .mime_types
+        .iter()
+        .rev()
+        .find(|m| m.ext.eq_ignore_ascii_case(ext))
+        .map(|m| m.mime.clone())
+}
+
+/// A MIME type for a file extension, from the `--mime EXT=TYPE` option.
+#[derive(Clone, Debug)]
+pub struct MimeOverride {
+    ext: String,
+    mime: Mime,
+}
+
+impl FromStr for MimeOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MimeOverride, String> {
+        let mut parts = s.splitn(2, '=');
+        let ext = parts.next().unwrap_or("").trim().trim_start_matches('.');
+        let mime = parts.next().unwrap_or("").trim();
+
+        if ext.is_empty() || mime.is_empty() {
+            return Err(format!("expected EXT=TYPE, found '{}'", s));
+        }
+
+        let mime = mime
+            .parse()
+            .map_err(|_| format!("invalid MIME type '{}'", mime))?;
+
+        Ok(MimeOverride {
+            ext: ext.to_string(),
+            mime,
+        })
+    }
+}
+
+/// The MIME types loaded from the `--mime-types` file, by lowercase file
+/// extension.
+///
+/// This is shared, since the configuration is cloned for every request.
+#[derive(Clone, Debug)]
+pub struct MimeTypesFile {
+    by_ext: Arc<HashMap<String, Mime>>,
+}
+
+impl MimeTypesFile {
+    /// Load a mime.types file, for the `--mime-types` option.
+    pub fn load(path: &OsStr) -> Result<MimeTypesFile, OsString> {
+        let path = Path::new(path);
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let by_ext = parse_mime_types(&contents)
+            .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+
+        Ok(MimeTypesFile {
+            by_ext: Arc::new(by_ext),
+        })
+    }
+
+    fn lookup(&self, file_path: &Path) -> Option<Mime> {
+        let ext = file_path.extension()?.to_str()?.to_ascii_lowercase();
+        self.by_ext.get(&ext).cloned()
+    }
+}
+
+/// Parse the contents of a mime.types file.
+///
+/// Each entry is a MIME type followed by its extensions, as in
+/// "text/html html htm". This accepts both Apache's format, with one entry
+/// per line and "#" comments, and nginx's, where the entries are inside a
+/// "types { ... }" block and end with ";".
+fn parse_mime_types(contents: &str) -> Result<HashMap<String, Mime>, String> {
+    let mut by_ext = HashMap::new();
+
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        for entry in line.split(';') {
+            let mut words = entry
+                .split_whitespace()
+                .filter(|w| !["types", "{", "}"].contains(w));
+            let mime = match words.next() {
+                Some(mime) => mime,
+                None => continue,
+            };
+            let mime: Mime = mime
+                .parse()
+                .map_err(|_| format!("invalid MIME type '{}'", mime))?;
+
+            for ext in words {
+                by_ext.insert(ext.to_ascii_lowercase(), mime.clone());
+            }
+        }
+    }
+
+    Ok(by_ext)
+}
@@ -0,0 +1,142 @@
+//! Serving files from memory maps, with `--mmap`, for large files on machines
+//! whose page cache holds them.
+//!
+//! A streamed file is read a chunk at a time on tokio's blocking threads,
+//! while a mapped file is copied straight out of the page cache a chunk at a
+//! time, on a blocking thread too. Only one chunk of a response is copied at
+//! once, and nothing is copied until the body is sent, so a response that
+//! turns out to be 304 Not Modified costs only the map.
+//!
+//! Reading a mapped file past its end crashes the server with SIGBUS if the
+//! file has been truncated, which editors do when saving, so the map is never
+//! read by the server itself. On Linux it's copied by the kernel, with
+//! `process_vm_readv`, which fails instead of crashing, and the response is
+//! cut short. On Windows files can't be truncated while they're mapped, so
+//! they're copied directly. Elsewhere, and where `process_vm_readv` isn't
+//! allowed, as in some containers, files are always read as usual.
+
+use bytes::Bytes;
+use futures::stream;
+use hyper::Body;
+use memmap2::Mmap;
+use std::fs::{File, Metadata};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio_executor::blocking;
+use tracing::debug;
+
+/// How much of a map is copied at a time.
+const CHUNK_LEN: usize = 256 * 1024;
+
+/// Map a file, returning its metadata and a body that copies it out of the
+/// map as it is sent, or `None` if it should be read instead.
+pub async fn file_body(path: PathBuf) -> io::Result<Option<(Metadata, Body)>> {
+    if !can_copy_map() {
+        return Ok(None);
+    }
+
+    let (meta, map) = blocking::run(move || -> io::Result<_> {
+        let file = File::open(&path)?;
+        let meta = file.metadata()?;
+        if meta.len() == 0 {
+            // Empty files can't be mapped.
+            return Ok((meta, None));
+        }
+        // Safe since the map is only read by `copy_map`.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok((meta, Some(Arc::new(map))))
+    })
+    .await?;
+
+    let map = match map {
+        Some(map) => map,
+        None => return Ok(Some((meta, Body::empty()))),
+    };
+
+    let chunks = stream::unfold(0, move |start| {
+        let map = map.clone();
+        async move {
+            if start >= map.len() {
+                return None;
+            }
+            let end = map.len().min(start + CHUNK_LEN);
+            let chunk = blocking::run(move || {
+                copy_map(&map[start..end]).ok_or_else(|| {
+                    debug!("couldn't copy from a memory map at {}", start);
+                    io::Error::new(io::ErrorKind::UnexpectedEof, "mapped file was truncated")
+                })
+            })
+            .await;
+            Some((chunk.map(Bytes::from), end))
+        }
+    });
+    Ok(Some((meta, Body::wrap_stream(chunks))))
+}
+
+/// Whether maps can be copied here, which on Linux is checked once by
+/// copying some of the server's own memory.
+#[cfg(target_os = "linux")]
+fn can_copy_map() -> bool {
+    use std::sync::OnceLock;
+
+    static CAN_COPY: OnceLock<bool> = OnceLock::new();
+    *CAN_COPY.get_or_init(|| {
+        let probe = [1u8; 16];
+        let can_copy = copy_map(&probe).is_some();
+        if !can_copy {
+            debug!("process_vm_readv isn't allowed, so --mmap reads files");
+        }
+        can_copy
+    })
+}
+
+#[cfg(windows)]
+fn can_copy_map() -> bool {
+    true
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn can_copy_map() -> bool {
+    false
+}
+
+/// Copy part of a map with the kernel, which returns what it copied before
+/// reaching any part of the file that was truncated, rather than raising
+/// SIGBUS.
+#[cfg(target_os = "linux")]
+fn copy_map(map: &[u8]) -> Option<Vec<u8>> {
+    let mut data = vec![0; map.len()];
+    let mut done = 0;
+    while done < map.len() {
+        let len = map.len() - done;
+        let local = libc::iovec {
+            iov_base: data[done..].as_mut_ptr() as *mut libc::c_void,
+            iov_len: len,
+        };
+        let remote = libc::iovec {
+            iov_base: map[done..].as_ptr() as *mut libc::c_void,
+            iov_len: len,
+        };
+        // Fails, rather than copying less, when the file was truncated at
+        // `done`, or when the call isn't allowed, as in some containers.
+        let copied = unsafe { libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0) };
+        if copied <= 0 {
+            return None;
+        }
+        done += copied as usize;
+    }
+    Some(data)
+}
+
+/// Copy part of a map directly, since Windows doesn't let mapped files be
+/// truncated.
+#[cfg(windows)]
+fn copy_map(map: &[u8]) -> Option<Vec<u8>> {
+    Some(map.to_vec())
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn copy_map(_map: &[u8]) -> Option<Vec<u8>> {
+    None
+}
@@ -0,0 +1,42 @@
+//! Overlaid root directories, with `--fallback-root`, so that a request for a
+//! file that isn't in the root directory is served from the first fallback
+//! that has it, like a theme directory under a project's overrides.
+//!
+//! A request is served entirely from the one directory it resolves in, so a
+//! directory listing only shows that directory's files.
+
+use super::Config;
+use hyper::{Body, Request};
+use std::path::Path;
+use tracing::trace;
+
+/// The configuration for a request, with the first root directory that has
+/// the requested file in place of the root directory.
+///
+/// If no root has the file, the root directory is kept, so that the error
+/// comes from it.
+pub fn resolve(mut config: Config, req: &Request<Body>) -> Config {
+    if config.fallback_roots.is_empty() || exists_in(&config.root_dir, req) {
+        return config;
+    }
+
+    let root_dir = config
+        .fallback_roots
+        .iter()
+        .find(|root| exists_in(root, req))
+        .cloned();
+
+    if let Some(root_dir) = root_dir {
+        trace!("serving {} from {}", req.uri(), root_dir.display());
+        config.root_dir = root_dir;
+    }
+
+    config
+}
+
+/// Whether the requested file, or the index of the requested directory, is in
+/// a root directory.
+fn exists_in(root_dir: &Path, req: &Request<Body>) -> bool {
+    super::local_path_with_maybe_index(req.uri(), root_dir)
+        .is_ok_and(|path| super::meta_cache::exists(&path))
+}
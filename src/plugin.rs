@@ -0,0 +1,362 @@
+//! WebAssembly plugins, with `--plugin FILE.wasm`, for handling requests in
+//! ways the server doesn't know about, without rebuilding it. This needs the
+//! "wasm" cargo feature.
+//!
+//! A plugin is a module that exports its `memory` and two functions:
+//!
+//! - `alloc(len: i32) -> i32`, returning where the server may write `len`
+//!   bytes;
+//! - `handle(ptr: i32, len: i32) -> i64`, given the request as JSON, such as
+//!   `{"method": "GET", "path": "/a", "query": "b=1", "headers": {...}}`,
+//!   and returning where its response is, as `ptr << 32 | len`, or 0 to let
+//!   the server handle the request.
+//!
+//! The response is JSON too, as `{"status": 200, "headers": {...}, "body":
+//! "..."}`. Plugins are tried in order, each request gets a new instance, and
+//! a request may use up to `FUEL` instructions.
+//!
+//! Plugins built for WASI get enough of it to run: their output to stdout and
+//! stderr is logged, and they see no arguments or environment. Other WASI
+//! calls fail.
+
+use super::{Config, Error, Hook, Result};
+use futures::future::{BoxFuture, FutureExt};
+use hyper::{Body, Request, Response};
+use std::error::Error as StdError;
+use std::fmt;
+
+#[cfg(feature = "wasm")]
+use {
+    http::header::{HeaderName, HeaderValue},
+    serde::Deserialize,
+    serde_json::json,
+    std::collections::HashMap,
+    std::convert::TryFrom,
+    std::fs,
+    std::path::PathBuf,
+    std::sync::Arc,
+    std::time::SystemTime,
+    tokio_executor::blocking,
+    tracing::{debug, info},
+    wasmi::{Caller, Engine, Extern, ExternType, Linker, Module, Store},
+};
+
+/// The instructions a plugin may run for a request, so that a plugin stuck in
+/// a loop can't hang the server.
+#[cfg(feature = "wasm")]
+const FUEL: u64 = 100_000_000;
+
+/// The largest response a plugin may return.
+#[cfg(feature = "wasm")]
+const MAX_RESPONSE_LEN: usize = 16 * 1024 * 1024;
+
+/// A plugin that failed to load or to handle a request.
+#[derive(Debug)]
+pub struct PluginError(String);
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl StdError for PluginError {}
+
+/// The loaded plugins, shared by every request.
+#[derive(Clone)]
+pub struct Plugins {
+    #[cfg(feature = "wasm")]
+    plugins: Arc<Vec<Plugin>>,
+}
+
+#[cfg(not(feature = "wasm"))]
+impl Plugins {
+    pub fn load(config: &Config) -> Result<Plugins> {
+        if config.plugin.is_empty() {
+            Ok(Plugins {})
+        } else {
+            Err(Error::Plugin(PluginError(
+                "--plugin needs the \"wasm\" cargo feature".to_string(),
+            )))
+        }
+    }
+
+    pub async fn handle(&self, _req: &Request<Body>) -> Result<Option<Response<Body>>> {
+        Ok(None)
+    }
+}
+
+/// The plugins, as the hook run before serving requests.
+impl Hook for Plugins {
+    fn before<'a>(
+        &'a self,
+        _config: &'a Config,
+        req: &'a mut Request<Body>,
+    ) -> BoxFuture<'a, Result<Option<Response<Body>>>> {
+        self.handle(req).boxed()
+    }
+}
+
+#[cfg(feature = "wasm")]
+struct Plugin {
+    path: PathBuf,
+    module: Module,
+    linker: Linker<()>,
+}
+
+#[cfg(feature = "wasm")]
+#[derive(Deserialize)]
+struct PluginResponse {
+    #[serde(default = "default_status")]
+    status: u16,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(default)]
+    body: String,
+}
+
+#[cfg(feature = "wasm")]
+fn default_status() -> u16 {
+    200
+}
+
+#[cfg(feature = "wasm")]
+impl Plugins {
+    /// Compile the plugins from `--plugin`.
+    pub fn load(config: &Config) -> Result<Plugins> {
+        let mut engine_config = wasmi::Config::default();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config);
+
+        let plugins = config
+            .plugin
+            .iter()
+            .map(|path| {
+                let err = |e: &dyn fmt::Display| {
+                    Error::Plugin(PluginError(format!("{}: {}", path.display(), e)))
+                };
+                let wasm = fs::read(path).map_err(|e| err(&e))?;
+                let module = Module::new(&engine, &wasm).map_err(|e| err(&e))?;
+                let linker = linker(&engine, &module).map_err(|e| err(&e))?;
+                info!("plugin: {}", path.display());
+                Ok(Plugin {
+                    path: path.clone(),
+                    module,
+                    linker,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Plugins {
+            plugins: Arc::new(plugins),
+        })
+    }
+
+    /// Give a request to each plugin in turn, until one responds. Plugins run
+    /// on a blocking thread, since one can take a while to use up its fuel.
+    pub async fn handle(&self, req: &Request<Body>) -> Result<Option<Response<Body>>> {
+        if self.plugins.is_empty() {
+            return Ok(None);
+        }
+
+        let mut headers = serde_json::Map::new();
+        for (name, value) in req.headers() {
+            if let Ok(value) = value.to_str() {
+                headers.insert(name.as_str().to_string(), value.into());
+            }
+        }
+        let request = json!({
+            "method": req.method().as_str(),
+            "path": req.uri().path(),
+            "query": req.uri().query(),
+            "headers": headers,
+        })
+        .to_string();
+
+        let plugins = self.plugins.clone();
+        let uri = req.uri().clone();
+        blocking::run(move || {
+            for plugin in plugins.iter() {
+                let response = plugin.call(request.as_bytes()).map_err(|e| {
+                    Error::Plugin(PluginError(format!("{}: {}", plugin.path.display(), e)))
+                })?;
+                if let Some(response) = response {
+                    debug!("{} handled by {}", uri, plugin.path.display());
+                    return make_response(response).map(Some);
+                }
+            }
+            Ok(None)
+        })
+        .await
+    }
+}
+
+#[cfg(feature = "wasm")]
+impl Plugin {
+    /// Run the plugin's `handle` on a request, in a new instance.
+    fn call(&self, request: &[u8]) -> std::result::Result<Option<PluginResponse>, wasmi::Error> {
+        let mut store = Store::new(self.module.engine(), ());
+        store.set_fuel(FUEL)?;
+        let instance = self
+            .linker
+            .instantiate(&mut store, &self.module)?
+            .start(&mut store)?;
+
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| wasmi::Error::new("no exported memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&store, "alloc")?;
+        let handle = instance.get_typed_func::<(i32, i32), i64>(&store, "handle")?;
+
+        let len = i32::try_from(request.len()).map_err(|_| wasmi::Error::new("request too big"))?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as u32 as usize, request)?;
+
+        let result = handle.call(&mut store, (ptr, len))? as u64;
+        if result == 0 {
+            return Ok(None);
+        }
+
+        let (ptr, len) = ((result >> 32) as usize, (result & 0xffff_ffff) as usize);
+        if len > MAX_RESPONSE_LEN {
+            return Err(wasmi::Error::new("response too big"));
+        }
+        let mut response = vec![0; len];
+        memory.read(&store, ptr, &mut response)?;
+
+        serde_json::from_slice(&response)
+            .map(Some)
+            .map_err(|e| wasmi::Error::new(format!("invalid response: {}", e)))
+    }
+}
+
+#[cfg(feature = "wasm")]
+fn make_response(plugin_resp: PluginResponse) -> Result<Response<Body>> {
+    let invalid = |what: &str| Error::Plugin(PluginError(format!("invalid response {}", what)));
+
+    let mut resp = Response::builder();
+    resp.status(plugin_resp.status);
+    for (name, value) in &plugin_resp.headers {
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid("header name"))?;
+        let value = HeaderValue::from_str(value).map_err(|_| invalid("header value"))?;
+        resp.header(name, value);
+    }
+    resp.body(Body::from(plugin_resp.body))
+        .map_err(|_| invalid("status"))
+}
+
+/// The imports a plugin can use: the bits of WASI that programs need to
+/// start and print, and failing stand-ins for everything else it imports.
+#[cfg(feature = "wasm")]
+fn linker(engine: &Engine, module: &Module) -> std::result::Result<Linker<()>, wasmi::Error> {
+    const WASI: &str = "wasi_snapshot_preview1";
+    const SUPPORTED_WASI: &[&str] = &[
+        "fd_write",
+        "proc_exit",
+        "args_sizes_get",
+        "environ_sizes_get",
+        "args_get",
+        "environ_get",
+        "clock_time_get",
+    ];
+    let mut linker = Linker::new(engine);
+
+    linker.func_wrap(WASI, "fd_write", wasi_fd_write)?;
+    linker.func_wrap(
+        WASI,
+        "proc_exit",
+        |code: i32| -> std::result::Result<(), wasmi::Error> { Err(wasmi::Error::i32_exit(code)) },
+    )?;
+    for name in &["args_sizes_get", "environ_sizes_get"] {
+        linker.func_wrap(
+            WASI,
+            name,
+            |mut caller: Caller<()>, count: i32, size: i32| {
+                write_u32s(&mut caller, &[(count, 0), (size, 0)])
+            },
+        )?;
+    }
+    for name in &["args_get", "environ_get"] {
+        linker.func_wrap(WASI, name, |_: i32, _: i32| 0)?;
+    }
+    linker.func_wrap(
+        WASI,
+        "clock_time_get",
+        |mut caller: Caller<()>, _id: i32, _precision: i64, time: i32| {
+            let nanos = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64);
+            let memory = memory(&caller)?;
+            memory.write(&mut caller, time as u32 as usize, &nanos.to_le_bytes())?;
+            Ok(0)
+        },
+    )?;
+
+    for import in module.imports() {
+        if let ExternType::Func(ty) = import.ty() {
+            let supported = import.module() == WASI && SUPPORTED_WASI.contains(&import.name());
+            if !supported {
+                let name = format!("{}::{}", import.module(), import.name());
+                linker.func_new(
+                    import.module(),
+                    import.name(),
+                    ty.clone(),
+                    move |_, _, _| Err(wasmi::Error::new(format!("{} isn't supported", name))),
+                )?;
+            }
+        }
+    }
+
+    Ok(linker)
+}
+
+/// WASI's `fd_write`, logging what's written to stdout and stderr.
+#[cfg(feature = "wasm")]
+fn wasi_fd_write(
+    mut caller: Caller<()>,
+    fd: i32,
+    iovs: i32,
+    iovs_len: i32,
+    nwritten: i32,
+) -> std::result::Result<i32, wasmi::Error> {
+    const EBADF: i32 = 8;
+    if fd != 1 && fd != 2 {
+        return Ok(EBADF);
+    }
+
+    let memory = memory(&caller)?;
+    let mut output = Vec::new();
+    for i in 0..iovs_len as u32 as usize {
+        let mut iov = [0; 8];
+        memory.read(&caller, iovs as u32 as usize + i * 8, &mut iov)?;
+        let ptr = u32::from_le_bytes([iov[0], iov[1], iov[2], iov[3]]) as usize;
+        let len = u32::from_le_bytes([iov[4], iov[5], iov[6], iov[7]]) as usize;
+        let start = output.len();
+        output.resize(start + len, 0);
+        memory.read(&caller, ptr, &mut output[start..])?;
+    }
+
+    info!("plugin: {}", String::from_utf8_lossy(&output).trim_end());
+    write_u32s(&mut caller, &[(nwritten, output.len() as u32)])
+}
+
+#[cfg(feature = "wasm")]
+fn memory(caller: &Caller<()>) -> std::result::Result<wasmi::Memory, wasmi::Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("no exported memory"))
+}
+
+/// Write little-endian numbers to a plugin's memory, returning WASI's success.
+#[cfg(feature = "wasm")]
+fn write_u32s(
+    caller: &mut Caller<()>,
+    values: &[(i32, u32)],
+) -> std::result::Result<i32, wasmi::Error> {
+    let memory = memory(caller)?;
+    for (ptr, value) in values {
+        memory.write(&mut *caller, *ptr as u32 as usize, &value.to_le_bytes())?;
+    }
+    Ok(0)
+}
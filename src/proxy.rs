@@ -0,0 +1,490 @@
+//! Forwarding requests to upstream servers, with `--proxy PREFIX=URL`.
+//!
+//! This lets the server front a single-page app and its backend during
+//! development. Request and response bodies are streamed through in both
+//! directions, and WebSocket connections are tunneled to the upstream server,
+//! for hot module reloading and realtime APIs.
+//!
+//! A route may have several upstream servers, which take turns. Upstream
+//! servers that can't be connected to are skipped until a health check finds
+//! them back up.
+
+use super::proxy_cache::{self, ProxyCache};
+use super::{Config, ConnInfo, Error, Result};
+use futures::future;
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use http::{Request, Response, StatusCode, Uri, Version};
+use hyper::client::HttpConnector;
+use hyper::upgrade::OnUpgrade;
+use hyper::{Body, Client};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::future::FutureExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::timer::delay_for;
+use tracing::{debug, info, warn};
+
+/// How often to check whether upstream servers are up.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait for an upstream server to accept a health check
+/// connection.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A path prefix and the upstream servers that requests under it are sent
+/// to, from the `--proxy PREFIX=URL[,URL...]` option.
+#[derive(Clone, Debug)]
+pub struct ProxyRoute {
+    /// The prefix without a trailing "/", so "/" is the empty string.
+    prefix: String,
+    upstreams: Arc<Vec<Upstream>>,
+    /// The round-robin counter, shared by all requests.
+    next: Arc<AtomicUsize>,
+}
+
+/// An upstream server of a route.
+#[derive(Debug)]
+struct Upstream {
+    uri: Uri,
+    healthy: AtomicBool,
+}
+
+impl Upstream {
+    fn set_healthy(&self, healthy: bool) {
+        let was_healthy = self.healthy.swap(healthy, Ordering::Relaxed);
+        if healthy && !was_healthy {
+            info!("upstream {} is up", self.uri);
+        } else if !healthy && was_healthy {
+            warn!("upstream {} is down", self.uri);
+        }
+    }
+
+    /// Whether the upstream server accepts connections.
+    async fn check(&self) -> bool {
+        let authority = match self.uri.authority_part() {
+            Some(authority) => authority,
+            None => return false,
+        };
+        let addr = (authority.host(), authority.port_u16().unwrap_or(80));
+
+        match TcpStream::connect(addr).timeout(HEALTH_CHECK_TIMEOUT).await {
+            Ok(Ok(_)) => true,
+            Ok(Err(e)) => {
+                debug!("health check of {} failed: {}", self.uri, e);
+                false
+            }
+            Err(_) => {
+                debug!("health check of {} timed out", self.uri);
+                false
+            }
+        }
+    }
+}
+
+impl FromStr for ProxyRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<ProxyRoute, String> {
+        let mut parts = s.splitn(2, '=');
+        let prefix = parts.next().unwrap_or("");
+        let urls = parts.next().unwrap_or("");
+
+        if !prefix.starts_with('/') || urls.is_empty() {
+            return Err(format!("expected PREFIX=URL[,URL...], found '{}'", s));
+        }
+
+        let mut upstreams = Vec::new();
+        for url in urls.split(',').map(str::trim) {
+            let uri: Uri = url
+                .parse()
+                .map_err(|e| format!("invalid URL '{}': {}", url, e))?;
+            if uri.scheme_str() != Some("http") || uri.authority_part().is_none() {
+                return Err(format!("expected an http:// URL, found '{}'", url));
+            }
+            upstreams.push(Upstream {
+                uri,
+                healthy: AtomicBool::new(true),
+            });
+        }
+
+        Ok(ProxyRoute {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            upstreams: Arc::new(upstreams),
+            next: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+}
+
+impl ProxyRoute {
+    /// Whether a request path is the prefix or under it.
+    fn matches(&self, path: &str) -> bool {
+        path.starts_with(&self.prefix) && {
+            let rest = &path[self.prefix.len()..];
+            rest.is_empty() || rest.starts_with('/')
+        }
+    }
+
+    /// Pick the next healthy upstream server in turn. If none are healthy,
+    /// they are tried anyway.
+    fn pick_upstream(&self) -> &Upstream {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.upstreams.len();
+
+        (0..count)
+            .map(|i| &self.upstreams[(start + i) % count])
+            .find(|upstream| upstream.healthy.load(Ordering::Relaxed))
+            .unwrap_or(&self.upstreams[start % count])
+    }
+}
+
+impl Upstream {
+    /// The URI to request from upstream. The whole request path is kept,
+    /// after the path of the upstream URL if it has one.
+    fn request_uri(&self, uri: &Uri) -> Result<Uri> {
+        let base = self.uri.path().trim_end_matches('/');
+        let path_and_query = uri.path_and_query().map_or("/", |pq| pq.as_str());
+
+        let mut builder = Uri::builder();
+        builder.scheme("http");
+        if let Some(authority) = self.uri.authority_part() {
+            builder.authority(authority.clone());
+        }
+        builder.path_and_query(format!("{}{}", base, path_and_query).as_str());
+        builder.build().map_err(Error::from)
+    }
+}
+
+/// Find the route for a request, preferring the longest matching prefix.
+pub fn find_route<'a>(config: &'a Config, uri: &Uri) -> Option<&'a ProxyRoute> {
+    config
+        .proxy
+        .iter()
+        .filter(|route| route.matches(uri.path()))
+        .max_by_key(|route| route.prefix.len())
+}
+
+/// Forwards requests to upstream servers.
+///
+/// One client is shared by all requests, so connections to upstream servers
+/// are reused.
+#[derive(Clone)]
+pub struct Proxy {
+    client: Client<HttpConnector>,
+    cache: Option<ProxyCache>,
+}
+
+impl Proxy {
+    /// Create the proxy, if any `--proxy` routes are configured.
+    pub fn new(config: &Config) -> Result<Option<Proxy>> {
+        if config.proxy.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Proxy {
+            client: Client::new(),
+            cache: ProxyCache::open(config)?,
+        }))
+    }
+
+    /// Send a request upstream and return its response, or a 502 Bad Gateway
+    /// error page if the upstream server can't be reached.
+    pub async fn forward(
+        &self,
+        config: &Config,
+        conn: ConnInfo,
+        route: &ProxyRoute,
+        req: Request<Body>,
+    ) -> Result<Response<Body>> {
+        let upstream = route.pick_upstream();
+        let uri = upstream.request_uri(req.uri())?;
+        debug!("proxying {} to {}", req.uri(), uri);
+
+        let websocket = is_websocket_upgrade(&req);
+
+        let (mut parts, body) = req.into_parts();
+        // HTTP/2 requests carry the host in the URI instead.
+        let host = parts.headers.remove(header::HOST).or_else(|| {
+            let authority = parts.uri.authority_part()?;
+            HeaderValue::from_str(authority.as_str()).ok()
+        });
+        remove_hop_by_hop_headers(&mut parts.headers);
+        if websocket {
+            add_websocket_upgrade_headers(&mut parts.headers);
+        }
+        add_forwarded_headers(config, &mut parts.headers, conn, host);
+        parts.uri = uri.clone();
+        // Clients may talk HTTP/2 to us, but upstream gets HTTP/1.1.
+        let version = parts.version;
+        parts.version = Version::HTTP_11;
+
+        // Answer from the cache if the response there is fresh, and otherwise
+        // ask upstream whether it has changed.
+        let cache = self
+            .cache
+            .as_ref()
+            .filter(|_| !websocket && proxy_cache::is_cacheable_request(&parts));
+        let cached = match cache {
+            Some(cache) => cache.lookup(&uri).await,
+            None => None,
+        };
+        let cached = match cached {
+            Some(cached) if cached.is_fresh(&parts.headers) => {
+                debug!("cache hit for {}", uri);
+                return cached.response(version, "HIT").await;
+            }
+            Some(cached) => {
+                cached.add_validators(&mut parts.headers);
+                Some(cached)
+            }
+            None => None,
+        };
+        if cache.is_some() {
+            // Cached bodies are stored unencoded, and compressed on the way
+            // out.
+            parts.headers.remove(header::ACCEPT_ENCODING);
+        }
+
+        // An upgrade request has no body, and the client's connection is
+        // taken over once the upgrade is answered.
+        let (body, client_upgrade) = if websocket {
+            (Body::empty(), Some(body.on_upgrade()))
+        } else {
+            (body, None)
+        };
+
+        match self.client.request(Request::from_parts(parts, body)).await {
+            Ok(resp) => {
+                let (mut parts, body) = resp.into_parts();
+                parts.version = version;
+
+                if let Some(client_upgrade) = client_upgrade {
+                    if parts.status == StatusCode::SWITCHING_PROTOCOLS {
+                        debug!("tunneling websocket to {}", uri);
+                        tokio::spawn(tunnel(client_upgrade, body.on_upgrade()));
+                        remove_hop_by_hop_headers(&mut parts.headers);
+                        add_websocket_upgrade_headers(&mut parts.headers);
+                        return Ok(Response::from_parts(parts, Body::empty()));
+                    }
+                }
+
+                remove_hop_by_hop_headers(&mut parts.headers);
+
+                match (cache, cached) {
+                    (Some(cache), Some(cached)) if parts.status == StatusCode::NOT_MODIFIED => {
+                        debug!("cached {} not modified", uri);
+                        let cached = cache.refresh(cached, &parts.headers).await;
+                        cached.response(version, "REVALIDATED").await
+                    }
+                    (Some(cache), _) => Ok(cache.store(&uri, Response::from_parts(parts, body))),
+                    (None, _) => Ok(Response::from_parts(parts, body)),
+                }
+            }
+            Err(e) => {
+                warn!("proxy error for {}: {}", uri, e);
+                if e.is_connect() {
+                    upstream.set_healthy(false);
+                }
+
+                // Fall back to a stale cached response, to work offline.
+                if let Some(cached) = cached {
+                    debug!("serving stale {} from cache", uri);
+                    return cached.response(version, "STALE").await;
+                }
+
+                super::make_error_response_from_code(config, StatusCode::BAD_GATEWAY)
+            }
+        }
+    }
+}
+
+/// Check the upstream servers of routes with more than one, forever, so that
+/// requests skip the ones that are down.
+pub async fn check_health(config: Config) {
+    let upstreams: Vec<&Upstream> = config
+        .proxy
+        .iter()
+        .filter(|route| route.upstreams.len() > 1)
+        .flat_map(|route| route.upstreams.iter())
+        .collect();
+
+    if upstreams.is_empty() {
+        return;
+    }
+
+    loop {
+        for upstream in &upstreams {
+            upstream.set_healthy(upstream.check().await);
+        }
+
+        delay_for(HEALTH_CHECK_INTERVAL).await;
+    }
+}
+
+/// Whether a request asks to upgrade its connection to a WebSocket.
+///
+/// Only HTTP/1.1 has upgrades; HTTP/2 WebSockets aren't supported.
+fn is_websocket_upgrade(req: &Request<Body>) -> bool {
+    req.version() == Version::HTTP_11
+        && req
+            .headers()
+            .get(header::UPGRADE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| {
+                v.split(',')
+                    .any(|p| p.trim().eq_ignore_ascii_case("websocket"))
+            })
+}
+
+/// Put back the headers for a WebSocket upgrade, after the hop-by-hop headers
+/// are removed.
+fn add_websocket_upgrade_headers(headers: &mut HeaderMap) {
+    headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+}
+
+/// Copy bytes both ways between the upgraded client and upstream
+/// connections, until both sides are done.
+async fn tunnel(client: OnUpgrade, upstream: OnUpgrade) {
+    let (client, upstream) = match future::try_join(client, upstream).await {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+            warn!("websocket upgrade error: {}", e);
+            return;
+        }
+    };
+
+    let (client_read, client_write) = tokio::io::split(client);
+    let (upstream_read, upstream_write) = tokio::io::split(upstream);
+
+    let (to_upstream, to_client) = future::join(
+        copy_and_shutdown(client_read, upstream_write),
+        copy_and_shutdown(upstream_read, client_write),
+    )
+    .await;
+
+    match (to_upstream, to_client) {
+        (Ok(sent), Ok(received)) => {
+            debug!(
+                "websocket closed, {} bytes sent, {} received",
+                sent, received
+            )
+        }
+        (Err(e), _) | (_, Err(e)) => debug!("websocket error: {}", e),
+    }
+}
+
+/// Copy one direction of a tunnel, then close the writing side so the other
+/// end sees the end of the stream.
+async fn copy_and_shutdown<R, W>(mut reader: R, mut writer: W) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let n = reader.copy(&mut writer).await?;
+    writer.shutdown().await?;
+    Ok(n)
+}
+
+/// Headers that only apply to a single connection, and must not be
+/// forwarded.
+///
+/// https://tools.ietf.org/html/rfc7230#section-6.1
+static HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Remove the hop-by-hop headers, including those named by `Connection`.
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let named: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| name.trim().parse().ok())
+        .collect();
+    for name in named {
+        headers.remove(name);
+    }
+
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+}
+
+/// Tell the upstream server about the original request, with the de facto
+/// standard `X-Forwarded-*` headers, and the standard `Forwarded` header if
+/// `--proxy-forwarded` is given.
+///
+/// Values from proxies in front of this one are added to, not replaced.
+fn add_forwarded_headers(
+    config: &Config,
+    headers: &mut HeaderMap,
+    conn: ConnInfo,
+    host: Option<HeaderValue>,
+) {
+    let proto = if conn.tls { "https" } else { "http" };
+
+    if config.proxy_forwarded {
+        let element = forwarded_element(conn, proto, host.as_ref());
+        let forwarded = match headers.get(header::FORWARDED).and_then(|v| v.to_str().ok()) {
+            Some(prev) => format!("{}, {}", prev, element),
+            None => element,
+        };
+        if let Ok(value) = HeaderValue::from_str(&forwarded) {
+            headers.insert(header::FORWARDED, value);
+        }
+    }
+
+    if let Some(addr) = conn.proxied_addr.or(conn.peer_addr) {
+        let ip = addr.ip().to_string();
+        let forwarded_for = match headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+            Some(prev) => format!("{}, {}", prev, ip),
+            None => ip,
+        };
+        if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+            headers.insert("x-forwarded-for", value);
+        }
+    }
+
+    headers.insert("x-forwarded-proto", HeaderValue::from_static(proto));
+
+    if let Some(host) = host {
+        headers.insert("x-forwarded-host", host);
+    }
+}
+
+/// Make this proxy's element of a `Forwarded` header, like
+/// `for=192.0.2.60;proto=http;host="example.com:4000"`.
+///
+/// https://tools.ietf.org/html/rfc7239#section-4
+fn forwarded_element(conn: ConnInfo, proto: &str, host: Option<&HeaderValue>) -> String {
+    let mut pairs = Vec::new();
+
+    // IPv6 addresses contain ":", so must be quoted, and in brackets.
+    let node = match conn.proxied_addr.or(conn.peer_addr).map(|addr| addr.ip()) {
+        Some(IpAddr::V4(ip)) => ip.to_string(),
+        Some(IpAddr::V6(ip)) => format!("\"[{}]\"", ip),
+        None => "unknown".to_string(),
+    };
+    pairs.push(format!("for={}", node));
+    pairs.push(format!("proto={}", proto));
+
+    if let Some(host) = host.and_then(|h| h.to_str().ok()) {
+        let host = host.replace('\\', "\\\\").replace('"', "\\\"");
+        pairs.push(format!("host=\"{}\"", host));
+    }
+
+    pairs.join(";")
+}
@@ -0,0 +1,502 @@
+//! Caching proxied responses on disk, with `--proxy-cache DIR`.
+//!
+//! Successful responses to GET requests are stored unless their headers say
+//! not to, and are served again while they are fresh according to their
+//! `Cache-Control` or `Expires` headers. Stale responses are revalidated
+//! upstream with their `ETag` or `Last-Modified`, and are served anyway if the
+//! upstream server can't be reached, so the cache works as a simple offline
+//! mirror.
+//!
+//! Each response is kept as two files named by a hash of its upstream URL:
+//! `.meta`, with the URL, the time it was stored, the status and headers, and
+//! `.body`. They are read and written on tokio's blocking threads, or with
+//! tokio's files.
+
+use super::{Config, Error, Result};
+use bytes::BytesMut;
+use chrono::DateTime;
+use futures::StreamExt;
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use http::request::Parts;
+use http::{Method, Response, StatusCode, Uri, Version};
+use hyper::body::Sender;
+use hyper::Body;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::codec::{BytesCodec, FramedRead};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio_executor::blocking;
+use tracing::{debug, warn};
+
+/// The longest time a response without explicit freshness is considered
+/// fresh, based on its `Last-Modified` header.
+const MAX_HEURISTIC_FRESHNESS: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The header telling clients how the cache handled a response.
+static X_CACHE: &str = "x-cache";
+
+/// Counts temporary files, to give each a unique name.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// The cache directory.
+#[derive(Clone)]
+pub struct ProxyCache {
+    dir: PathBuf,
+}
+
+impl ProxyCache {
+    /// Open the cache directory, if `--proxy-cache` is given, creating it if
+    /// needed.
+    pub fn open(config: &Config) -> Result<Option<ProxyCache>> {
+        let dir = match &config.proxy_cache {
+            Some(dir) => dir.clone(),
+            None => return Ok(None),
+        };
+
+        fs::create_dir_all(&dir).map_err(Error::ProxyCacheDir)?;
+
+        Ok(Some(ProxyCache { dir }))
+    }
+
+    /// The paths of the metadata and body files for an upstream URL.
+    fn paths(&self, uri: &Uri) -> (PathBuf, PathBuf) {
+        let key = format!("{:016x}", fnv1a(uri.to_string().as_bytes()));
+        (
+            self.dir.join(format!("{}.meta", key)),
+            self.dir.join(format!("{}.body", key)),
+        )
+    }
+
+    /// A new temporary file path in the cache directory.
+    fn temp_path(&self) -> PathBuf {
+        let n = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        self.dir.join(format!("{}-{}.tmp", std::process::id(), n))
+    }
+
+    /// Find the cached response for an upstream URL.
+    pub async fn lookup(&self, uri: &Uri) -> Option<Cached> {
+        let (meta_path, body_path) = self.paths(uri);
+        let read_path = meta_path.clone();
+        let meta = match blocking::run(move || fs::read_to_string(read_path)).await {
+            Ok(meta) => meta,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("unable to read {}: {}", meta_path.display(), e);
+                return None;
+            }
+        };
+
+        let cached = parse_meta(&meta, body_path);
+        if cached.is_none() {
+            warn!("ignoring invalid cache entry {}", meta_path.display());
+        }
+        // Different URLs could have the same hash.
+        cached.filter(|cached| cached.uri == uri.to_string())
+    }
+
+    /// Update a cached response with the headers of a 304 Not Modified
+    /// response, and mark it as stored now.
+    pub async fn refresh(&self, mut cached: Cached, not_modified: &HeaderMap) -> Cached {
+        for (name, value) in not_modified {
+            if name != header::CONTENT_LENGTH {
+                cached.headers.insert(name.clone(), value.clone());
+            }
+        }
+        cached.stored = SystemTime::now();
+
+        if let Ok(uri) = cached.uri.parse() {
+            let (meta_path, _) = self.paths(&uri);
+            let temp_path = self.temp_path();
+            let meta = format_meta(&cached);
+            blocking::run(move || {
+                let written =
+                    fs::write(&temp_path, meta).and_then(|_| fs::rename(&temp_path, &meta_path));
+                if let Err(e) = written {
+                    warn!("unable to update {}: {}", meta_path.display(), e);
+                    let _ = fs::remove_file(&temp_path);
+                }
+            })
+            .await;
+        }
+
+        cached
+    }
+
+    /// Store a response from upstream as it is streamed to the client, if it
+    /// may be cached.
+    ///
+    /// The request must be one that `is_cacheable_request` allows.
+    pub fn store(&self, uri: &Uri, resp: Response<Body>) -> Response<Body> {
+        let (mut parts, body) = resp.into_parts();
+        parts
+            .headers
+            .insert(X_CACHE, HeaderValue::from_static("MISS"));
+
+        if !is_storable(parts.status, &parts.headers) {
+            return Response::from_parts(parts, body);
+        }
+
+        let (meta_path, body_path) = self.paths(uri);
+        let cached = Cached {
+            uri: uri.to_string(),
+            stored: SystemTime::now(),
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body_path,
+        };
+        let store = Store {
+            cache: self.clone(),
+            temp_path: self.temp_path(),
+            meta_path,
+            expected_len: content_length(&parts.headers),
+            cached,
+        };
+
+        let (sender, store_body) = Body::channel();
+        tokio::spawn(store.run(body, sender));
+
+        Response::from_parts(parts, store_body)
+    }
+}
+
+/// A response in the cache.
+pub struct Cached {
+    uri: String,
+    stored: SystemTime,
+    status: StatusCode,
+    headers: HeaderMap,
+    body_path: PathBuf,
+}
+
+impl Cached {
+    /// Whether the response may be served without asking upstream, given the
+    /// headers of the request.
+    pub fn is_fresh(&self, req_headers: &HeaderMap) -> bool {
+        let req_cache_control = directives(req_headers, &header::CACHE_CONTROL);
+        let pragma = directives(req_headers, &header::PRAGMA);
+        // Browsers send these when reloading.
+        let reload = req_cache_control
+            .iter()
+            .any(|(name, value)| name == "no-cache" || (name == "max-age" && value == "0"))
+            || pragma.iter().any(|(name, _)| name == "no-cache");
+        if reload {
+            return false;
+        }
+
+        self.age() < freshness_lifetime(&self.headers)
+    }
+
+    /// How old the response is, including the time it had spent in caches
+    /// upstream when it was stored.
+    fn age(&self) -> Duration {
+        let age_when_stored = self
+            .headers
+            .get(header::AGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default();
+        let since_stored = SystemTime::now()
+            .duration_since(self.stored)
+            .unwrap_or_default();
+
+        age_when_stored + since_stored
+    }
+
+    /// Add the validators of the cached response to a request, so upstream
+    /// can answer 304 Not Modified if it hasn't changed.
+    pub fn add_validators(&self, req_headers: &mut HeaderMap) {
+        req_headers.remove(header::IF_NONE_MATCH);
+        req_headers.remove(header::IF_MODIFIED_SINCE);
+
+        if let Some(etag) = self.headers.get(header::ETAG) {
+            req_headers.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = self.headers.get(header::LAST_MODIFIED) {
+            req_headers.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+
+    /// Make a response from the cache, labeled with how the cache handled it,
+    /// e.g. "HIT".
+    pub async fn response(
+        self,
+        version: Version,
+        cache_status: &'static str,
+    ) -> Result<Response<Body>> {
+        let file = File::open(&self.body_path).await?;
+        let len = file.metadata().await?.len();
+
+        let stream = FramedRead::new(file, BytesCodec::new());
+        let stream = stream.map(|b| b.map(BytesMut::freeze));
+
+        let age = self.age().as_secs();
+
+        let mut resp = Response::new(Body::wrap_stream(stream));
+        *resp.status_mut() = self.status;
+        *resp.version_mut() = version;
+        *resp.headers_mut() = self.headers;
+        let headers = resp.headers_mut();
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+        headers.insert(header::AGE, HeaderValue::from(age));
+        headers.insert(X_CACHE, HeaderValue::from_static(cache_status));
+
+        Ok(resp)
+    }
+}
+
+/// Whether a GET request may be answered from the cache, and its response
+/// stored.
+pub fn is_cacheable_request(req: &Parts) -> bool {
+    req.method == Method::GET
+        && !req.headers.contains_key(header::AUTHORIZATION)
+        && !req.headers.contains_key(header::RANGE)
+        && !directives(&req.headers, &header::CACHE_CONTROL)
+            .iter()
+            .any(|(name, _)| name == "no-store")
+}
+
+/// Whether a response may be stored, according to its headers.
+fn is_storable(status: StatusCode, headers: &HeaderMap) -> bool {
+    if status != StatusCode::OK {
+        return false;
+    }
+
+    let forbidden = directives(headers, &header::CACHE_CONTROL)
+        .iter()
+        .any(|(name, _)| name == "no-store" || name == "private");
+    if forbidden || headers.contains_key(header::SET_COOKIE) {
+        return false;
+    }
+
+    // Requests are sent upstream without Accept-Encoding, so only varying by
+    // that is fine.
+    directives(headers, &header::VARY)
+        .iter()
+        .all(|(name, _)| name == "accept-encoding")
+}
+
+/// How long a response is fresh for, from its `Cache-Control` or `Expires`
+/// header, or else a tenth of the time since its `Last-Modified` date, as
+/// suggested by RFC 7234.
+///
+/// https://tools.ietf.org/html/rfc7234#section-4.2
+fn freshness_lifetime(headers: &HeaderMap) -> Duration {
+    let cache_control = directives(headers, &header::CACHE_CONTROL);
+    let directive = |wanted: &str| {
+        cache_control
+            .iter()
+            .find(|(name, _)| name == wanted)
+            .map(|(_, value)| value.as_str())
+    };
+
+    if directive("no-cache").is_some() || directive("must-revalidate").is_some() {
+        return Duration::from_secs(0);
+    }
+
+    let max_age = directive("s-maxage").or_else(|| directive("max-age"));
+    if let Some(max_age) = max_age {
+        return Duration::from_secs(max_age.parse().unwrap_or(0));
+    }
+
+    let date = http_date(headers, &header::DATE);
+    if let (Some(expires), Some(date)) = (http_date(headers, &header::EXPIRES), date) {
+        return (expires - date).to_std().unwrap_or_default();
+    }
+    if headers.contains_key(header::EXPIRES) {
+        // Invalid dates, like "0", mean already expired.
+        return Duration::from_secs(0);
+    }
+
+    match (http_date(headers, &header::LAST_MODIFIED), date) {
+        (Some(last_modified), Some(date)) => {
+            let since_modified = (date - last_modified).to_std().unwrap_or_default();
+            (since_modified / 10).min(MAX_HEURISTIC_FRESHNESS)
+        }
+        _ => Duration::from_secs(0),
+    }
+}
+
+/// Parse a header of comma-separated directives, like `Cache-Control`, into
+/// lowercase names and their values, if any.
+fn directives(headers: &HeaderMap, name: &HeaderName) -> Vec<(String, String)> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|directive| {
+            let mut parts = directive.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            let value = parts.next().unwrap_or("").trim().trim_matches('"');
+            (name, value.to_string())
+        })
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+/// Parse a date header, like "Sun, 06 Nov 1994 08:49:37 GMT".
+fn http_date(headers: &HeaderMap, name: &HeaderName) -> Option<DateTime<chrono::FixedOffset>> {
+    let value = headers.get(name)?.to_str().ok()?;
+    DateTime::parse_from_rfc2822(value).ok()
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+/// Write the metadata file of a cached response.
+fn format_meta(cached: &Cached) -> String {
+    let stored = cached
+        .stored
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let mut meta = format!("{}\n{}\n{}\n", cached.uri, stored, cached.status.as_u16());
+    for (name, value) in &cached.headers {
+        if name == X_CACHE {
+            continue;
+        }
+        if let Ok(value) = value.to_str() {
+            meta.push_str(&format!("{}: {}\n", name, value));
+        }
+    }
+    meta
+}
+
+/// Read the metadata file of a cached response.
+fn parse_meta(meta: &str, body_path: PathBuf) -> Option<Cached> {
+    let mut lines = meta.lines();
+    let uri = lines.next()?.to_string();
+    let stored = UNIX_EPOCH + Duration::from_secs(lines.next()?.parse().ok()?);
+    let status = StatusCode::from_u16(lines.next()?.parse().ok()?).ok()?;
+
+    let mut headers = HeaderMap::new();
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let name: HeaderName = parts.next()?.parse().ok()?;
+        let value = HeaderValue::from_str(parts.next()?.trim()).ok()?;
+        headers.append(name, value);
+    }
+
+    Some(Cached {
+        uri,
+        stored,
+        status,
+        headers,
+        body_path,
+    })
+}
+
+/// The 64-bit FNV-1a hash, which is stable between builds, unlike the
+/// standard library's hasher.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Sends a response body on to the client, writing it to a temporary file
+/// as it passes through, which is moved into the cache once the whole body is
+/// written.
+struct Store {
+    cache: ProxyCache,
+    temp_path: PathBuf,
+    meta_path: PathBuf,
+    expected_len: Option<u64>,
+    cached: Cached,
+}
+
+impl Store {
+    async fn run(self, mut body: Body, mut sender: Sender) {
+        let mut file = match File::create(&self.temp_path).await {
+            Ok(file) => Some(file),
+            Err(e) => {
+                warn!("unable to create {}: {}", self.temp_path.display(), e);
+                None
+            }
+        };
+        let mut written = 0;
+
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    warn!("error reading {} from upstream: {}", self.cached.uri, e);
+                    sender.abort();
+                    if file.is_some() {
+                        self.remove_temp().await;
+                    }
+                    return;
+                }
+            };
+
+            if let Some(f) = &mut file {
+                match f.write_all(&chunk).await {
+                    Ok(()) => written += chunk.len() as u64,
+                    Err(e) => {
+                        warn!("unable to cache {}: {}", self.cached.uri, e);
+                        file = None;
+                        self.remove_temp().await;
+                    }
+                }
+            }
+
+            if sender.send_data(chunk).await.is_err() {
+                // The client went away before the body was finished.
+                if file.is_some() {
+                    self.remove_temp().await;
+                }
+                return;
+            }
+        }
+
+        if let Some(file) = file {
+            if let Err(e) = self.finish(file, written).await {
+                warn!("unable to cache {}: {}", self.cached.uri, e);
+                self.remove_temp().await;
+            }
+        }
+    }
+
+    async fn finish(&self, mut file: File, written: u64) -> io::Result<()> {
+        // Wait for the last write to finish.
+        file.flush().await?;
+        drop(file);
+
+        if self.expected_len.is_some_and(|len| len != written) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "body length doesn't match Content-Length",
+            ));
+        }
+
+        let temp_path = self.temp_path.clone();
+        let body_path = self.cached.body_path.clone();
+        let meta_temp_path = self.cache.temp_path();
+        let meta_path = self.meta_path.clone();
+        let meta = format_meta(&self.cached);
+        blocking::run(move || {
+            fs::rename(&temp_path, &body_path)?;
+            fs::write(&meta_temp_path, meta)?;
+            fs::rename(&meta_temp_path, &meta_path)
+        })
+        .await?;
+
+        debug!("cached {}", self.cached.uri);
+        Ok(())
+    }
+
+    async fn remove_temp(&self) {
+        let temp_path = self.temp_path.clone();
+        let _ = blocking::run(move || fs::remove_file(temp_path)).await;
+    }
+}
@@ -0,0 +1,105 @@
+//! The PROXY protocol, with `--proxy-protocol`, which TCP load balancers such
+//! as HAProxy and AWS's use to tell the server the client's address.
+//!
+//! The load balancer sends a header before anything else on the connection,
+//! in the text version 1 or the binary version 2, which is read before the
+//! TLS handshake or HTTP. Connections without a valid header are closed.
+//!
+//! https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+/// The start of a version 2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest a version 1 header can be, including the "\r\n".
+const V1_MAX_LEN: usize = 107;
+
+/// Read the PROXY protocol header from a new connection, returning the
+/// client's address, or `None` if the load balancer didn't give one, as for
+/// its own health checks.
+pub async fn read_header(stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+    // Both versions' headers are at least this long.
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid("no PROXY protocol header"))
+    }
+}
+
+/// Read the rest of a version 1 header, e.g.
+/// "PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n".
+///
+/// It's read a byte at a time, so nothing after it is read.
+async fn read_v1(stream: &mut TcpStream, start: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid("PROXY protocol header is too long"));
+        }
+        let mut byte = [0];
+        stream.read_exact(&mut byte).await?;
+        line.push(byte[0]);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol header is not text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", src, _, sport, _] | ["PROXY", "TCP6", src, _, sport, _] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("invalid PROXY address"))?;
+            let port: u16 = sport.parse().map_err(|_| invalid("invalid PROXY port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("invalid PROXY protocol header")),
+    }
+}
+
+/// Read the rest of a version 2 header, after the signature.
+async fn read_v2(stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+    let mut head = [0; 4];
+    stream.read_exact(&mut head).await?;
+    let (ver_cmd, family) = (head[0], head[1]);
+    let len = usize::from(u16::from_be_bytes([head[2], head[3]]));
+
+    let mut addrs = vec![0; len];
+    stream.read_exact(&mut addrs).await?;
+
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    // The LOCAL command is for the load balancer's own connections.
+    if ver_cmd & 0xf == 0 {
+        return Ok(None);
+    }
+
+    // The source address and port are first, then the destination's.
+    match family >> 4 {
+        1 if len >= 12 => {
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        2 if len >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        // Unix sockets and unknown families have no address to use.
+        _ => Ok(None),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,27 @@
+//! Printing a QR code of the site's URL, with `--qr`, so that phones and
+//! tablets on the same network can open it without typing the address.
+
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+use tracing::warn;
+
+/// Print a QR code of a URL to the terminal.
+pub fn print(url: &str) {
+    let code = match QrCode::new(url) {
+        Ok(code) => code,
+        Err(e) => {
+            warn!("unable to make a QR code for {}: {}", url, e);
+            return;
+        }
+    };
+
+    // Drawn light on dark, which phones read fine, so that it shows up on
+    // the usual dark terminal.
+    let image = code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build();
+
+    eprintln!("{}\n{}", url, image);
+}
@@ -0,0 +1,146 @@
+//! Limiting the rate of requests from each client address, with
+//! `--rate-limit` and `--burst`, so that one greedy client or a buggy polling
+//! script can't starve everyone else.
+//!
+//! Each address has a token bucket that holds up to the burst size, and is
+//! refilled at the rate. A request takes a token, and is answered with 429
+//! Too Many Requests, and when to retry, if there is none.
+
+use super::{Config, Result};
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+use hyper::{Body, Response};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// The most addresses that are tracked. Past this, the full buckets are
+/// dropped, and then the least recently seen, down to `PRUNED_LEN`, so that
+/// pruning is rare however many addresses come and go.
+const MAX_ADDRS: usize = 4096;
+
+/// How many addresses are left after pruning.
+const PRUNED_LEN: usize = MAX_ADDRS * 3 / 4;
+
+/// A request rate, written as e.g. "10r/s" or "600r/m", or just "10" for
+/// requests per second.
+#[derive(Clone, Copy, Debug)]
+pub struct Rate {
+    per_sec: f64,
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Rate, String> {
+        let (count, secs) = match s.find("r/") {
+            Some(i) => {
+                let secs = match &s[i + 2..] {
+                    "s" => 1.0,
+                    "m" => 60.0,
+                    "h" => 3600.0,
+                    _ => return Err(format!("expected r/s, r/m or r/h, found '{}'", s)),
+                };
+                (&s[..i], secs)
+            }
+            None => (s, 1.0),
+        };
+
+        match count.parse::<u32>() {
+            Ok(count) if count > 0 => Ok(Rate {
+                per_sec: f64::from(count) / secs,
+            }),
+            _ => Err(format!("expected a number of requests, found '{}'", s)),
+        }
+    }
+}
+
+/// The token buckets of the client addresses, shared by every connection.
+#[derive(Clone)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Arc<Mutex<HashMap<IpAddr, Bucket>>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Create the rate limiter, if `--rate-limit` is given.
+    ///
+    /// The burst defaults to a second's worth of requests.
+    pub fn new(config: &Config) -> Option<RateLimiter> {
+        let rate = config.rate_limit?.per_sec;
+        let burst = match config.burst {
+            Some(burst) => f64::from(burst.max(1)),
+            None => rate.ceil().max(1.0),
+        };
+
+        Some(RateLimiter {
+            rate,
+            burst,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Take a token for a request from an address, or say how long until
+    /// there will be one.
+    pub fn check(&self, ip: IpAddr) -> std::result::Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("rate limit lock");
+
+        if buckets.len() >= MAX_ADDRS && !buckets.contains_key(&ip) {
+            self.prune(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.tokens(bucket, now);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            debug!("rate limiting {}", ip);
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Drop the full buckets, which are the same as new ones, and then the
+    /// least recently used, until at most `PRUNED_LEN` are left.
+    fn prune(&self, buckets: &mut HashMap<IpAddr, Bucket>, now: Instant) {
+        buckets.retain(|_, bucket| self.tokens(bucket, now) < self.burst);
+        if buckets.len() <= PRUNED_LEN {
+            return;
+        }
+
+        let excess = buckets.len() - PRUNED_LEN;
+        let mut updated: Vec<Instant> = buckets.values().map(|bucket| bucket.updated).collect();
+        let (_, &mut cutoff, _) = updated.select_nth_unstable(excess - 1);
+        buckets.retain(|_, bucket| bucket.updated > cutoff);
+        debug!("rate limiter pruned to {} addresses", buckets.len());
+    }
+
+    /// The tokens in a bucket, after refilling it since it was last used.
+    fn tokens(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
+/// Make the 429 Too Many Requests response, saying when to retry in whole
+/// seconds.
+pub fn too_many_requests(config: &Config, wait: Duration) -> Result<Response<Body>> {
+    let secs = wait.as_secs_f64().ceil().max(1.0) as u64;
+    let mut headers = HeaderMap::new();
+    headers.insert(header::RETRY_AFTER, HeaderValue::from(secs));
+    super::make_error_response_from_code_and_headers(config, StatusCode::TOO_MANY_REQUESTS, headers)
+}
@@ -0,0 +1,237 @@
+//! Redirects from a file in the root directory, with `--redirects`, for
+//! previewing static sites that rely on a host's redirect rules.
+//!
+//! The rules are read from `_redirects`, in the Netlify format, with one
+//! "FROM TO [STATUS]" rule per line, or else from `redirects.toml`, with
+//! `[[redirects]]` tables of `from`, `to` and `status`. The file is read
+//! again, on a blocking thread, whenever it has changed, so edits apply right
+//! away.
+//!
+//! A FROM path may end in `*`, matching the rest of the path, which TO can
+//! use as `:splat`, and its segments may be placeholders such as `:year`,
+//! which TO can use by name. The first matching rule wins, and redirects are
+//! sent before looking for files. The status defaults to 301.
+
+use super::{Config, Error, Result};
+use http::{header, StatusCode};
+use hyper::{Body, Request, Response};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+use tokio_executor::blocking;
+use tracing::{info, warn};
+
+/// The redirects file in the Netlify format.
+const REDIRECTS_FILE: &str = "_redirects";
+
+/// The redirects file in TOML.
+const REDIRECTS_TOML_FILE: &str = "redirects.toml";
+
+/// One redirect rule.
+#[derive(Deserialize)]
+struct Rule {
+    from: String,
+    to: String,
+    #[serde(default)]
+    status: Option<u16>,
+}
+
+#[derive(Deserialize)]
+struct TomlRules {
+    #[serde(default)]
+    redirects: Vec<Rule>,
+}
+
+/// Make the redirect response for a request, if a rule matches it.
+pub async fn redirect(config: &Config, req: &Request<Body>) -> Result<Option<Response<Body>>> {
+    if !config.redirects {
+        return Ok(None);
+    }
+
+    let root_dir = config.root_dir.clone();
+    let rules = blocking::run(move || cached_rules(&root_dir)).await?;
+    for rule in rules.iter() {
+        let mut location = match rule.destination(req.uri().path()) {
+            Some(location) => location,
+            None => continue,
+        };
+
+        let status = match rule.status() {
+            Some(status) => status,
+            None => {
+                warn!(
+                    "skipping redirect from {} with status {:?}",
+                    rule.from, rule.status
+                );
+                continue;
+            }
+        };
+
+        // Keep the query string, unless the rule gives one.
+        if let (false, Some(query)) = (location.contains('?'), req.uri().query()) {
+            location.push('?');
+            location.push_str(query);
+        }
+
+        info!("redirecting {} to {}", req.uri(), location);
+        let resp = Response::builder()
+            .status(status)
+            .header(header::LOCATION, location)
+            .body(Body::empty())?;
+        return Ok(Some(resp));
+    }
+
+    Ok(None)
+}
+
+/// The number of rules in the redirects file, for `check`.
+pub fn rule_count(config: &Config) -> Result<usize> {
+    read_rules(&config.root_dir).map(|rules| rules.len())
+}
+
+/// The modification time and length of each redirects file, to tell when
+/// they change.
+type Stamp = [Option<(SystemTime, u64)>; 2];
+
+/// The rules last read for each root directory, with the stamp they were read
+/// at.
+type Cache = Mutex<HashMap<PathBuf, (Stamp, Arc<Vec<Rule>>)>>;
+
+/// The rules for a root directory, read again only if the redirects files
+/// have changed since they were last read.
+fn cached_rules(root_dir: &Path) -> Result<Arc<Vec<Rule>>> {
+    static CACHE: OnceLock<Cache> = OnceLock::new();
+    let cache = CACHE.get_or_init(Default::default);
+
+    let stamp_of = |name: &str| {
+        let meta = fs::metadata(root_dir.join(name)).ok()?;
+        Some((meta.modified().ok()?, meta.len()))
+    };
+    let stamp = [stamp_of(REDIRECTS_FILE), stamp_of(REDIRECTS_TOML_FILE)];
+
+    if let Some((cached_stamp, rules)) = cache.lock().expect("redirects lock").get(root_dir) {
+        if *cached_stamp == stamp {
+            return Ok(rules.clone());
+        }
+    }
+
+    let rules = Arc::new(read_rules(root_dir)?);
+    cache
+        .lock()
+        .expect("redirects lock")
+        .insert(root_dir.to_owned(), (stamp, rules.clone()));
+    Ok(rules)
+}
+
+/// Read the rules from the redirects file, if there is one.
+fn read_rules(root_dir: &Path) -> Result<Vec<Rule>> {
+    match fs::read_to_string(root_dir.join(REDIRECTS_FILE)) {
+        Ok(s) => return Ok(parse_rules(&s)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(Error::RedirectsRead(e)),
+    }
+
+    match fs::read_to_string(root_dir.join(REDIRECTS_TOML_FILE)) {
+        Ok(s) => {
+            let rules: TomlRules = toml::from_str(&s).map_err(Error::RedirectsParse)?;
+            Ok(rules.redirects)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(Error::RedirectsRead(e)),
+    }
+}
+
+/// Parse the Netlify format, skipping blank lines and lines starting with
+/// `#`. A `#` elsewhere is part of the rule, as in a fragment of its TO.
+///
+/// Anything after the status, such as Netlify's conditions, is ignored.
+fn parse_rules(s: &str) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    for line in s.lines() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (from, to) = match (fields.next(), fields.next()) {
+            (None, _) => continue,
+            (Some(from), Some(to)) => (from, to),
+            (Some(_), None) => {
+                warn!("skipping redirect rule '{}'", line);
+                continue;
+            }
+        };
+
+        // A status may be forced with "!", which makes no difference here
+        // since redirects are always sent before looking for files.
+        let status = match fields.next().map(|s| s.trim_end_matches('!').parse()) {
+            None => None,
+            Some(Ok(status)) => Some(status),
+            Some(Err(_)) => {
+                warn!("skipping redirect rule '{}'", line);
+                continue;
+            }
+        };
+
+        rules.push(Rule {
+            from: from.to_string(),
+            to: to.to_string(),
+            status,
+        });
+    }
+    rules
+}
+
+impl Rule {
+    /// The redirect status, if it's a redirect.
+    fn status(&self) -> Option<StatusCode> {
+        match self.status {
+            Some(status) => StatusCode::from_u16(status)
+                .ok()
+                .filter(StatusCode::is_redirection),
+            None => Some(StatusCode::MOVED_PERMANENTLY),
+        }
+    }
+
+    /// The location to redirect a path to, if the rule matches it.
+    fn destination(&self, path: &str) -> Option<String> {
+        let mut from = segments(&self.from);
+        let mut path = segments(path);
+        let mut params: Vec<(&str, String)> = Vec::new();
+
+        if from.last() == Some(&"*") {
+            from.pop();
+            if path.len() < from.len() {
+                return None;
+            }
+            params.push(("splat", path.split_off(from.len()).join("/")));
+        } else if path.len() != from.len() {
+            return None;
+        }
+
+        for (pattern, segment) in from.iter().zip(&path) {
+            if let Some(name) = pattern.strip_prefix(':') {
+                params.push((name, segment.to_string()));
+            } else if pattern != segment {
+                return None;
+            }
+        }
+
+        // Replace the longest names first, so ":year" isn't taken for ":y".
+        params.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+        let mut to = self.to.clone();
+        for (name, value) in params {
+            to = to.replace(&format!(":{}", name), &value);
+        }
+        Some(to)
+    }
+}
+
+/// The segments of a path, ignoring a trailing slash.
+fn segments(path: &str) -> Vec<&str> {
+    path.split('/').filter(|s| !s.is_empty()).collect()
+}
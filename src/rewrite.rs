@@ -0,0 +1,79 @@
+//! Internal URL rewrites, with `--rewrite PATTERN REPLACEMENT`, so that
+//! single-page apps and old URL schemes can be served without changing the
+//! files.
+//!
+//! A request whose path matches a rule's regex is served as if it were for the
+//! replacement, which can refer to the pattern's groups as `$1`, `$name`, etc.
+//! The first matching rule wins. The client isn't redirected, and doesn't see
+//! the new URL.
+
+use super::{Config, Error, Result};
+use http::Uri;
+use hyper::{Body, Request};
+use regex::Regex;
+use std::sync::Arc;
+use tracing::debug;
+
+/// One rewrite rule.
+struct Rule {
+    pattern: Regex,
+    replacement: String,
+}
+
+/// The rewrite rules, compiled once and shared by every request.
+#[derive(Clone)]
+pub struct Rewrites {
+    rules: Arc<Vec<Rule>>,
+}
+
+impl Rewrites {
+    /// Compile the rules from the `--rewrite` options, which come in pattern
+    /// and replacement pairs.
+    pub fn new(config: &Config) -> Result<Rewrites> {
+        let rules = config
+            .rewrite
+            .chunks(2)
+            .map(|pair| {
+                Ok(Rule {
+                    pattern: Regex::new(&pair[0]).map_err(Error::RewritePattern)?,
+                    replacement: pair.get(1).cloned().unwrap_or_default(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Rewrites {
+            rules: Arc::new(rules),
+        })
+    }
+
+    /// Rewrite the URI of a request by the first rule matching its path.
+    ///
+    /// The query string is kept, unless the replacement has its own.
+    pub fn apply(&self, mut req: Request<Body>) -> Result<Request<Body>> {
+        let path = req.uri().path();
+        let rule = match self.rules.iter().find(|rule| rule.pattern.is_match(path)) {
+            Some(rule) => rule,
+            None => return Ok(req),
+        };
+
+        let mut path_and_query = rule.pattern.replace(path, &*rule.replacement).into_owned();
+        if let (false, Some(query)) = (path_and_query.contains('?'), req.uri().query()) {
+            path_and_query.push('?');
+            path_and_query.push_str(query);
+        }
+
+        let mut builder = Uri::builder();
+        if let Some(scheme) = req.uri().scheme_part() {
+            builder.scheme(scheme.clone());
+        }
+        if let Some(authority) = req.uri().authority_part() {
+            builder.authority(authority.clone());
+        }
+        builder.path_and_query(path_and_query.as_str());
+        let uri = builder.build()?;
+
+        debug!("rewriting {} to {}", req.uri(), uri);
+        *req.uri_mut() = uri;
+        Ok(req)
+    }
+}
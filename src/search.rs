@@ -0,0 +1,214 @@
+//! Searching for files by name, with `/_search?q=...` and `-x`.
+//!
+//! The whole root directory is walked for each search, on tokio's blocking
+//! threads, leaving out hidden files and not following symlinks to
+//! directories. Results are an HTML page of links, or JSON with
+//! `?format=json` or `Accept: application/json`.
+
+use super::ext::{escape_html, query_param, PATH_SET};
+use super::{Config, HtmlCfg, Page, Result};
+use http::{header, Request, Response, StatusCode};
+use hyper::Body;
+use percent_encoding::utf8_percent_encode;
+use serde::Serialize;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio_executor::blocking;
+use tracing::warn;
+
+/// The path of the search page.
+pub static SEARCH_PATH: &str = "/_search";
+
+/// The form added to the top of directory listings.
+#[cfg(feature = "dir-listing")]
+pub static SEARCH_FORM: &str = r#"
+<form class="search" action="/_search">
+  <input type="search" name="q" placeholder="Search file names" required>
+</form>
+"#;
+
+/// The most results a search returns.
+const MAX_RESULTS: usize = 1000;
+
+#[derive(Serialize)]
+struct SearchResults {
+    query: String,
+    results: Vec<SearchResult>,
+    /// Whether there were more than `MAX_RESULTS` matches.
+    truncated: bool,
+}
+
+#[derive(Serialize)]
+struct SearchResult {
+    /// The URL path of the file, without %-encoding.
+    path: String,
+    dir: bool,
+}
+
+/// Whether a request is for the search page.
+pub fn is_search_request(config: &Config, req: &Request<Body>) -> bool {
+    config.use_extensions && req.uri().path() == SEARCH_PATH
+}
+
+/// Search for the files whose names contain the `q` parameter, ignoring
+/// case, and list them.
+pub async fn serve(config: &Config, req: &Request<Body>) -> Result<Response<Body>> {
+    let query = query_param(req, "q").unwrap_or_default();
+    let walk_config = config.clone();
+    let results = blocking::run(move || search(&walk_config, query.trim())).await;
+
+    let mut resp = if wants_json(req) {
+        let json = serde_json::to_string(&results).expect("results serialize");
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(header::CONTENT_LENGTH, json.len())
+            .body(Body::from(json))?
+    } else {
+        let html = render_results(config, &results)?;
+        super::html_str_to_response(html, StatusCode::OK)?
+    };
+
+    // The format can depend on the Accept header, so caches must too.
+    resp.headers_mut()
+        .append(header::VARY, header::HeaderValue::from_static("accept"));
+
+    Ok(resp)
+}
+
+/// Whether the client asked for JSON, with `?format=json` or an Accept header
+/// that wants JSON but not HTML.
+fn wants_json(req: &Request<Body>) -> bool {
+    if query_param(req, "format").as_deref() == Some("json") {
+        return true;
+    }
+
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    accept.contains("application/json") && !accept.contains("text/html")
+}
+
+/// Walk the root directory for file names containing the query, in path
+/// order.
+fn search(config: &Config, query: &str) -> SearchResults {
+    let mut results = SearchResults {
+        query: query.to_string(),
+        results: Vec::new(),
+        truncated: false,
+    };
+    if query.is_empty() {
+        return results;
+    }
+
+    let query = query.to_lowercase();
+    let mut stack = vec![config.root_dir.clone()];
+
+    'walk: while let Some(dir) = stack.pop() {
+        let dents = match fs::read_dir(&dir) {
+            Ok(dents) => dents,
+            Err(e) => {
+                warn!("unable to search {}: {}", dir.display(), e);
+                continue;
+            }
+        };
+
+        let mut paths: Vec<PathBuf> = dents
+            .filter_map(|dent| dent.ok().map(|dent| dent.path()))
+            .filter(|path| !super::hide::is_hidden(config, path))
+            .collect();
+        paths.sort();
+
+        for path in paths.iter().rev() {
+            // Symlinks may lead out of the root or around in circles.
+            let is_dir = fs::symlink_metadata(path)
+                .map(|meta| meta.is_dir())
+                .unwrap_or(false);
+            if is_dir {
+                stack.push(path.clone());
+            }
+        }
+
+        for path in paths {
+            let matches = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_lowercase().contains(&query))
+                .unwrap_or(false);
+            if !matches {
+                continue;
+            }
+            if results.results.len() == MAX_RESULTS {
+                results.truncated = true;
+                break 'walk;
+            }
+            results.results.push(SearchResult {
+                path: url_path(config, &path),
+                dir: path.is_dir(),
+            });
+        }
+    }
+
+    results.results.sort_by(|a, b| a.path.cmp(&b.path));
+    results
+}
+
+/// The URL path of a file, without %-encoding.
+fn url_path(config: &Config, path: &Path) -> String {
+    let rel_path = path.strip_prefix(&config.root_dir).unwrap_or(path);
+    let mut url = String::new();
+    for c in rel_path.components() {
+        url.push('/');
+        url.push_str(&c.as_os_str().to_string_lossy());
+    }
+    url
+}
+
+/// Render the results as a page of links, below the search box.
+fn render_results(config: &Config, results: &SearchResults) -> Result<String> {
+    let mut buf = String::new();
+
+    let _ = writeln!(
+        buf,
+        "<form class=\"search\" action=\"{}\">\n  <input type=\"search\" name=\"q\" value=\"{}\" required>\n</form>",
+        SEARCH_PATH,
+        escape_html(&results.query)
+    );
+
+    if !results.query.is_empty() && results.results.is_empty() {
+        let _ = writeln!(buf, "<p>No files found.</p>");
+    }
+
+    let _ = writeln!(buf, "<div class=\"listing\">");
+    for result in &results.results {
+        let slash = if result.dir { "/" } else { "" };
+        let href = utf8_percent_encode(&result.path, PATH_SET).to_string();
+        let _ = writeln!(
+            buf,
+            "<div><a href=\"{}{}\">{}{}</a></div>",
+            super::base_path::url(config, &href),
+            slash,
+            escape_html(&result.path),
+            slash
+        );
+    }
+    let _ = writeln!(buf, "</div>");
+
+    if results.truncated {
+        let _ = writeln!(
+            buf,
+            "<p>Only the first {} results are shown.</p>",
+            MAX_RESULTS
+        );
+    }
+
+    let title = if results.query.is_empty() {
+        "Search".to_string()
+    } else {
+        format!("Search: {}", results.query)
+    };
+
+    super::render_html(config, Page::Listing, HtmlCfg { title, body: buf })
+}
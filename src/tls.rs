@@ -0,0 +1,370 @@
+//! TLS configuration, and generation of self-signed certificates.
+
+use super::{Config, Error, Result};
+use rcgen::{Certificate as RcgenCertificate, CertificateParams, SanType};
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+    Certificate, NoClientAuth, PrivateKey, ResolvesServerCert, ResolvesServerCertUsingSNI,
+    ServerConfig, SignatureScheme,
+};
+use std::fs;
+use std::io::{self, BufReader};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::timer::delay_for;
+use tokio_executor::blocking;
+use tracing::{info, warn};
+use webpki::DNSNameRef;
+
+/// The default path of the certificate written by `gen-cert`.
+static DEFAULT_CERT_PATH: &str = "cert.pem";
+
+/// The default path of the private key written by `gen-cert`.
+static DEFAULT_KEY_PATH: &str = "key.pem";
+
+/// A certificate to use for one host name, chosen by SNI, from the
+/// `--tls-sni HOST=CERT,KEY` option.
+#[derive(Clone, Debug)]
+pub struct SniCert {
+    host: String,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl FromStr for SniCert {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<SniCert, String> {
+        let mut parts = s.splitn(2, '=');
+        let host = parts.next().unwrap_or("");
+        let mut paths = parts.next().unwrap_or("").splitn(2, ',');
+        let cert = paths.next().unwrap_or("");
+        let key = paths.next().unwrap_or("");
+
+        if host.is_empty() || cert.is_empty() || key.is_empty() {
+            return Err(format!("expected HOST=CERT,KEY, found '{}'", s));
+        }
+
+        Ok(SniCert {
+            host: host.to_string(),
+            cert: PathBuf::from(cert),
+            key: PathBuf::from(key),
+        })
+    }
+}
+
+/// How often to check the certificate files for changes.
+const CERT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Create the rustls configuration from the command line options, along with
+/// a watcher that reloads the certificates when their files change.
+///
+/// Returns `None` if the server should use plain HTTP.
+pub fn server_config(config: &Config) -> Result<Option<(Arc<ServerConfig>, CertWatcher)>> {
+    let resolver = match cert_resolver(config)? {
+        Some(resolver) => resolver,
+        None => return Ok(None),
+    };
+    let resolver = Arc::new(ReloadingResolver {
+        current: RwLock::new(resolver),
+    });
+
+    let mut tls_config = ServerConfig::new(NoClientAuth::new());
+    tls_config.cert_resolver = resolver.clone();
+    tls_config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+
+    let watcher = CertWatcher {
+        config: config.clone(),
+        resolver,
+    };
+
+    Ok(Some((Arc::new(tls_config), watcher)))
+}
+
+/// Load the certificates named on the command line, or generate one.
+fn cert_resolver(config: &Config) -> Result<Option<SniResolver>> {
+    let default = default_cert(config)?;
+    if default.is_none() && config.tls_sni.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(SniResolver {
+        by_name: sni_certs(config)?,
+        default,
+    }))
+}
+
+/// Load or generate the certificate used when no `--tls-sni` certificate
+/// matches.
+fn default_cert(config: &Config) -> Result<Option<CertifiedKey>> {
+    let default = match (&config.tls_cert, &config.tls_key, config.tls_auto) {
+        (None, None, false) => None,
+        (None, None, true) => {
+            info!("generating ephemeral self-signed certificate");
+            let cert = generate_self_signed()?;
+            let certs = vec![Certificate(
+                cert.serialize_der().map_err(Error::CertGenerate)?,
+            )];
+            let key = PrivateKey(cert.serialize_private_key_der());
+            Some(certified_key(certs, key)?)
+        }
+        (Some(cert_path), Some(key_path), false) => Some(certified_key(
+            load_certs(cert_path)?,
+            load_private_key(key_path)?,
+        )?),
+        _ => return Err(Error::TlsOptions),
+    };
+    Ok(default)
+}
+
+/// Load the `--tls-sni` certificates.
+fn sni_certs(config: &Config) -> Result<ResolvesServerCertUsingSNI> {
+    let mut by_name = ResolvesServerCertUsingSNI::new();
+    for sni in &config.tls_sni {
+        info!("certificate for {}: {}", sni.host, sni.cert.display());
+        let certified_key = certified_key(load_certs(&sni.cert)?, load_private_key(&sni.key)?)?;
+        by_name
+            .add(&sni.host, certified_key)
+            .map_err(Error::TlsSniCert)?;
+    }
+    Ok(by_name)
+}
+
+/// Combine a certificate chain and its private key for use by a resolver.
+fn certified_key(certs: Vec<Certificate>, key: PrivateKey) -> Result<CertifiedKey> {
+    let key = sign::any_supported_type(&key).map_err(|_| Error::TlsKeyParse)?;
+    Ok(CertifiedKey::new(certs, Arc::new(key)))
+}
+
+/// Chooses a certificate by the SNI host name the client asks for, falling
+/// back to the default certificate if there is one.
+struct SniResolver {
+    by_name: ResolvesServerCertUsingSNI,
+    default: Option<CertifiedKey>,
+}
+
+/// A resolver whose certificates can be replaced while the server is running.
+struct ReloadingResolver {
+    current: RwLock<SniResolver>,
+}
+
+impl ResolvesServerCert for ReloadingResolver {
+    fn resolve(
+        &self,
+        server_name: Option<DNSNameRef>,
+        sigschemes: &[SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        let current = self.current.read().expect("certificate lock");
+        current.resolve(server_name, sigschemes)
+    }
+}
+
+/// Watches the certificate and key files, and reloads them when they change,
+/// so that renewed certificates are picked up without a restart.
+pub struct CertWatcher {
+    config: Config,
+    resolver: Arc<ReloadingResolver>,
+}
+
+impl CertWatcher {
+    /// Poll the files forever.
+    ///
+    /// A reload happens once the files have stopped changing for one poll
+    /// interval, so that a certificate isn't loaded without its new key.
+    /// If loading fails the old certificates stay in use. A certificate
+    /// generated by `--tls-auto` is kept, so that clients that accepted it
+    /// don't have to again. The files are read on a blocking thread.
+    pub async fn run(self) {
+        let config = &self.config;
+        let mut paths: Vec<PathBuf> = config
+            .tls_cert
+            .iter()
+            .chain(&config.tls_key)
+            .cloned()
+            .collect();
+        for sni in &config.tls_sni {
+            paths.push(sni.cert.clone());
+            paths.push(sni.key.clone());
+        }
+
+        if paths.is_empty() {
+            return;
+        }
+        let paths = Arc::new(paths);
+
+        let generated = if config.tls_auto {
+            let current = self.resolver.current.read().expect("certificate lock");
+            current.default.clone()
+        } else {
+            None
+        };
+
+        let mut mtimes = file_mtimes(paths.clone()).await;
+        let mut changed = false;
+
+        loop {
+            delay_for(CERT_POLL_INTERVAL).await;
+
+            let new_mtimes = file_mtimes(paths.clone()).await;
+            if new_mtimes != mtimes {
+                mtimes = new_mtimes;
+                changed = true;
+                continue;
+            }
+
+            if !changed {
+                continue;
+            }
+            changed = false;
+
+            info!("certificate files changed, reloading");
+            let reloaded = {
+                let config = config.clone();
+                let generated = generated.clone();
+                blocking::run(move || reload(&config, generated)).await
+            };
+            match reloaded {
+                Ok(resolver) => {
+                    *self.resolver.current.write().expect("certificate lock") = resolver;
+                }
+                Err(e) => {
+                    warn!("failed to reload certificates, keeping the old ones");
+                    super::log_error_chain(&e);
+                }
+            }
+        }
+    }
+}
+
+/// Load the certificates from their files again, keeping the default
+/// certificate if it was generated.
+fn reload(config: &Config, generated: Option<CertifiedKey>) -> Result<SniResolver> {
+    let default = match generated {
+        Some(default) => Some(default),
+        None => default_cert(config)?,
+    };
+    Ok(SniResolver {
+        by_name: sni_certs(config)?,
+        default,
+    })
+}
+
+/// Get the modification times of files, or `None` for files that can't be
+/// read at the moment.
+async fn file_mtimes(paths: Arc<Vec<PathBuf>>) -> Vec<Option<SystemTime>> {
+    blocking::run(move || {
+        paths
+            .iter()
+            .map(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+            .collect()
+    })
+    .await
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(
+        &self,
+        server_name: Option<DNSNameRef>,
+        sigschemes: &[SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        self.by_name
+            .resolve(server_name, sigschemes)
+            .or_else(|| self.default.clone())
+    }
+}
+
+/// Generate a self-signed certificate and write it and its key to the paths
+/// given by `--tls-cert` and `--tls-key`, or their defaults.
+pub fn gen_cert_files(config: &Config) -> Result<()> {
+    let cert_path = config
+        .tls_cert
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CERT_PATH));
+    let key_path = config
+        .tls_key
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_KEY_PATH));
+
+    let cert = generate_self_signed()?;
+    let cert_pem = cert.serialize_pem().map_err(Error::CertGenerate)?;
+    let key_pem = cert.serialize_private_key_pem();
+
+    fs::write(&cert_path, cert_pem).map_err(Error::CertWrite)?;
+    write_private(&key_path, key_pem.as_bytes()).map_err(Error::CertWrite)?;
+
+    info!("wrote certificate to {}", cert_path.display());
+    info!("wrote private key to {}", key_path.display());
+
+    Ok(())
+}
+
+/// Write a file that only its owner can read, like a private key.
+#[cfg(unix)]
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // The mode is only used for new files.
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(data)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    fs::write(path, data)
+}
+
+/// Generate a self-signed certificate for "localhost", the loopback addresses
+/// and the addresses of the machine's network interfaces.
+fn generate_self_signed() -> Result<RcgenCertificate> {
+    let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+
+    let mut addrs: Vec<IpAddr> = vec![[127, 0, 0, 1].into(), [0u16, 0, 0, 0, 0, 0, 0, 1].into()];
+    match if_addrs::get_if_addrs() {
+        Ok(ifaces) => addrs.extend(ifaces.iter().map(|iface| iface.ip())),
+        Err(e) => warn!("unable to list network interfaces: {}", e),
+    }
+    addrs.sort();
+    addrs.dedup();
+
+    for addr in addrs {
+        info!("certificate valid for {}", addr);
+        params.subject_alt_names.push(SanType::IpAddress(addr));
+    }
+
+    RcgenCertificate::from_params(params).map_err(Error::CertGenerate)
+}
+
+/// Load a PEM certificate chain.
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let file = fs::File::open(path).map_err(Error::TlsFileRead)?;
+    let certs = pemfile::certs(&mut BufReader::new(file)).map_err(|_| Error::TlsCertParse)?;
+    if certs.is_empty() {
+        return Err(Error::TlsCertParse);
+    }
+    Ok(certs)
+}
+
+/// Load a PEM private key, in either PKCS #8 or RSA format.
+fn load_private_key(path: &Path) -> Result<PrivateKey> {
+    let pem = fs::read(path).map_err(Error::TlsFileRead)?;
+
+    let pkcs8_keys = pemfile::pkcs8_private_keys(&mut &pem[..]).map_err(|_| Error::TlsKeyParse)?;
+    let rsa_keys = pemfile::rsa_private_keys(&mut &pem[..]).map_err(|_| Error::TlsKeyParse)?;
+
+    pkcs8_keys
+        .into_iter()
+        .chain(rsa_keys)
+        .next()
+        .ok_or(Error::TlsKeyParse)
+}
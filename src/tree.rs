@@ -0,0 +1,224 @@
+//! The directory tree as JSON, with `/_tree?path=...&depth=N` and `-x`, and
+//! the metadata of one file, with `/_stat?path=...`.
+//!
+//! Scripts and UIs can fetch the layout of a directory in one request instead
+//! of crawling listings. Each node has the file's name, URL path and type, and
+//! the size and modification time of files; directories have their children
+//! down to the requested depth, which is 1 by default. Hidden files are left
+//! out, and symlinks aren't followed. The tree is read on tokio's blocking
+//! threads, and stops growing at `MAX_NODES` files, after which directories
+//! are left without their children, as if they were too deep.
+//!
+//! The metadata of a single file adds its permissions, MIME type and ETag, so
+//! that sync scripts can tell whether to download it again.
+
+use super::ext::{json_response, query_param, url_path_to_local};
+use super::{Config, Result};
+use http::{Request, Response, StatusCode};
+use hyper::Body;
+use serde::Serialize;
+use serde_json::json;
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio_executor::blocking;
+use tracing::warn;
+
+/// The path of the tree endpoint.
+pub static TREE_PATH: &str = "/_tree";
+
+/// The path of the stat endpoint.
+pub static STAT_PATH: &str = "/_stat";
+
+/// The deepest tree that can be asked for.
+const MAX_DEPTH: u32 = 16;
+
+/// The most files a tree may describe, so that a request for a big tree
+/// can't keep a thread busy for long.
+const MAX_NODES: usize = 10_000;
+
+#[derive(Serialize)]
+struct Node {
+    name: String,
+    /// The URL path of the file, without %-encoding.
+    path: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    /// Seconds since the Unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified: Option<u64>,
+    /// Left out for directories deeper than the requested depth.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    children: Option<Vec<Node>>,
+    /// The permission bits in octal, like "0644", only from `/_stat`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+    /// Only from `/_stat`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mime: Option<String>,
+    /// The ETag the file is served with, only from `/_stat`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+}
+
+/// Whether a request is for the tree endpoint.
+pub fn is_tree_request(config: &Config, req: &Request<Body>) -> bool {
+    config.use_extensions && req.uri().path() == TREE_PATH
+}
+
+/// Whether a request is for the stat endpoint.
+pub fn is_stat_request(config: &Config, req: &Request<Body>) -> bool {
+    config.use_extensions && req.uri().path() == STAT_PATH
+}
+
+/// Answer with the tree under the `path` parameter, or the root directory.
+pub async fn serve(config: &Config, req: &Request<Body>) -> Result<Response<Body>> {
+    let depth = match query_param(req, "depth").map(|d| d.parse::<u32>()) {
+        None => 1,
+        Some(Ok(depth)) => depth.min(MAX_DEPTH),
+        Some(Err(_)) => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                json!({ "error": "depth must be a number" }),
+            )
+        }
+    };
+
+    let (path, meta) = match find_path(config, req) {
+        Some(found) => found,
+        None => return json_response(StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+    };
+
+    let walk_config = config.clone();
+    let node = blocking::run(move || {
+        let mut nodes = MAX_NODES;
+        read_node(&walk_config, &path, &meta, depth, &mut nodes)
+    })
+    .await;
+    let json = serde_json::to_value(&node).expect("tree serializes");
+    json_response(StatusCode::OK, json)
+}
+
+/// Answer with the metadata of the file at the `path` parameter.
+pub fn serve_stat(config: &Config, req: &Request<Body>) -> Result<Response<Body>> {
+    let (path, meta) = match find_path(config, req) {
+        Some(found) => found,
+        None => return json_response(StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+    };
+
+    let mut node = read_node(config, &path, &meta, 0, &mut 0);
+    node.mode = mode(&meta);
+    if meta.is_file() {
+        node.mime = Some(super::mime_type::file_path_mime(config, &path).to_string());
+        node.etag = super::file_etag(&meta).ok();
+    }
+
+    let json = serde_json::to_value(&node).expect("stat serializes");
+    json_response(StatusCode::OK, json)
+}
+
+/// Find the file at the `path` parameter, or the root directory, unless it
+/// is hidden.
+fn find_path(config: &Config, req: &Request<Body>) -> Option<(PathBuf, Metadata)> {
+    let url_path = query_param(req, "path").unwrap_or_else(|| "/".to_string());
+    let path = url_path_to_local(config, &url_path)?;
+    if super::hide::is_hidden(config, &path) {
+        return None;
+    }
+    let meta = fs::symlink_metadata(&path).ok()?;
+    Some((path, meta))
+}
+
+/// The permission bits of a file, in octal.
+#[cfg(unix)]
+fn mode(meta: &Metadata) -> Option<String> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(format!("{:04o}", meta.permissions().mode() & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn mode(_meta: &Metadata) -> Option<String> {
+    None
+}
+
+/// Describe a file, and the files under it down to a depth, counting the
+/// files described against `nodes`.
+fn read_node(config: &Config, path: &Path, meta: &Metadata, depth: u32, nodes: &mut usize) -> Node {
+    let rel_path = path.strip_prefix(&config.root_dir).unwrap_or(path);
+    let mut url_path = String::new();
+    for c in rel_path.components() {
+        url_path.push('/');
+        url_path.push_str(&c.as_os_str().to_string_lossy());
+    }
+    if url_path.is_empty() {
+        url_path.push('/');
+    }
+
+    let file_type = meta.file_type();
+    let kind = if file_type.is_symlink() {
+        "symlink"
+    } else if file_type.is_dir() {
+        "dir"
+    } else {
+        "file"
+    };
+
+    let children = if file_type.is_dir() && depth > 0 && *nodes > 0 {
+        Some(read_children(config, path, depth - 1, nodes))
+    } else {
+        None
+    };
+
+    Node {
+        name: path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        path: url_path,
+        kind,
+        size: if file_type.is_file() {
+            Some(meta.len())
+        } else {
+            None
+        },
+        modified: meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs()),
+        children,
+        mode: None,
+        mime: None,
+        etag: None,
+    }
+}
+
+/// Describe the files in a directory, sorted by name, up to the number of
+/// `nodes` left.
+fn read_children(config: &Config, dir: &Path, depth: u32, nodes: &mut usize) -> Vec<Node> {
+    let dents = match fs::read_dir(dir) {
+        Ok(dents) => dents,
+        Err(e) => {
+            warn!("unable to read {}: {}", dir.display(), e);
+            return Vec::new();
+        }
+    };
+
+    let mut paths: Vec<_> = dents
+        .filter_map(|dent| dent.ok().map(|dent| dent.path()))
+        .filter(|path| !super::hide::is_hidden(config, path))
+        .collect();
+    paths.sort();
+    paths.truncate(*nodes);
+    *nodes -= paths.len();
+
+    paths
+        .iter()
+        .filter_map(|path| {
+            let meta = fs::symlink_metadata(path).ok()?;
+            Some(read_node(config, path, &meta, depth, nodes))
+        })
+        .collect()
+}
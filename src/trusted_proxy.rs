@@ -0,0 +1,146 @@
+//! Finding the client's address behind reverse proxies, with
+//! `--trusted-proxy`, so that logs, rate limits and CGI scripts see the
+//! client instead of the proxy.
+//!
+//! The `Forwarded` header, or else `X-Forwarded-For`, is only believed when
+//! the connection comes from a trusted proxy. Its addresses are read from the
+//! last one added, skipping the trusted proxies that added them, and the
+//! first untrusted one is the client.
+
+use super::conn::ConnInfo;
+use super::Config;
+use http::header;
+use hyper::{Body, Request};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use tracing::trace;
+
+/// A network address range in CIDR notation, e.g. "10.0.0.0/8", or a single
+/// address.
+#[derive(Clone, Copy, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let err = || format!("expected an address or CIDR range, found '{}'", s);
+
+        let (addr, prefix_len) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| err())?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(err)?,
+            None => max_len,
+        };
+
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+impl Cidr {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_eq(
+                u32::from(net).into(),
+                u32::from(ip).into(),
+                self.prefix_len,
+                32,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(u128::from(net), u128::from(ip), self.prefix_len, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `len` of `bits` bits of two numbers are the same.
+fn prefix_eq(a: u128, b: u128, len: u32, bits: u32) -> bool {
+    len == 0 || (a ^ b) >> (bits - len) == 0
+}
+
+/// The connection information with the client's address from the proxy
+/// headers, if the connection is from a trusted proxy.
+pub fn resolve(config: &Config, mut conn: ConnInfo, req: &Request<Body>) -> ConnInfo {
+    let peer = match conn.peer_addr {
+        Some(peer) if is_trusted(config, peer.ip()) => peer,
+        _ => return conn,
+    };
+
+    // A load balancer's PROXY protocol header names the last hop before it.
+    let mut addrs = forwarded_addrs(req).unwrap_or_default();
+    addrs.extend(conn.proxied_addr);
+    let client = addrs
+        .iter()
+        .rev()
+        .find(|addr| !is_trusted(config, addr.ip()))
+        .or_else(|| addrs.first());
+
+    if let Some(client) = client {
+        trace!("client {} forwarded by {}", client, peer);
+        conn.remote_addr = Some(*client);
+    }
+    conn
+}
+
+fn is_trusted(config: &Config, ip: IpAddr) -> bool {
+    config.trusted_proxy.iter().any(|cidr| cidr.contains(ip))
+}
+
+/// The addresses in the `Forwarded` or `X-Forwarded-For` headers, first to
+/// last, or `None` if an address is hidden or can't be read, since the
+/// addresses after it can't be placed.
+fn forwarded_addrs(req: &Request<Body>) -> Option<Vec<SocketAddr>> {
+    let forwarded = header_values(req, header::FORWARDED.as_str());
+    if !forwarded.is_empty() {
+        return forwarded
+            .iter()
+            .flat_map(|element| element.split(';'))
+            .filter_map(|pair| {
+                let (name, value) = pair.split_at(pair.find('=')?);
+                if name.trim().eq_ignore_ascii_case("for") {
+                    Some(value[1..].trim().trim_matches('"'))
+                } else {
+                    None
+                }
+            })
+            .map(parse_node)
+            .collect();
+    }
+
+    let forwarded_for = header_values(req, "x-forwarded-for");
+    forwarded_for.iter().map(|node| parse_node(node)).collect()
+}
+
+/// The comma-separated elements of every instance of a header.
+fn header_values(req: &Request<Body>, name: &str) -> Vec<String> {
+    req.headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// Parse an address, which may have a port, and brackets if it's IPv6, e.g.
+/// "192.0.2.60", "192.0.2.60:4711" or "[2001:db8::17]:4711".
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    let ip = node.trim_start_matches('[').trim_end_matches(']');
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
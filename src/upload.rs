@@ -0,0 +1,359 @@
+//! Uploading files from the browser, with `--webdav` and `-x`.
+//!
+//! Directory listings get a form for choosing files, which is posted back to
+//! the directory's URL as `multipart/form-data`. Each file in the form is
+//! streamed into the directory as it arrives, without overwriting any file
+//! already there, and the browser is sent back to the listing.
+//!
+//! https://tools.ietf.org/html/rfc7578
+
+use super::{Config, Error, Result};
+use http::header::{self, HeaderMap};
+use http::uri::Authority;
+use http::{Method, Request, Response, StatusCode, Uri};
+use hyper::Body;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info, warn};
+
+/// The form added to directory listings.
+#[cfg(feature = "dir-listing")]
+pub static UPLOAD_FORM: &str = r#"
+<form class="upload" method="post" enctype="multipart/form-data">
+  <input type="file" name="file" multiple required>
+  <button type="submit">Upload</button>
+</form>
+"#;
+
+/// The most bytes of headers a part of the form may have.
+const MAX_PART_HEADERS_LEN: usize = 16 * 1024;
+
+/// Whether uploads are allowed, which needs both writable mode and the
+/// developer extensions that list directories.
+pub fn is_enabled(config: &Config) -> bool {
+    config.webdav && config.use_extensions
+}
+
+/// Whether a request is an upload, posted from a directory listing.
+pub fn is_upload_request(config: &Config, req: &Request<Body>) -> bool {
+    is_enabled(config) && req.method() == Method::POST
+}
+
+/// Whether a request was sent by a page on another site.
+///
+/// A form on any web page can post to a server on the local machine, so
+/// requests that change files are refused unless they come from the server's
+/// own pages. Browsers say where a request came from with `Sec-Fetch-Site`,
+/// or, when older, only with `Origin`. Requests with neither, as from curl,
+/// aren't from a page, and are allowed.
+pub fn is_cross_site(req: &Request<Body>) -> bool {
+    let headers = req.headers();
+    if let Some(site) = headers.get("sec-fetch-site") {
+        return site != "same-origin" && site != "none";
+    }
+
+    let origin = match headers.get(header::ORIGIN) {
+        Some(origin) => origin,
+        None => return false,
+    };
+    let origin = origin
+        .to_str()
+        .ok()
+        .and_then(|origin| origin.parse::<Uri>().ok())
+        .and_then(|uri| uri.authority_part().cloned());
+    let host = req.uri().authority_part().cloned().or_else(|| {
+        headers
+            .get(header::HOST)?
+            .to_str()
+            .ok()?
+            .parse::<Authority>()
+            .ok()
+    });
+
+    match (origin, host) {
+        (Some(origin), Some(host)) => origin != host,
+        // Including "Origin: null", from sandboxed pages and local files.
+        _ => true,
+    }
+}
+
+/// Save the files posted to a directory, and redirect back to its listing.
+pub async fn serve(config: &Config, req: Request<Body>) -> Result<Response<Body>> {
+    if is_cross_site(&req) {
+        warn!("refusing upload to {} from another site", req.uri());
+        return super::make_error_response_from_code(config, StatusCode::FORBIDDEN);
+    }
+
+    let dir = match super::webdav::local_path(config, req.uri())? {
+        Some(dir) if !super::hide::is_hidden(config, &dir) => dir,
+        _ => return super::make_error_response_from_code(config, StatusCode::FORBIDDEN),
+    };
+    if !dir.is_dir() {
+        return super::make_error_response_from_code(config, StatusCode::NOT_FOUND);
+    }
+
+    let boundary = match boundary(req.headers()) {
+        Some(boundary) => boundary,
+        None => {
+            return super::make_error_response_from_code(config, StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        }
+    };
+
+    super::webdav::check_content_length(config, req.headers())?;
+
+    let location = req.uri().path().to_string();
+    let mut form = Multipart::new(config, req.into_body(), &boundary);
+
+    match save_files(config, &dir, &mut form).await {
+        Ok(()) => {}
+        Err(Error::Upload(e)) => {
+            debug!("bad upload: {}", e);
+            return super::make_error_response_from_code(config, StatusCode::BAD_REQUEST);
+        }
+        Err(e) => return Err(e),
+    }
+
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, location)
+        .body(Body::empty())
+        .map_err(Error::from)
+}
+
+/// Save each file in the form to the directory.
+///
+/// Parts that aren't files, and files with hidden names, are skipped.
+async fn save_files(config: &Config, dir: &Path, form: &mut Multipart) -> Result<()> {
+    while let Some(headers) = form.next_part().await? {
+        let name = match file_name(&headers) {
+            Some(name) => name,
+            None => continue,
+        };
+
+        let path = unique_path(&dir.join(name));
+        if super::hide::is_hidden(config, &path) {
+            warn!("not saving hidden upload {}", path.display());
+            continue;
+        }
+
+        let temp = super::webdav::UploadTemp::new(&path);
+        write_part(form, temp.path()).await?;
+        temp.persist(&path)?;
+
+        info!("uploaded {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Stream the data of the current part to a new file.
+async fn write_part(form: &mut Multipart, path: &Path) -> Result<()> {
+    let mut file = File::create(path).await?;
+    while let Some(data) = form.next_data().await? {
+        file.write_all(&data).await?;
+    }
+    // Wait for the last write to finish.
+    file.flush().await?;
+    Ok(())
+}
+
+/// The boundary between parts, from a `multipart/form-data` Content-Type.
+fn boundary(headers: &HeaderMap) -> Option<String> {
+    let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    let mut params = content_type.split(';').map(str::trim);
+
+    if !params.next()?.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+
+    params
+        .filter_map(|param| {
+            let mut parts = param.splitn(2, '=');
+            let name = parts.next()?.trim();
+            let value = parts.next()?.trim().trim_matches('"');
+            Some((name, value))
+        })
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.to_string())
+        .filter(|boundary| !boundary.is_empty())
+}
+
+/// The name of the uploaded file, from the `filename` parameter of a part's
+/// Content-Disposition, without any directories.
+fn file_name(headers: &str) -> Option<String> {
+    let disposition = headers.lines().find_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next()?.trim();
+        let value = parts.next()?.trim();
+        if name.eq_ignore_ascii_case("content-disposition") {
+            Some(value)
+        } else {
+            None
+        }
+    })?;
+
+    let file_name = disposition.split(';').map(str::trim).find_map(|param| {
+        let mut parts = param.splitn(2, '=');
+        let name = parts.next()?.trim();
+        let value = parts.next()?.trim().trim_matches('"');
+        if name.eq_ignore_ascii_case("filename") {
+            Some(value)
+        } else {
+            None
+        }
+    })?;
+
+    // Some browsers send the whole path of the file on the client.
+    let file_name = file_name.rsplit(['/', '\\']).next()?;
+    match file_name {
+        "" | "." | ".." => None,
+        name => Some(name.to_string()),
+    }
+}
+
+/// A path for a new file that doesn't replace an existing one, adding a
+/// number to the name if needed, like "photo (1).jpg".
+fn unique_path(path: &Path) -> PathBuf {
+    if !path.exists() {
+        return path.to_owned();
+    }
+
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+
+    (1..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, ext)))
+        .find(|path| !path.exists())
+        .expect("a free file name")
+}
+
+/// A streaming parser for a `multipart/form-data` body.
+///
+/// Each part is found with `next_part`, and its data is read with
+/// `next_data` until it returns `None`. Data that isn't read is skipped.
+struct Multipart {
+    body: Body,
+    /// How many bytes of the body have been read.
+    len: u64,
+    max_len: Option<u64>,
+    buf: Vec<u8>,
+    /// The delimiter before each part, "\r\n--" and the boundary.
+    delimiter: Vec<u8>,
+    done: bool,
+}
+
+impl Multipart {
+    fn new(config: &Config, body: Body, boundary: &str) -> Multipart {
+        Multipart {
+            body,
+            len: 0,
+            max_len: config.max_upload_size,
+            // The first delimiter may not have a line break before it.
+            buf: b"\r\n".to_vec(),
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            done: false,
+        }
+    }
+
+    /// Read more of the body into the buffer, failing at the end of the
+    /// body, since the parser stops at the final delimiter, or if the body is
+    /// bigger than `--max-upload-size`.
+    async fn fill(&mut self) -> Result<()> {
+        match self.body.next().await {
+            Some(chunk) => {
+                let chunk = chunk?;
+                self.len += chunk.len() as u64;
+                if self.max_len.is_some_and(|max| self.len > max) {
+                    return Err(Error::UploadTooLarge);
+                }
+                self.buf.extend_from_slice(&chunk);
+                Ok(())
+            }
+            None => Err(invalid("unexpected end of form")),
+        }
+    }
+
+    /// Find the next part, skipping the rest of the current one, and return
+    /// its headers, or `None` after the last part.
+    async fn next_part(&mut self) -> Result<Option<String>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        // Skip to the end of the delimiter.
+        loop {
+            if let Some(i) = find(&self.buf, &self.delimiter) {
+                self.buf.drain(..i + self.delimiter.len());
+                break;
+            }
+            // Keep what may be the start of the delimiter.
+            let keep = self.delimiter.len() - 1;
+            if self.buf.len() > keep {
+                self.buf.drain(..self.buf.len() - keep);
+            }
+            self.fill().await?;
+        }
+
+        while self.buf.len() < 2 {
+            self.fill().await?;
+        }
+        if self.buf.starts_with(b"--") {
+            // The final delimiter.
+            self.done = true;
+            return Ok(None);
+        }
+
+        // The line break after the delimiter starts the headers, so that the
+        // blank line is found even if there are none.
+        let end = loop {
+            if let Some(i) = find(&self.buf, b"\r\n\r\n") {
+                break i;
+            }
+            if self.buf.len() > MAX_PART_HEADERS_LEN {
+                return Err(invalid("part headers too long"));
+            }
+            self.fill().await?;
+        };
+
+        let headers = String::from_utf8_lossy(&self.buf[..end]).into_owned();
+        self.buf.drain(..end + 4);
+        Ok(Some(headers))
+    }
+
+    /// Read the next piece of the current part's data, or `None` at its end.
+    async fn next_data(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(i) = find(&self.buf, &self.delimiter) {
+                if i == 0 {
+                    return Ok(None);
+                }
+                return Ok(Some(self.buf.drain(..i).collect()));
+            }
+
+            // Everything but what may be the start of the delimiter is data.
+            let keep = self.delimiter.len() - 1;
+            if self.buf.len() > keep {
+                return Ok(Some(self.buf.drain(..self.buf.len() - keep).collect()));
+            }
+            self.fill().await?;
+        }
+    }
+}
+
+/// Find the first position of a byte string in another.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// The error for a malformed form.
+fn invalid(msg: &str) -> Error {
+    Error::Upload(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
@@ -0,0 +1,144 @@
+//! Per-user directories, with `--userdir`, serving "/~USER/" from each user's
+//! own directory, as on shared machines.
+//!
+//! The directory is written like Apache's `UserDir`: a relative path, e.g.
+//! "public_html", is in the user's home directory, a path with a "*", e.g.
+//! "/srv/www/*/public", has the "*" replaced by the user name, and any other
+//! absolute path has the user name added to it. The root user's directory is
+//! never served.
+//!
+//! A user's site is served like the site under `--base-path`, with
+//! "/~USER" taken off its URLs and put back on the links and redirects made
+//! for it. This includes the file manager's endpoints, so that "/~USER/_files/"
+//! works on the user's directory.
+//!
+//! The password file and the user's directory are looked at on a blocking
+//! thread.
+
+use super::Config;
+use http::{header, Uri};
+use hyper::{Body, Request, Response};
+use std::path::PathBuf;
+use tokio_executor::blocking;
+use tracing::trace;
+
+/// The configuration for a request for a user's site, with the user's
+/// directory as the root directory, and "/~USER" taken off its URL.
+pub async fn resolve(mut config: Config, req: &mut Request<Body>) -> Config {
+    let (user, rest) = match split_path(&config, req.uri().path()) {
+        Some((user, rest)) if !rest.is_empty() => (user.to_string(), rest.to_string()),
+        _ => return config,
+    };
+    let root_dir = match user_dir(&config, &user).await {
+        Some(dir) => dir,
+        None => return config,
+    };
+
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", rest, query),
+        None => rest,
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = match path_and_query.parse() {
+        Ok(path_and_query) => Some(path_and_query),
+        Err(_) => return config,
+    };
+    let uri = match Uri::from_parts(parts) {
+        Ok(uri) => uri,
+        Err(_) => return config,
+    };
+
+    trace!("serving ~{} from {}", user, root_dir.display());
+    *req.uri_mut() = uri;
+
+    // The user's site is its own site, not a part of the main one.
+    config.base_path = Some(super::base_path::url(&config, &format!("/~{}", user)));
+    config.root_dir = root_dir;
+    config.vhost.clear();
+    config.fallback_roots.clear();
+    config
+}
+
+/// Redirect "/~USER" to "/~USER/", so that relative links in the user's
+/// index page work.
+pub async fn redirect(config: &Config, req: &Request<Body>) -> Option<Response<Body>> {
+    let user = match split_path(config, req.uri().path()) {
+        Some((user, "")) => user,
+        _ => return None,
+    };
+    user_dir(config, user).await?;
+
+    Response::builder()
+        .status(config.dir_redirect_status)
+        .header(header::LOCATION, format!("/~{}/", user))
+        .body(Body::empty())
+        .ok()
+}
+
+/// Split a URL path into the user name and the path in the user's site.
+fn split_path<'a>(config: &Config, path: &'a str) -> Option<(&'a str, &'a str)> {
+    config.userdir.as_ref()?;
+
+    let path = path.strip_prefix("/~")?;
+    let (user, rest) = match path.find('/') {
+        Some(i) => path.split_at(i),
+        None => (path, ""),
+    };
+
+    let valid = !user.is_empty()
+        && !user.starts_with('.')
+        && user
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-');
+    if valid {
+        Some((user, rest))
+    } else {
+        None
+    }
+}
+
+/// The directory of a user's site, if it exists.
+async fn user_dir(config: &Config, user: &str) -> Option<PathBuf> {
+    let pattern = config.userdir.clone()?;
+    if user == "root" {
+        return None;
+    }
+
+    let user = user.to_string();
+    blocking::run(move || {
+        let dir = if pattern.contains('*') {
+            PathBuf::from(pattern.replace('*', &user))
+        } else if pattern.starts_with('/') {
+            PathBuf::from(pattern).join(&user)
+        } else {
+            home_dir(&user)?.join(pattern)
+        };
+
+        if dir.is_dir() {
+            Some(dir)
+        } else {
+            None
+        }
+    })
+    .await
+}
+
+/// The home directory of a user other than root, from the password file.
+#[cfg(unix)]
+fn home_dir(user: &str) -> Option<PathBuf> {
+    let passwd = std::fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields.as_slice() {
+            [name, _, uid, _, _, home, ..] if *name == user && *uid != "0" => {
+                Some(PathBuf::from(home))
+            }
+            _ => None,
+        }
+    })
+}
+
+#[cfg(not(unix))]
+fn home_dir(_user: &str) -> Option<PathBuf> {
+    None
+}
@@ -0,0 +1,93 @@
+//! Virtual hosts, with `--vhost`, serving a different root directory for each
+//! host name a request is sent to.
+//!
+//! Hosts are chosen by the request's `Host` header, or the authority of its
+//! URI for HTTP/2, and requests for other hosts are served from the default
+//! root directory.
+
+use super::Config;
+use http::header;
+use http::uri::Authority;
+use hyper::{Body, Request};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::trace;
+
+/// A host name and its root directory, from the `--vhost HOST=DIR` option.
+#[derive(Clone, Debug)]
+pub struct VirtualHost {
+    host: String,
+    root_dir: PathBuf,
+}
+
+impl FromStr for VirtualHost {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<VirtualHost, String> {
+        let mut parts = s.splitn(2, '=');
+        let host = parts.next().unwrap_or("").trim();
+        let dir = parts.next().unwrap_or("");
+
+        if host.is_empty() || dir.is_empty() {
+            return Err(format!("expected HOST=DIR, found '{}'", s));
+        }
+
+        Ok(VirtualHost {
+            host: host.to_ascii_lowercase(),
+            root_dir: PathBuf::from(dir),
+        })
+    }
+}
+
+impl VirtualHost {
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn root_dir(&self) -> &Path {
+        &self.root_dir
+    }
+}
+
+/// The configuration for the host a request is for, with its root directory
+/// in place of the default one.
+pub fn resolve(mut config: Config, req: &Request<Body>) -> Config {
+    if config.vhost.is_empty() {
+        return config;
+    }
+
+    let host = match request_host(req) {
+        Some(host) => host,
+        None => return config,
+    };
+
+    let root_dir = config
+        .vhost
+        .iter()
+        .find(|vhost| vhost.host == host)
+        .map(|vhost| vhost.root_dir.clone());
+
+    if let Some(root_dir) = root_dir {
+        trace!("serving host {} from {}", host, root_dir.display());
+        config.root_dir = root_dir;
+    }
+
+    config
+}
+
+/// The host name a request is for, lowercased and without the port.
+pub fn request_host(req: &Request<Body>) -> Option<String> {
+    let authority = match req.uri().authority_part() {
+        Some(authority) => authority.clone(),
+        None => req
+            .headers()
+            .get(header::HOST)?
+            .to_str()
+            .ok()?
+            .parse::<Authority>()
+            .ok()?,
+    };
+
+    let host = authority.host().trim_end_matches('.');
+    Some(host.to_ascii_lowercase())
+}
@@ -0,0 +1,279 @@
+//! Live reload, with `--watch`.
+//!
+//! A file system watcher runs over the root directory, and every change is
+//! sent as a Server-Sent Event to the clients listening on `/_events`, which
+//! can then reload the page.
+//!
+//! https://html.spec.whatwg.org/multipage/server-sent-events.html
+
+use super::{Config, Error, Result};
+use bytes::Bytes;
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::stream::{self, Stream, StreamExt};
+use futures::task::{Context, Poll};
+use http::header::HeaderValue;
+use hyper::{header, Body, Response, StatusCode};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::error::Error as StdError;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::mpsc as std_mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, trace, warn};
+
+/// The path of the event stream.
+pub static EVENTS_PATH: &str = "/_events";
+
+/// How long to wait for more changes before notifying clients, since saving
+/// a file often causes several events in a row.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// The clients listening for changes.
+#[derive(Clone, Default)]
+pub struct Events {
+    clients: Arc<Mutex<Vec<UnboundedSender<()>>>>,
+}
+
+impl Events {
+    /// Tell every client about a change, forgetting clients that have gone.
+    fn notify(&self) {
+        let mut clients = self.clients.lock().expect("events lock");
+        clients.retain(|client| client.unbounded_send(()).is_ok());
+        debug!("notified {} clients of changes", clients.len());
+    }
+
+    /// Respond to a request for the event stream.
+    ///
+    /// The response never ends, and gets a "change" event whenever files
+    /// change.
+    pub fn response(&self) -> Result<Response<Body>> {
+        let (tx, rx) = mpsc::unbounded();
+        self.clients.lock().expect("events lock").push(tx);
+
+        // Send a comment first, so that the client knows it's connected.
+        let connected = stream::once(async { Bytes::from_static(b": connected\n\n") });
+        let changes = rx.map(|()| Bytes::from_static(b"event: change\ndata: change\n\n"));
+        let body = connected.chain(changes).map(Ok::<_, io::Error>);
+
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("text/event-stream"),
+            )
+            .header(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"))
+            .body(Body::wrap_stream(body))
+            .map_err(Error::from)
+    }
+}
+
+/// Start watching the root directory, if `--watch` is set.
+///
+/// The watcher runs on its own thread for the life of the server.
+pub fn start(config: &Config) -> Result<Option<Events>> {
+    if !config.watch {
+        return Ok(None);
+    }
+
+    let root_dir = config
+        .root_dir
+        .canonicalize()
+        .map_err(|e| Error::Watch(notify::Error::io(e)))?;
+    let access_log = config
+        .access_log
+        .as_ref()
+        .and_then(|path| path.canonicalize().ok());
+
+    let (tx, rx) = std_mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).map_err(Error::Watch)?;
+    watcher
+        .watch(&root_dir, RecursiveMode::Recursive)
+        .map_err(Error::Watch)?;
+
+    let events = Events::default();
+    let filter = ChangeFilter {
+        config: config.clone(),
+        root_dir,
+        access_log,
+    };
+
+    let thread_events = events.clone();
+    thread::spawn(move || {
+        // The watcher stops when it is dropped.
+        let _watcher = watcher;
+
+        while let Ok(event) = rx.recv() {
+            if !filter.is_change(event) {
+                continue;
+            }
+
+            // Wait until the changes stop coming before notifying.
+            while rx.recv_timeout(DEBOUNCE).is_ok() {}
+
+            // The reloaded pages must not see the old files.
+            super::meta_cache::clear();
+            thread_events.notify();
+        }
+    });
+
+    Ok(Some(events))
+}
+
+/// Decides which file system events are changes that clients should see.
+struct ChangeFilter {
+    config: Config,
+    root_dir: PathBuf,
+    access_log: Option<PathBuf>,
+}
+
+impl ChangeFilter {
+    fn is_change(&self, event: notify::Result<notify::Event>) -> bool {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("file watch error: {}", e);
+                return false;
+            }
+        };
+
+        // Reading files isn't a change.
+        if let EventKind::Access(_) = event.kind {
+            return false;
+        }
+
+        event.paths.iter().any(|path| self.is_visible(path))
+    }
+
+    /// Whether a changed file is one that clients can see.
+    ///
+    /// Changes to the access log in particular must be ignored, or every
+    /// reload would cause another reload.
+    fn is_visible(&self, path: &Path) -> bool {
+        if Some(path) == self.access_log.as_deref() {
+            return false;
+        }
+
+        let rel_path = path.strip_prefix(&self.root_dir).unwrap_or(path);
+        let path = self.config.root_dir.join(rel_path);
+        let visible = !super::hide::is_hidden(&self.config, &path);
+        trace!("changed: {} (visible: {})", path.display(), visible);
+        visible
+    }
+}
+
+/// The script that reloads the page when files change.
+static RELOAD_SCRIPT: &str = "<script>new EventSource(\"/_events\")\
+    .addEventListener(\"change\", function () { location.reload(); });</script>\n";
+
+/// Add the reload script to an HTML page, so that pages reload without being
+/// changed to include it themselves.
+///
+/// The script is inserted before "</body>", or at the end of pages without
+/// one, as the body is streamed.
+pub fn inject_reload_script(resp: Response<Body>) -> Response<Body> {
+    let is_html = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/html"));
+    if resp.status() != StatusCode::OK || !is_html {
+        return resp;
+    }
+
+    trace!("injecting reload script");
+
+    let (mut parts, body) = resp.into_parts();
+
+    // The script is always inserted exactly once, so the new length is known.
+    let len = parts
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if let Some(len) = len {
+        let len = HeaderValue::from(len + RELOAD_SCRIPT.len());
+        parts.headers.insert(header::CONTENT_LENGTH, len);
+    }
+
+    let stream = InjectStream {
+        body,
+        carry: Vec::new(),
+        injected: false,
+        done: false,
+    };
+
+    Response::from_parts(parts, Body::wrap_stream(stream))
+}
+
+static BODY_END: &[u8] = b"</body>";
+
+/// A stream that inserts the reload script into an HTML body.
+struct InjectStream {
+    body: Body,
+    // The end of the last chunk, which may be the start of "</body>".
+    carry: Vec<u8>,
+    injected: bool,
+    done: bool,
+}
+
+impl Stream for InjectStream {
+    type Item = std::result::Result<Bytes, Box<dyn StdError + Send + Sync>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        let chunk = match Pin::new(&mut this.body).poll_next(cx) {
+            Poll::Ready(chunk) => chunk,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        let chunk = match chunk {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(e)) => return Poll::Ready(Some(Err(Box::new(e)))),
+            None => {
+                // Add the script at the end if there was no "</body>".
+                this.done = true;
+                let mut out = std::mem::take(&mut this.carry);
+                if !this.injected {
+                    out.extend_from_slice(RELOAD_SCRIPT.as_bytes());
+                }
+                return Poll::Ready(Some(Ok(Bytes::from(out))));
+            }
+        };
+
+        if this.injected {
+            return Poll::Ready(Some(Ok(Bytes::from(chunk.to_vec()))));
+        }
+
+        let mut data = std::mem::take(&mut this.carry);
+        data.extend_from_slice(&chunk);
+
+        let end = data
+            .windows(BODY_END.len())
+            .position(|w| w.eq_ignore_ascii_case(BODY_END));
+
+        let out = match end {
+            Some(i) => {
+                this.injected = true;
+                let mut out = data[..i].to_vec();
+                out.extend_from_slice(RELOAD_SCRIPT.as_bytes());
+                out.extend_from_slice(&data[i..]);
+                out
+            }
+            None => {
+                let keep = data.len().min(BODY_END.len() - 1);
+                this.carry = data.split_off(data.len() - keep);
+                data
+            }
+        };
+
+        Poll::Ready(Some(Ok(Bytes::from(out))))
+    }
+}
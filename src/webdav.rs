@@ -0,0 +1,541 @@
+//! WebDAV, with `--webdav`, so the root directory can be mounted as a network
+//! drive.
+//!
+//! This is the class 1 subset of RFC 4918: PROPFIND lists files and their
+//! properties, and MKCOL, PUT, DELETE, MOVE and COPY change them. GET is served
+//! as usual. There is no locking, and no PROPPATCH, so properties can't be
+//! changed.
+//!
+//! Hidden files can't be seen or changed, and nothing outside the root
+//! directory can be reached. Directories with hidden files in them can't be
+//! deleted or replaced. Directories are listed, copied and deleted on tokio's
+//! blocking threads.
+//!
+//! https://tools.ietf.org/html/rfc4918
+
+use super::ext::{escape_html, PATH_SET};
+use super::{Config, Error, Result};
+use chrono::{DateTime, Utc};
+use http::header::{self, HeaderMap, HeaderValue};
+use http::{Request, Response, StatusCode, Uri};
+use hyper::Body;
+use percent_encoding::{percent_decode_str, utf8_percent_encode};
+use std::fmt::Write as _;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio_executor::blocking;
+use tracing::{debug, info};
+
+/// The methods allowed with `--webdav`, for the `Allow` header.
+pub static ALLOWED_METHODS: &str = "GET, OPTIONS, PROPFIND, MKCOL, PUT, DELETE, MOVE, COPY";
+
+/// The compliance classes, for the `DAV` header.
+pub static DAV_CLASSES: &str = "1";
+
+/// Counts partly uploaded files, to give each a unique name.
+static UPLOAD_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether a request uses one of the WebDAV methods other than GET and
+/// OPTIONS, with `--webdav` on.
+pub fn is_webdav_request(config: &Config, req: &Request<Body>) -> bool {
+    if !config.webdav {
+        return false;
+    }
+
+    matches!(
+        req.method().as_str(),
+        "PROPFIND" | "MKCOL" | "PUT" | "DELETE" | "MOVE" | "COPY"
+    )
+}
+
+/// Handle a WebDAV request.
+pub async fn serve(config: &Config, req: Request<Body>) -> Result<Response<Body>> {
+    let path = match local_path(config, req.uri())? {
+        Some(path) => path,
+        None => return super::make_error_response_from_code(config, StatusCode::FORBIDDEN),
+    };
+
+    // Requests for hidden files are answered as if they don't exist, except
+    // that they can't be created either.
+    if super::hide::is_hidden(config, &path) {
+        debug!("hiding {}", req.uri());
+        let status = match req.method().as_str() {
+            "PUT" | "MKCOL" => StatusCode::FORBIDDEN,
+            _ => StatusCode::NOT_FOUND,
+        };
+        return super::make_error_response_from_code(config, status);
+    }
+
+    let status = match req.method().as_str() {
+        "PROPFIND" => return propfind(config, &req, path).await,
+        "MKCOL" => mkcol(&req, &path)?,
+        "PUT" => put(config, req, &path).await?,
+        "DELETE" => {
+            let config = config.clone();
+            blocking::run(move || delete(&config, &path)).await?
+        }
+        "MOVE" => copy_or_move(config, &req, path, true).await?,
+        "COPY" => copy_or_move(config, &req, path, false).await?,
+        _ => unreachable!("not a WebDAV method"),
+    };
+
+    if status.is_success() {
+        Response::builder()
+            .status(status)
+            .body(Body::empty())
+            .map_err(Error::from)
+    } else {
+        super::make_error_response_from_code(config, status)
+    }
+}
+
+/// Map a URL to a local path, or `None` if it would be outside the root
+/// directory, as with `..` or a second leading `/`.
+pub fn local_path(config: &Config, uri: &Uri) -> Result<Option<PathBuf>> {
+    let url_path = percent_decode_str(uri.path())
+        .decode_utf8()
+        .map_err(|_| Error::UriNotUtf8)?;
+    Ok(super::ext::url_path_to_local(config, &url_path))
+}
+
+/// List the properties of a file, or of a directory and the files in it.
+///
+/// Every property is returned, whatever the request asks for. A depth of
+/// "infinity" is treated as 1, so that a request can't walk the whole tree.
+async fn propfind(config: &Config, req: &Request<Body>, path: PathBuf) -> Result<Response<Body>> {
+    let with_children = req
+        .headers()
+        .get("depth")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("infinity")
+        != "0";
+
+    let config = config.clone();
+    let buf = blocking::run(move || multistatus(&config, &path, with_children)).await?;
+
+    Response::builder()
+        .status(StatusCode::MULTI_STATUS)
+        .header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+        .header(header::CONTENT_LENGTH, buf.len())
+        .body(Body::from(buf))
+        .map_err(Error::from)
+}
+
+/// Write the `multistatus` element with the properties of a file, and of
+/// the files in it if it's a directory and `with_children` is given.
+fn multistatus(config: &Config, path: &Path, with_children: bool) -> Result<String> {
+    let meta = fs::metadata(path)?;
+
+    let mut buf = String::new();
+    buf.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    buf.push_str("<D:multistatus xmlns:D=\"DAV:\">\n");
+    write_response(&mut buf, config, path, &meta)?;
+
+    if meta.is_dir() && with_children {
+        let mut paths = Vec::new();
+        for dent in fs::read_dir(path)? {
+            let path = dent?.path();
+            if !super::hide::is_hidden(config, &path) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        for path in paths {
+            // Skip broken symlinks.
+            if let Ok(meta) = fs::metadata(&path) {
+                write_response(&mut buf, config, &path, &meta)?;
+            }
+        }
+    }
+
+    buf.push_str("</D:multistatus>\n");
+    Ok(buf)
+}
+
+/// Write the `response` element with the properties of one file.
+fn write_response(buf: &mut String, config: &Config, path: &Path, meta: &Metadata) -> Result<()> {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let modified: DateTime<Utc> = meta.modified()?.into();
+
+    let _ = writeln!(buf, "<D:response>");
+    let _ = writeln!(
+        buf,
+        "<D:href>{}</D:href>",
+        href(config, path, meta.is_dir())
+    );
+    let _ = writeln!(buf, "<D:propstat>\n<D:prop>");
+    let _ = writeln!(buf, "<D:displayname>{}</D:displayname>", escape_html(&name));
+    let _ = writeln!(
+        buf,
+        "<D:getlastmodified>{}</D:getlastmodified>",
+        modified.format("%a, %d %b %Y %H:%M:%S GMT")
+    );
+    if meta.is_dir() {
+        let _ = writeln!(buf, "<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        let mime = super::mime_type::file_path_mime(config, path);
+        let _ = writeln!(buf, "<D:resourcetype/>");
+        let _ = writeln!(
+            buf,
+            "<D:getcontentlength>{}</D:getcontentlength>",
+            meta.len()
+        );
+        let _ = writeln!(
+            buf,
+            "<D:getcontenttype>{}</D:getcontenttype>",
+            escape_html(mime.as_ref())
+        );
+        let _ = writeln!(
+            buf,
+            "<D:getetag>{}</D:getetag>",
+            escape_html(&super::file_etag(meta)?)
+        );
+    }
+    let _ = writeln!(buf, "</D:prop>\n<D:status>HTTP/1.1 200 OK</D:status>");
+    let _ = writeln!(buf, "</D:propstat>\n</D:response>");
+
+    Ok(())
+}
+
+/// The absolute URL of a file, ending with "/" for directories.
+fn href(config: &Config, path: &Path, is_dir: bool) -> String {
+    let rel_path = path.strip_prefix(&config.root_dir).unwrap_or(path);
+    let mut url = String::new();
+    for c in rel_path.components() {
+        url.push('/');
+        url.extend(utf8_percent_encode(
+            &c.as_os_str().to_string_lossy(),
+            PATH_SET,
+        ));
+    }
+    if is_dir || url.is_empty() {
+        url.push('/');
+    }
+    super::base_path::url(config, &url)
+}
+
+/// Create a directory.
+fn mkcol(req: &Request<Body>, path: &Path) -> Result<StatusCode> {
+    // Bodies describing the new directory aren't supported.
+    let has_body = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .is_some_and(|len| len != "0");
+    if has_body {
+        return Ok(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    make_dir(path)
+}
+
+/// Create a directory, if it doesn't exist and its parent does.
+pub fn make_dir(path: &Path) -> Result<StatusCode> {
+    if path.exists() {
+        return Ok(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    if !parent_exists(path) {
+        return Ok(StatusCode::CONFLICT);
+    }
+
+    fs::create_dir(path)?;
+    info!("created directory {}", path.display());
+    Ok(StatusCode::CREATED)
+}
+
+/// Write the request body to a file.
+///
+/// The body is written to a temporary file next to it first, so that a
+/// failed upload doesn't leave a partial file behind.
+async fn put(config: &Config, req: Request<Body>, path: &Path) -> Result<StatusCode> {
+    check_content_length(config, req.headers())?;
+
+    if path.is_dir() {
+        return Ok(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    if !parent_exists(path) {
+        return Ok(StatusCode::CONFLICT);
+    }
+
+    let existed = path.exists();
+    let temp = UploadTemp::new(path);
+    write_file(config, req.into_body(), temp.path()).await?;
+    temp.persist(path)?;
+
+    info!("wrote {}", path.display());
+    if existed {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::CREATED)
+    }
+}
+
+/// A temporary file next to a file being uploaded, which is removed unless
+/// it is persisted, even if the upload is abandoned part way. It's named
+/// like ".NAME.PID-N.part", and always hidden, so that it isn't listed.
+pub struct UploadTemp {
+    path: PathBuf,
+}
+
+impl UploadTemp {
+    pub fn new(path: &Path) -> UploadTemp {
+        let n = UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let file_name = format!(".{}.{}-{}.part", name, std::process::id(), n);
+        UploadTemp {
+            path: path.with_file_name(file_name),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move the finished file into place.
+    pub fn persist(self, path: &Path) -> Result<()> {
+        fs::rename(&self.path, path).map_err(Error::Io)
+    }
+
+    /// Whether a file name is a temporary file's.
+    pub fn is_temp_name(name: &str) -> bool {
+        let rest = match name.strip_suffix(".part") {
+            Some(rest) if name.starts_with('.') => rest,
+            _ => return false,
+        };
+        let counter = rest.rsplit('.').next().unwrap_or("");
+        let mut numbers = counter.splitn(2, '-');
+        let is_number = |s: Option<&str>| {
+            s.is_some_and(|s| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()))
+        };
+        counter.len() < rest.len() && is_number(numbers.next()) && is_number(numbers.next())
+    }
+}
+
+impl Drop for UploadTemp {
+    fn drop(&mut self) {
+        // Gone already if it was persisted. The removal is queued on a
+        // blocking thread, and not waited for.
+        let path = std::mem::take(&mut self.path);
+        drop(blocking::run(move || fs::remove_file(path)));
+    }
+}
+
+/// Stream a request body to a new file.
+async fn write_file(config: &Config, mut body: Body, path: &Path) -> Result<()> {
+    let mut file = File::create(path).await?;
+    let mut len = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        len += chunk.len() as u64;
+        check_upload_size(config, len)?;
+        file.write_all(&chunk).await?;
+    }
+    // Wait for the last write to finish.
+    file.flush().await?;
+    Ok(())
+}
+
+/// Fail if an upload is bigger than `--max-upload-size`.
+pub fn check_upload_size(config: &Config, len: u64) -> Result<()> {
+    match config.max_upload_size {
+        Some(max) if len > max => Err(Error::UploadTooLarge),
+        _ => Ok(()),
+    }
+}
+
+/// Fail early if the Content-Length of an upload is too big, before reading
+/// any of it.
+pub fn check_content_length(config: &Config, headers: &HeaderMap) -> Result<()> {
+    let len = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+    match len {
+        Some(len) => check_upload_size(config, len),
+        None => Ok(()),
+    }
+}
+
+/// Delete a file, or a directory and everything in it, unless it has hidden
+/// files in it, which the client can't know it would be deleting.
+pub fn delete(config: &Config, path: &Path) -> Result<StatusCode> {
+    if path == config.root_dir || has_hidden(config, path)? {
+        return Ok(StatusCode::FORBIDDEN);
+    }
+
+    remove(path)?;
+    info!("deleted {}", path.display());
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Copy or move a file or directory to the URL in the `Destination` header.
+///
+/// An existing file at the destination is replaced, unless the `Overwrite`
+/// header is "F".
+async fn copy_or_move(
+    config: &Config,
+    req: &Request<Body>,
+    path: PathBuf,
+    is_move: bool,
+) -> Result<StatusCode> {
+    let dest = req
+        .headers()
+        .get("destination")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<Uri>().ok())
+        .and_then(|uri| {
+            super::base_path::strip_path(config, uri.path())?
+                .parse::<Uri>()
+                .ok()
+        });
+    let dest = match dest {
+        Some(dest) => dest,
+        None => return Ok(StatusCode::BAD_REQUEST),
+    };
+    let dest = match local_path(config, &dest)? {
+        Some(dest) => dest,
+        None => return Ok(StatusCode::FORBIDDEN),
+    };
+
+    let overwrite = req.headers().get("overwrite") != Some(&HeaderValue::from_static("F"));
+    let copy = if is_move {
+        None
+    } else {
+        // A depth of 0 copies a directory without its contents.
+        Some(req.headers().get("depth") != Some(&HeaderValue::from_static("0")))
+    };
+
+    let config = config.clone();
+    blocking::run(move || transfer(&config, &path, &dest, overwrite, copy)).await
+}
+
+/// Move a file or directory, without replacing anything at the destination.
+pub fn move_path(config: &Config, path: &Path, dest: &Path) -> Result<StatusCode> {
+    transfer(config, path, dest, false, None)
+}
+
+/// Move a file or directory, or copy it if `copy` is given, saying whether to
+/// copy a directory's contents.
+fn transfer(
+    config: &Config,
+    path: &Path,
+    dest: &Path,
+    overwrite: bool,
+    copy: Option<bool>,
+) -> Result<StatusCode> {
+    if !path.exists() {
+        return Ok(StatusCode::NOT_FOUND);
+    }
+    if path == config.root_dir || dest.starts_with(path) || super::hide::is_hidden(config, dest) {
+        return Ok(StatusCode::FORBIDDEN);
+    }
+    if !parent_exists(dest) {
+        return Ok(StatusCode::CONFLICT);
+    }
+
+    let existed = dest.exists();
+    if existed {
+        if !overwrite {
+            return Ok(StatusCode::PRECONDITION_FAILED);
+        }
+        if has_hidden(config, dest)? {
+            return Ok(StatusCode::FORBIDDEN);
+        }
+        remove(dest)?;
+    }
+
+    match copy {
+        None => {
+            fs::rename(path, dest)?;
+            info!("moved {} to {}", path.display(), dest.display());
+        }
+        Some(recursive) => {
+            copy_path(config, path, dest, recursive)?;
+            info!("copied {} to {}", path.display(), dest.display());
+        }
+    }
+
+    if existed {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::CREATED)
+    }
+}
+
+/// Copy a file, or a directory and the files in it that aren't hidden.
+/// Symlinks are copied as links, so their targets are never copied or walked.
+fn copy_path(config: &Config, from: &Path, to: &Path, recursive: bool) -> io::Result<()> {
+    let file_type = fs::symlink_metadata(from)?.file_type();
+    if file_type.is_symlink() {
+        return copy_link(from, to);
+    }
+    if !file_type.is_dir() {
+        return fs::copy(from, to).map(|_| ());
+    }
+
+    fs::create_dir(to)?;
+    if recursive {
+        for dent in fs::read_dir(from)? {
+            let path = dent?.path();
+            if super::hide::is_hidden(config, &path) {
+                continue;
+            }
+            if let Some(name) = path.file_name() {
+                copy_path(config, &path, &to.join(name), true)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Make a symlink at `to` pointing where the one at `from` does.
+#[cfg(unix)]
+fn copy_link(from: &Path, to: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(from)?, to)
+}
+
+#[cfg(not(unix))]
+fn copy_link(from: &Path, _to: &Path) -> io::Result<()> {
+    let msg = format!("can't copy the symlink {}", from.display());
+    Err(io::Error::new(io::ErrorKind::Other, msg))
+}
+
+/// Whether there are hidden files anywhere in a directory. Symlinks to
+/// directories aren't followed, since removing them leaves their files.
+fn has_hidden(config: &Config, path: &Path) -> io::Result<bool> {
+    if !fs::symlink_metadata(path)?.is_dir() {
+        return Ok(false);
+    }
+
+    for dent in fs::read_dir(path)? {
+        let path = dent?.path();
+        if super::hide::is_hidden(config, &path) || has_hidden(config, &path)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Remove a file or directory.
+fn remove(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Whether the directory a file would be created in exists.
+fn parent_exists(path: &Path) -> bool {
+    path.parent().is_some_and(Path::is_dir)
+}